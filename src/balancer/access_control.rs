@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, Result};
+use std::net::IpAddr;
+
+use log::warn;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AccessAction {
+    Allow,
+    Deny,
+}
+
+/**
+    Node of a binary prefix trie, children are indexes into the node list (0 means none, as the root is never a child)
+*/
+#[derive(Default)]
+struct TrieNode {
+    children: [u32; 2],
+    action: Option<AccessAction>,
+}
+
+/**
+    Binary trie of network prefixes, lookups take at most one step per address bit no matter how many prefixes there are
+*/
+struct PrefixTrie {
+    nodes: Vec<TrieNode>,
+}
+
+impl PrefixTrie {
+    fn new() -> Self {
+        PrefixTrie {
+            nodes: vec![TrieNode::default()],
+        }
+    }
+
+    fn insert(&mut self, bits: u128, len: u8, action: AccessAction) {
+        let mut node = 0;
+        for i in 0..len {
+            let bit = ((bits >> (127 - i)) & 1) as usize;
+            if self.nodes[node].children[bit] == 0 {
+                self.nodes.push(TrieNode::default());
+                self.nodes[node].children[bit] = (self.nodes.len() - 1) as u32;
+            }
+            node = self.nodes[node].children[bit] as usize;
+        }
+
+        // a network that is both allowed and denied stays denied, no matter in which order the rules are added
+        let existing = &mut self.nodes[node].action;
+        if *existing != Some(AccessAction::Deny) {
+            *existing = Some(action);
+        }
+    }
+
+    /**
+        Returns the action of the longest prefix matching the address, [bits] are left-aligned
+    */
+    fn lookup(&self, bits: u128, len: u8) -> Option<AccessAction> {
+        let mut node = 0;
+        let mut action = self.nodes[0].action;
+        for i in 0..len {
+            let bit = ((bits >> (127 - i)) & 1) as usize;
+            node = match self.nodes[node].children[bit] {
+                0 => break,
+                n => n as usize,
+            };
+            action = self.nodes[node].action.or(action);
+        }
+
+        action
+    }
+}
+
+/**
+    Allow and deny rules for network prefixes. The most specific matching rule wins, so a smaller network can be allowed
+    inside a denied one (and the other way around), deny wins for the same network. Addresses no rule matches are allowed
+    only if there are no allow rules.
+*/
+pub struct AccessList {
+    ipv4: PrefixTrie,
+    ipv6: PrefixTrie,
+    has_allow_rules: bool,
+    rules: usize,
+}
+
+impl AccessList {
+    pub fn new() -> Self {
+        AccessList {
+            ipv4: PrefixTrie::new(),
+            ipv6: PrefixTrie::new(),
+            has_allow_rules: false,
+            rules: 0,
+        }
+    }
+
+    /**
+        Adds a rule for the given CIDR (like `10.0.0.0/8`), a plain address matches just itself. Returns [false] if the CIDR is not valid
+    */
+    pub fn add(&mut self, cidr: &str, action: AccessAction) -> bool {
+        let (addr, len) = match cidr.split_once('/') {
+            Some((a, l)) => (a, l.parse::<u8>().ok()),
+            None => (cidr, None),
+        };
+
+        let (bits, max_len, trie) = match addr.trim().parse::<IpAddr>() {
+            Ok(IpAddr::V4(v4)) => ((u32::from(v4) as u128) << 96, 32, &mut self.ipv4),
+            Ok(IpAddr::V6(v6)) => (u128::from(v6), 128, &mut self.ipv6),
+            Err(_) => return false,
+        };
+        let len = match len {
+            Some(l) if l <= max_len => l,
+            Some(_) => return false,
+            None if cidr.contains('/') => return false,
+            None => max_len,
+        };
+
+        trie.insert(bits, len, action);
+        self.has_allow_rules |= action == AccessAction::Allow;
+        self.rules += 1;
+        true
+    }
+
+    /**
+        Adds rules for every CIDR in the file (one per line, `#` starts a comment). Returns the number of added rules
+    */
+    pub fn add_file(&mut self, path: &str, action: AccessAction) -> Result<usize> {
+        let reader = BufReader::new(File::open(path)?);
+
+        let mut added = 0;
+        for line in reader.lines() {
+            let line = line?;
+            let cidr = line.split('#').next().unwrap_or("").trim();
+            if cidr.is_empty() {
+                continue;
+            }
+
+            if self.add(cidr, action) {
+                added += 1;
+            } else {
+                warn!(target: "Access", "Invalid network '{}' in '{}'", cidr, path);
+            }
+        }
+
+        Ok(added)
+    }
+
+    pub fn rule_count(&self) -> usize {
+        self.rules
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of dual-stack listeners are matched against IPv4 rules
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            v4 => v4,
+        };
+
+        let action = match ip {
+            IpAddr::V4(v4) => self.ipv4.lookup((u32::from(v4) as u128) << 96, 32),
+            IpAddr::V6(v6) => self.ipv6.lookup(u128::from(v6), 128),
+        };
+
+        match action {
+            Some(a) => a == AccessAction::Allow,
+            None => !self.has_allow_rules,
+        }
+    }
+}
+
+/**
+    Access lists of all listeners, listeners without their own list use the default one
+*/
+pub struct AccessControl {
+    default: AccessList,
+    ports: HashMap<u16, AccessList>,
+}
+
+impl AccessControl {
+    pub fn new(default: AccessList) -> Self {
+        AccessControl {
+            default,
+            ports: HashMap::new(),
+        }
+    }
+
+    /**
+        Uses the given list instead of the default one for the listener on [port]
+    */
+    pub fn set_port(&mut self, port: u16, list: AccessList) {
+        self.ports.insert(port, list);
+    }
+
+    /**
+        Checks if a client from [ip] may connect to the listener on [port]
+    */
+    pub fn is_allowed(&self, port: u16, ip: IpAddr) -> bool {
+        self.ports.get(&port).unwrap_or(&self.default).is_allowed(ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access_list(allow: &[&str], deny: &[&str]) -> AccessList {
+        let mut list = AccessList::new();
+        for cidr in allow {
+            assert!(list.add(cidr, AccessAction::Allow), "{}", cidr);
+        }
+        for cidr in deny {
+            assert!(list.add(cidr, AccessAction::Deny), "{}", cidr);
+        }
+        list
+    }
+
+    fn allowed(list: &AccessList, ip: &str) -> bool {
+        list.is_allowed(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn most_specific_rule_wins() {
+        let list = access_list(&["10.1.0.0/16", "10.1.2.3"], &["10.0.0.0/8", "10.1.2.0/24"]);
+        assert!(!allowed(&list, "10.2.0.1"));
+        assert!(allowed(&list, "10.1.0.1"));
+        assert!(!allowed(&list, "10.1.2.4"));
+        assert!(allowed(&list, "10.1.2.3"));
+        // allow rules exist, so unmatched addresses are denied
+        assert!(!allowed(&list, "192.168.0.1"));
+    }
+
+    #[test]
+    fn deny_wins_for_same_network() {
+        let mut list = access_list(&["10.0.0.0/8"], &["10.0.0.0/8", "2001:db8::1"]);
+        assert!(list.add("2001:db8::1/128", AccessAction::Allow));
+        assert!(!allowed(&list, "10.0.0.1"));
+        assert!(!allowed(&list, "2001:db8::1"));
+        assert_eq!(list.rule_count(), 4);
+    }
+
+    #[test]
+    fn handles_full_and_empty_prefixes() {
+        let list = access_list(&["0.0.0.0/0"], &["192.0.2.1/32", "::/0"]);
+        assert!(allowed(&list, "203.0.113.7"));
+        assert!(!allowed(&list, "192.0.2.1"));
+        assert!(allowed(&list, "192.0.2.2"));
+        assert!(!allowed(&list, "2001:db8::1"));
+
+        let list = access_list(&["::/0"], &["2001:db8::1/128"]);
+        assert!(allowed(&list, "2001:db8::2"));
+        assert!(!allowed(&list, "2001:db8::1"));
+        // IPv4 addresses only match IPv4 rules
+        assert!(!allowed(&list, "192.0.2.1"));
+    }
+
+    #[test]
+    fn matches_ipv4_mapped_addresses_as_ipv4() {
+        let list = access_list(&["192.0.2.0/24"], &["192.0.2.128/25"]);
+        assert!(allowed(&list, "::ffff:192.0.2.1"));
+        assert!(!allowed(&list, "::ffff:192.0.2.200"));
+        assert!(!allowed(&list, "::ffff:198.51.100.1"));
+    }
+
+    #[test]
+    fn allows_everything_without_allow_rules() {
+        assert!(allowed(&AccessList::new(), "192.0.2.1"));
+        assert!(allowed(&AccessList::new(), "2001:db8::1"));
+
+        let list = access_list(&[], &["192.0.2.0/24"]);
+        assert!(!allowed(&list, "192.0.2.1"));
+        assert!(allowed(&list, "198.51.100.1"));
+    }
+
+    #[test]
+    fn rejects_invalid_networks() {
+        let mut list = AccessList::new();
+        for cidr in ["10.0.0.0/33", "2001:db8::/129", "10.0.0.0/", "10.0.0", "example.com/8"] {
+            assert!(!list.add(cidr, AccessAction::Deny), "{}", cidr);
+        }
+        assert_eq!(list.rule_count(), 0);
+    }
+
+    #[test]
+    fn uses_list_of_listener_port() {
+        let mut control = AccessControl::new(access_list(&[], &["192.0.2.0/24"]));
+        control.set_port(8443, access_list(&["192.0.2.0/24"], &[]));
+        assert!(!control.is_allowed(80, "192.0.2.1".parse().unwrap()));
+        assert!(control.is_allowed(8443, "192.0.2.1".parse().unwrap()));
+        assert!(!control.is_allowed(8443, "198.51.100.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,154 @@
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde::Serialize;
+
+use super::TcpClient;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum AccessLogFormat {
+    /**
+        Single line of `key=value` pairs, meant to be read by people
+    */
+    Human,
+    /**
+        Newline-delimited JSON, one object per session
+    */
+    Json,
+}
+
+#[derive(Serialize)]
+struct AccessLogEntry<'a> {
+    timestamp: String,
+    worker: u32,
+    client: String,
+    backends: Vec<String>,
+    retries: usize,
+    connect_ms: Option<f64>,
+    bytes_in: u64,
+    bytes_out: u64,
+    duration_ms: f64,
+    close_reason: &'a str,
+}
+
+/**
+    Writes one record per proxied session to a file. The file is reopened on SIGUSR1, so it can be rotated
+*/
+pub struct AccessLog {
+    path: String,
+    format: AccessLogFormat,
+    file: Mutex<File>,
+    should_reopen: Arc<AtomicBool>,
+}
+
+impl AccessLog {
+    pub fn new(path: &str, format: AccessLogFormat) -> Result<Self> {
+        let should_reopen = Arc::new(AtomicBool::new(false));
+
+        #[cfg(unix)]
+        signal_hook::flag::register(signal_hook::consts::SIGUSR1, Arc::clone(&should_reopen))?;
+
+        Ok(AccessLog {
+            path: path.to_string(),
+            format,
+            file: Mutex::new(AccessLog::open(path)?),
+            should_reopen,
+        })
+    }
+
+    fn open(path: &str) -> Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    /**
+        Writes the record for a client that has been removed from its worker thread
+    */
+    pub fn log(&self, worker: u32, client: &TcpClient) {
+        let stats = &client.stats;
+        let entry = AccessLogEntry {
+            timestamp: format_timestamp(stats.started_at),
+            worker,
+            client: client.address.to_string(),
+            backends: stats.targets.iter().map(|t| t.to_string()).collect(),
+            retries: stats.targets.len().saturating_sub(1),
+            connect_ms: stats.connect_latency.map(as_millis),
+            bytes_in: stats.bytes_in,
+            bytes_out: stats.bytes_out,
+            duration_ms: as_millis(stats.started.elapsed()),
+            close_reason: stats.close_reason.map_or("unknown", |r| r.as_str()),
+        };
+
+        let mut line = match self.format {
+            AccessLogFormat::Json => serde_json::to_string(&entry).unwrap_or_default(),
+            AccessLogFormat::Human => format!(
+                "{} [Thread {}] {} -> {} retries={} connect={} in={}B out={}B duration={:.1}ms reason={}",
+                entry.timestamp,
+                entry.worker,
+                entry.client,
+                if entry.backends.is_empty() { "-".to_string() } else { entry.backends.join(",") },
+                entry.retries,
+                entry.connect_ms.map_or("-".to_string(), |c| format!("{:.2}ms", c)),
+                entry.bytes_in,
+                entry.bytes_out,
+                entry.duration_ms,
+                entry.close_reason
+            ),
+        };
+        line.push('\n');
+
+        let mut file = self.file.lock().unwrap();
+
+        // reopen if requested (log rotation)
+        if self.should_reopen.swap(false, Ordering::Relaxed) {
+            match AccessLog::open(&self.path) {
+                Ok(f) => *file = f,
+                Err(e) => warn!(target: "AccessLog", "Failed to reopen '{}' -> {}", self.path, e),
+            }
+        }
+
+        file.write_all(line.as_bytes()).unwrap_or_else(|e| {
+            warn!(target: "AccessLog", "Failed to write to '{}' -> {}", self.path, e);
+        });
+    }
+}
+
+fn as_millis(duration: Duration) -> f64 {
+    // microsecond precision is plenty
+    (duration.as_secs_f64() * 1_000_000.0).round() / 1000.0
+}
+
+/**
+    Formats the given time as an ISO 8601 UTC timestamp with milliseconds
+*/
+pub fn format_timestamp(time: SystemTime) -> String {
+    let now = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = now.as_secs();
+    let (hour, minute, second) = ((secs / 3600) % 24, (secs / 60) % 60, secs % 60);
+
+    // civil date from days since epoch (Howard Hinnant's algorithm)
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+        now.subsec_millis()
+    )
+}
@@ -0,0 +1,349 @@
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::info;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use super::{BackendMetrics, BalancingAlgorithm, HostManager, HostState, HttpRequest, HttpResponse, Metrics, Pool, RoundRobin, TcpClient};
+use crate::logger::Logger;
+
+// timeout for connecting to a backend when a health check is forced
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+// how often the connection count of a draining backend is checked
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+// how long to wait for worker threads to report their sessions
+const SESSIONS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/**
+    Pending session listing requests for every worker thread, workers answer them from their event loop
+*/
+pub type SessionRequests = Arc<Vec<Mutex<Vec<Sender<Vec<SessionInfo>>>>>>;
+
+/**
+    Draining backend that is logged once it has no connections left
+*/
+struct DrainWatch {
+    balancing_algorithm: Arc<RwLock<RoundRobin>>,
+    addr: SocketAddr,
+    metrics: Arc<BackendMetrics>,
+}
+
+/**
+    Snapshot of a single client session
+*/
+#[derive(Serialize, Clone)]
+pub struct SessionInfo {
+    client: String,
+    backend: Option<String>,
+    pool: String,
+    state: &'static str,
+    duration_ms: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+impl SessionInfo {
+    pub fn new(client: &TcpClient) -> Self {
+        SessionInfo {
+            client: client.address.to_string(),
+            backend: client.get_target_addr().map(|t| t.to_string()),
+            pool: client.get_pool().name.clone(),
+            state: match (client.is_connected(), client.is_connecting()) {
+                (true, _) => "connected",
+                (false, true) => "connecting",
+                (false, false) if client.queued_since.is_some() => "queued",
+                // target is picked once the client sends data
+                (false, false) => "waiting",
+            },
+            duration_ms: client.stats.started.elapsed().as_millis() as u64,
+            bytes_in: client.stats.bytes_in,
+            bytes_out: client.stats.bytes_out,
+        }
+    }
+}
+
+/**
+    JSON API for inspecting and controlling the balancer at runtime. Requests are authorized with a bearer token if one is set.
+    Backends are addressed by their address, `/pools/<pool>/backends/<addr>` picks one of an address that is in multiple pools
+    (the first pool that has it is used otherwise)
+*/
+pub struct Admin {
+    pools: Vec<Arc<Pool>>,
+    metrics: Arc<Metrics>,
+    session_requests: SessionRequests,
+    token: Option<String>,
+    /**
+        Backends watched until they're drained, by a single thread that runs while there are any
+    */
+    draining: Arc<Mutex<Vec<DrainWatch>>>,
+}
+
+impl Admin {
+    pub fn new(pools: Vec<Arc<Pool>>, metrics: Arc<Metrics>, session_requests: SessionRequests, token: Option<String>) -> Self {
+        Admin {
+            pools,
+            metrics,
+            session_requests,
+            token,
+            draining: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    pub fn handle(&self, request: &HttpRequest) -> HttpResponse {
+        if let Some(token) = &self.token {
+            let authorized = request
+                .header("authorization")
+                .and_then(|h| h.strip_prefix("Bearer "))
+                .is_some_and(|t| constant_time_eq(t.as_bytes(), token.as_bytes()));
+            if !authorized {
+                return error(401, "missing or invalid token");
+            }
+        }
+
+        let mut segments: Vec<&str> = request.path.split('/').filter(|s| !s.is_empty()).collect();
+
+        // /pools/<pool>/backends/<addr>/... picks the backend of that pool, for backends in several pools
+        let mut pool_name = None;
+        if let ["pools", name, "backends", _, ..] = segments.as_slice() {
+            pool_name = Some(*name);
+            segments.drain(..2);
+        }
+
+        match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["backends"]) => self.list_backends(),
+            ("GET", ["backends", addr]) => match self.find_backend(pool_name, addr) {
+                Some((pool, a)) => json(200, self.backend_info(pool, &pool.balancing_algorithm.read().unwrap(), a)),
+                None => error(404, "unknown backend"),
+            },
+            ("GET", ["sessions"]) => self.list_sessions(),
+            ("GET", ["log-level"]) => json(200, json!({ "level": log::max_level().to_string().to_lowercase() })),
+            ("PUT", ["log-level"]) => self.set_log_level(request),
+            (method, ["backends", addr, action]) => {
+                let (pool, addr) = match self.find_backend(pool_name, addr) {
+                    Some(b) => b,
+                    None => return error(404, "unknown backend"),
+                };
+
+                match (method, *action) {
+                    ("POST", "enable") => self.set_state(pool, addr, HostState::Active),
+                    ("POST", "drain") => self.set_state(pool, addr, HostState::Draining),
+                    ("POST", "disable") => self.set_state(pool, addr, HostState::Disabled),
+                    ("PUT", "weight") => self.set_weight(pool, addr, request),
+                    ("POST", "check") => self.health_check(pool, addr),
+                    _ => error(404, "not found"),
+                }
+            }
+            _ => error(404, "not found"),
+        }
+    }
+
+    /**
+        Finds the backend in the pool named [pool_name], or in the first pool that has it if no pool is given
+    */
+    fn find_backend(&self, pool_name: Option<&str>, addr: &str) -> Option<(&Pool, SocketAddr)> {
+        let addr: SocketAddr = addr.parse().ok()?;
+        self.pools
+            .iter()
+            .filter(|p| pool_name.is_none_or(|n| p.name == n))
+            .find(|p| p.balancing_algorithm.read().unwrap().get_hosts().contains(&addr))
+            .map(|p| (p.as_ref(), addr))
+    }
+
+    fn list_backends(&self) -> HttpResponse {
+        let mut backends: Vec<Value> = vec![];
+        for pool in &self.pools {
+            let algorithm = pool.balancing_algorithm.read().unwrap();
+            backends.extend(algorithm.get_hosts().into_iter().map(|h| self.backend_info(pool, &algorithm, h)));
+        }
+
+        json(200, Value::Array(backends))
+    }
+
+    fn backend_info(&self, pool: &Pool, algorithm: &RoundRobin, addr: SocketAddr) -> Value {
+        let host_manager: &HostManager = algorithm.get_host_manager();
+        let state = host_manager.get_state(addr);
+        let on_cooldown = algorithm.is_on_cooldown(addr);
+        let active_connections = self.metrics.backend(addr).active_connections.load(Ordering::Relaxed);
+
+        json!({
+            "address": addr.to_string(),
+            "pool": pool.name,
+            "state": state.as_str(),
+            "weight": host_manager.get_weight(addr),
+            "tier": host_manager.get_tier(addr),
+            "effective_weight": (host_manager.get_effective_weight(addr) * 100.0).round() / 100.0,
+            "healthy": !on_cooldown,
+            "cooldown": on_cooldown,
+            "active_connections": active_connections,
+            "max_conns": host_manager.get_max_conns(addr),
+            // draining backends can be safely stopped once this is set
+            "drained": state == HostState::Draining && active_connections <= 0,
+        })
+    }
+
+    fn list_sessions(&self) -> HttpResponse {
+        // ask every worker for its sessions, workers that don't answer in time (e.g. stopped) are reported as such
+        let receivers: Vec<_> = self
+            .session_requests
+            .iter()
+            .map(|requests| {
+                let (tx, rx) = mpsc::channel();
+                requests.lock().unwrap().push(tx);
+                rx
+            })
+            .collect();
+
+        let deadline = Instant::now() + SESSIONS_TIMEOUT;
+        let workers: Vec<Value> = receivers
+            .into_iter()
+            .enumerate()
+            .map(|(id, rx)| match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(sessions) => json!({ "worker": id, "sessions": sessions }),
+                Err(_) => json!({ "worker": id, "sessions": null }),
+            })
+            .collect();
+
+        json(200, Value::Array(workers))
+    }
+
+    fn set_state(&self, pool: &Pool, addr: SocketAddr, state: HostState) -> HttpResponse {
+        let previous = {
+            let mut algorithm = pool.balancing_algorithm.write().unwrap();
+            let host_manager = algorithm.get_host_manager_mut();
+            let previous = host_manager.get_state(addr);
+            host_manager.set_state(addr, state);
+            previous
+        };
+
+        if state == HostState::Draining && previous != HostState::Draining {
+            self.watch_drain(pool, addr);
+        }
+
+        json(200, self.backend_info(pool, &pool.balancing_algorithm.read().unwrap(), addr))
+    }
+
+    /**
+        Logs once the draining backend has no connections left, stops watching if the backend is no longer draining
+    */
+    fn watch_drain(&self, pool: &Pool, addr: SocketAddr) {
+        let mut draining = self.draining.lock().unwrap();
+        if draining.iter().any(|w| Arc::ptr_eq(&w.balancing_algorithm, &pool.balancing_algorithm) && w.addr == addr) {
+            return;
+        }
+
+        draining.push(DrainWatch {
+            balancing_algorithm: Arc::clone(&pool.balancing_algorithm),
+            addr,
+            metrics: self.metrics.backend(addr),
+        });
+
+        // the watching thread exits once the list is empty, while holding the lock, so there is never more than one
+        if draining.len() == 1 {
+            let draining = Arc::clone(&self.draining);
+            thread::spawn(move || loop {
+                thread::sleep(DRAIN_CHECK_INTERVAL);
+
+                let mut draining = draining.lock().unwrap();
+                draining.retain(|w| {
+                    if w.balancing_algorithm.read().unwrap().get_host_manager().get_state(w.addr) != HostState::Draining {
+                        return false;
+                    }
+
+                    let drained = w.metrics.active_connections.load(Ordering::Relaxed) <= 0;
+                    if drained {
+                        info!(target: "Hosts", "Host {} drained, no connections left", w.addr);
+                    }
+                    !drained
+                });
+                if draining.is_empty() {
+                    return;
+                }
+            });
+        }
+    }
+
+    fn set_weight(&self, pool: &Pool, addr: SocketAddr, request: &HttpRequest) -> HttpResponse {
+        let weight = match parse_body(request).and_then(|b| b["weight"].as_u64()) {
+            Some(w) if w > 0 && w <= u32::MAX as u64 => w as u32,
+            _ => return error(400, "expected a body like {\"weight\": 5}, weight must be positive"),
+        };
+
+        pool.balancing_algorithm.write().unwrap().get_host_manager_mut().set_weight(addr, weight);
+        json(200, json!({ "address": addr.to_string(), "weight": weight }))
+    }
+
+    fn health_check(&self, pool: &Pool, addr: SocketAddr) -> HttpResponse {
+        let result = TcpStream::connect_timeout(&addr, HEALTH_CHECK_TIMEOUT);
+
+        let mut algorithm = pool.balancing_algorithm.write().unwrap();
+        match result {
+            Ok(_) => {
+                algorithm.report_success(addr);
+                info!(target: "Admin", "Health check of {} succeeded", addr);
+                json(200, json!({ "address": addr.to_string(), "healthy": true }))
+            }
+            Err(e) => {
+                algorithm.report_error(addr);
+                info!(target: "Admin", "Health check of {} failed -> {}", addr, e);
+                json(200, json!({ "address": addr.to_string(), "healthy": false, "error": e.to_string() }))
+            }
+        }
+    }
+
+    fn set_log_level(&self, request: &HttpRequest) -> HttpResponse {
+        let level = match parse_body(request) {
+            Some(b) => b["level"].as_str().unwrap_or("").to_string(),
+            None => String::new(),
+        };
+
+        if !Logger::set_level(&level) {
+            return error(400, "expected a body like {\"level\": \"debug\"} with a valid log level");
+        }
+
+        info!(target: "Admin", "Log level set to '{}'", level);
+        json(200, json!({ "level": level }))
+    }
+}
+
+/**
+    Compares without returning early on the first difference, so response times don't reveal how much of a guessed token is right
+*/
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn parse_body(request: &HttpRequest) -> Option<Value> {
+    serde_json::from_slice(&request.body).ok()
+}
+
+fn json(status: u16, value: Value) -> HttpResponse {
+    HttpResponse::new(status, "application/json", format!("{}\n", value))
+}
+
+fn error(status: u16, message: &str) -> HttpResponse {
+    json(status, json!({ "error": message }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_tokens() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
@@ -2,4 +2,5 @@ mod round_robin;
 
 pub use round_robin::RoundRobin;
 use super::BalancingAlgorithm;
-use super::HostManager;
\ No newline at end of file
+use super::HostManager;
+use super::HostState;
@@ -1,16 +1,22 @@
 use std::net::SocketAddr;
 use std::time::Duration;
 use std::time::Instant;
-use std::usize;
 
 use super::BalancingAlgorithm;
 use super::HostManager;
+use super::HostState;
 
+/**
+    Weighted round robin (smooth, as in nginx), hosts with equal weights are picked in turns
+*/
 pub struct RoundRobin {
     current_host: usize,
-    max_host: usize,
     host_manager: HostManager,
     cooldowns: Vec<(SocketAddr, Instant)>,
+    /**
+        Current weight of every host (in the order of the host list), the host with the highest one is picked next
+    */
+    current_weights: Vec<(SocketAddr, f64)>,
 }
 
 impl RoundRobin {
@@ -18,12 +24,11 @@ impl RoundRobin {
     const TARGET_DOWN_COOLDOWN: Duration = Duration::from_secs(30);
 
     pub fn new(host_manager: HostManager) -> Self {
-        let max = host_manager.hosts.len();
         RoundRobin {
             current_host: 0,
-            host_manager: host_manager,
-            max_host: max,
+            host_manager,
             cooldowns: vec![],
+            current_weights: vec![],
         }
     }
 
@@ -40,46 +45,103 @@ impl RoundRobin {
     }
 
     fn increment_host_counter(&mut self) {
-        self.current_host = self.current_host + 1;
-        if self.current_host >= self.max_host {
+        self.current_host += 1;
+        if self.current_host >= self.host_manager.hosts.len() {
             self.current_host = 0
         }
     }
 }
 
 impl BalancingAlgorithm for RoundRobin {
-    fn get_next_host(&mut self) -> SocketAddr {
-        let mut val;
-        let starting_host_index = self.current_host;
-
-        loop {
-            // select host
-            val = self.host_manager.hosts[self.current_host];
-
-            // offset host selector to next one
-            self.increment_host_counter();
-
-            // if host on cooldown, avoid it (but if we made a full cycle, just return the initial choice)
-            let cooldown_index = self.get_host_cooldown_index(val);
-            let cycle_reached = starting_host_index == self.current_host;
-            if cooldown_index >= 0 && !cycle_reached {
-                // check if cooldown has passed
-                if Instant::now() > self.cooldowns[cooldown_index as usize].1 {
-                    // cooldown passed, remove it
-                    self.cooldowns.remove(cooldown_index as usize);
-                    break;
-                }
+    fn get_next_host(&mut self) -> Option<SocketAddr> {
+        // apply any changes reported by service discovery
+        if self.host_manager.refresh() {
+            let hosts = &self.host_manager.hosts;
+            self.cooldowns.retain(|c| hosts.contains(&c.0));
+            if self.current_host >= hosts.len() {
+                self.current_host = 0;
+            }
+        }
+
+        // passed cooldowns are removed, hosts coming back from them go through slow start
+        let now = Instant::now();
+        for (host, _) in self.cooldowns.iter().filter(|c| now > c.1) {
+            self.host_manager.mark_recovered(*host);
+        }
+        self.cooldowns.retain(|c| now <= c.1);
+
+        if self.host_manager.hosts.is_empty() {
+            return None;
+        }
+
+        // only hosts of the tier currently getting traffic are considered
+        let cooldowns = &self.cooldowns;
+        let tier = self.host_manager.select_tier(|h| !cooldowns.iter().any(|c| c.0 == h));
+
+        // hosts keep their current weight when the host list changes, new ones (also replacing others) start from zero
+        let hosts = &self.host_manager.hosts;
+        if self.current_weights.len() != hosts.len() || self.current_weights.iter().zip(hosts).any(|(w, h)| w.0 != *h) {
+            let previous = std::mem::take(&mut self.current_weights);
+            self.current_weights = hosts.iter().map(|h| (*h, previous.iter().find(|w| w.0 == *h).map_or(0.0, |w| w.1))).collect();
+        }
+
+        // pick from hosts that are active and not on cooldown
+        let mut total_weight: f64 = 0.0;
+        let mut selected: Option<usize> = None;
+        let mut any_full = false;
+        for (i, host) in hosts.iter().enumerate() {
+            if self.host_manager.get_state(*host) != HostState::Active
+                || self.get_host_cooldown_index(*host) >= 0
+                || Some(self.host_manager.get_tier(*host)) != tier
+            {
+                continue;
+            }
+
+            // hosts at their connection limit can't take more clients
+            if self.host_manager.is_full(*host) {
+                any_full = true;
+                continue;
+            }
 
+            // hosts without weight (e.g. set to 0) get no clients
+            let weight = self.host_manager.get_effective_weight(*host);
+            if weight <= 0.0 {
                 continue;
-            } else if cycle_reached {
-                // cycle reached, let's increment the counter to continue trying different hosts until one actually connects
-                self.increment_host_counter();
             }
+            self.current_weights[i].1 += weight;
+            total_weight += weight;
+
+            if selected.is_none_or(|s| self.current_weights[i].1 > self.current_weights[s].1) {
+                selected = Some(i);
+            }
+        }
+
+        if let Some(i) = selected {
+            self.current_weights[i].1 -= total_weight;
+            return Some(hosts[i]);
+        }
 
-            break;
+        // clients should wait for a host with free capacity
+        if any_full {
+            return None;
         }
 
-        val
+        // all active hosts are on cooldown, keep trying different hosts (of the lowest tier) until one actually connects
+        let count = hosts.len();
+        let next = (0..count)
+            .map(|offset| (self.current_host + offset) % count)
+            .filter(|i| {
+                let host = hosts[*i];
+                self.host_manager.get_state(host) == HostState::Active
+                    && !self.host_manager.is_full(host)
+                    && self.host_manager.get_effective_weight(host) > 0.0
+            })
+            .min_by_key(|i| self.host_manager.get_tier(hosts[*i]))?;
+
+        let host = hosts[next];
+        self.current_host = next;
+        self.increment_host_counter();
+        Some(host)
     }
 
     fn report_error(&mut self, addr: SocketAddr) {
@@ -103,10 +165,103 @@ impl BalancingAlgorithm for RoundRobin {
         }
 
         self.cooldowns.remove(index as usize);
+        self.host_manager.mark_recovered(addr);
     }
 
     fn is_on_cooldown(&self, addr: SocketAddr) -> bool {
         let index: i32 = self.get_host_cooldown_index(addr);
-        return index >= 0;
+        index >= 0 && Instant::now() <= self.cooldowns[index as usize].1
+    }
+
+    fn get_hosts(&self) -> Vec<SocketAddr> {
+        self.host_manager.hosts.clone()
+    }
+
+    fn get_host_manager(&self) -> &HostManager {
+        &self.host_manager
+    }
+
+    fn get_host_manager_mut(&mut self) -> &mut HostManager {
+        &mut self.host_manager
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+        Balancer over the hosts in the given host file content
+    */
+    fn round_robin(name: &str, hosts: &str) -> RoundRobin {
+        let path = std::env::temp_dir().join(format!("lb-round-robin-{}-{}", std::process::id(), name));
+        std::fs::write(&path, hosts).unwrap();
+        let host_manager = HostManager::new(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        RoundRobin::new(host_manager)
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn picks(round_robin: &mut RoundRobin, count: usize) -> Vec<SocketAddr> {
+        (0..count).map(|_| round_robin.get_next_host().unwrap()).collect()
+    }
+
+    #[test]
+    fn picks_hosts_by_weight() {
+        let mut round_robin = round_robin("weights", "127.0.0.1:1\n127.0.0.1:2\n");
+        round_robin.get_host_manager_mut().set_weight(addr("127.0.0.1:1"), 3);
+
+        let picked = picks(&mut round_robin, 8);
+        assert_eq!(picked.iter().filter(|h| **h == addr("127.0.0.1:1")).count(), 6);
+        // smooth: the lighter host isn't left until the end
+        assert_eq!(picked[..4].iter().filter(|h| **h == addr("127.0.0.1:2")).count(), 1);
+    }
+
+    #[test]
+    fn skips_hosts_without_weight() {
+        let mut round_robin = round_robin("zero-weight", "127.0.0.1:1\n127.0.0.1:2\n");
+        round_robin.get_host_manager_mut().set_weight(addr("127.0.0.1:2"), 0);
+        assert!(picks(&mut round_robin, 4).iter().all(|h| *h == addr("127.0.0.1:1")));
+
+        // also when every host is on cooldown
+        round_robin.report_error(addr("127.0.0.1:1"));
+        round_robin.report_error(addr("127.0.0.1:2"));
+        assert!(picks(&mut round_robin, 4).iter().all(|h| *h == addr("127.0.0.1:1")));
+
+        round_robin.get_host_manager_mut().set_weight(addr("127.0.0.1:1"), 0);
+        assert_eq!(round_robin.get_next_host(), None);
+    }
+
+    #[test]
+    fn replaced_hosts_start_without_credit() {
+        let mut round_robin = round_robin("replaced", "127.0.0.1:1\n127.0.0.1:2\n");
+        let manager = round_robin.get_host_manager_mut();
+        manager.set_weight(addr("127.0.0.1:1"), 3);
+        manager.set_weight(addr("127.0.0.1:3"), 2);
+        assert_eq!(round_robin.get_next_host(), Some(addr("127.0.0.1:1")));
+
+        // :2 saved up credit, the host replacing it doesn't get it
+        round_robin.get_host_manager_mut().hosts[1] = addr("127.0.0.1:3");
+        assert_eq!(round_robin.get_next_host(), Some(addr("127.0.0.1:1")));
+        assert_eq!(round_robin.current_weights[1], (addr("127.0.0.1:3"), 2.0));
+    }
+
+    #[test]
+    fn falls_back_to_lowest_tier_when_all_on_cooldown() {
+        let mut round_robin = round_robin("tiers", "127.0.0.1:1 backup\n127.0.0.1:2 tier=2\n127.0.0.1:3\n127.0.0.1:4\n");
+        for port in 1..=4 {
+            round_robin.report_error(addr(&format!("127.0.0.1:{}", port)));
+        }
+
+        let picked = picks(&mut round_robin, 4);
+        assert_eq!(picked, vec![addr("127.0.0.1:3"), addr("127.0.0.1:4"), addr("127.0.0.1:3"), addr("127.0.0.1:4")]);
+
+        round_robin.get_host_manager_mut().set_state(addr("127.0.0.1:3"), HostState::Disabled);
+        round_robin.get_host_manager_mut().set_state(addr("127.0.0.1:4"), HostState::Draining);
+        assert_eq!(picks(&mut round_robin, 2), vec![addr("127.0.0.1:1"), addr("127.0.0.1:1")]);
     }
 }
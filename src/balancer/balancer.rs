@@ -1,14 +1,36 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::sync::Mutex;
 use std::sync::RwLock;
-use std::usize;
+use std::thread::JoinHandle;
+use std::time::Instant;
 use std::vec;
-use std::{thread, time::Duration, u16};
+use std::{thread, time::Duration};
 
+use super::AccessLog;
+use super::Admin;
 use super::BalancingAlgorithm;
+use super::BandwidthLimits;
+use super::CloseReason;
+use super::ConnectionPermit;
+use super::IdleConnections;
+use super::ListenerOptions;
+use super::Metrics;
+use super::Pool;
+use super::PoolStatus;
+use super::RejectReason;
 use super::RoundRobin;
+use super::SessionInfo;
+use super::SessionRequests;
 use super::TcpClient;
+use super::Throttle;
+use log::{debug, error, log_enabled, warn, Level};
 use mio::net::TcpStream;
 use mio::Events;
 use mio::Interest;
@@ -21,6 +43,13 @@ const TOTAL_CONNECTION_TIMEOUT: Duration = Duration::from_millis(4000);
 // this is used as the timeout to connect to a target host
 const CONNECTION_TIMEOUT: Duration = Duration::from_millis(400);
 
+type PendingClientLists = Arc<RwLock<Vec<Arc<RwLock<Vec<TcpClient>>>>>>;
+
+/**
+    Clients throttled by bandwidth limits, ordered by when they should be processed again
+*/
+type ThrottledClients = BinaryHeap<Reverse<(Instant, Token)>>;
+
 pub struct LoadBalancer {
     /**
         Holds client counts for all threads
@@ -29,15 +58,40 @@ pub struct LoadBalancer {
     /**
         Newly added clients are added here, threads will add them to polling when they can
     */
-    client_lists_pending: Arc<RwLock<Vec<Arc<RwLock<Vec<TcpClient>>>>>>,
+    client_lists_pending: PendingClientLists,
     threads: u16,
+    thread_handles: Vec<JoinHandle<()>>,
     stopped: Arc<RwLock<bool>>,
-    debug: Arc<RwLock<bool>>,
-    balancing_algorithm: Arc<RwLock<RoundRobin>>,
+    /**
+        Set when draining, workers keep serving existing clients until they finish or this deadline passes
+    */
+    drain_deadline: Arc<RwLock<Option<Instant>>>,
+    /**
+        Pool clients are routed to unless routed elsewhere, and all pools (including the default one)
+    */
+    default_pool: Arc<Pool>,
+    pools: Vec<Arc<Pool>>,
+    access_log: Option<Arc<AccessLog>>,
+    metrics: Arc<Metrics>,
+    session_requests: SessionRequests,
+    /**
+        How many clients every thread lets wait for a host with free capacity, and for how long
+    */
+    queue_size: usize,
+    queue_timeout: Duration,
+    bandwidth: BandwidthLimits,
+    /**
+        How long clients of listeners routing by SNI can take to send their ClientHello
+    */
+    client_hello_timeout: Duration,
+    /**
+        How long HTTP clients can take to send a request head
+    */
+    request_head_timeout: Duration,
 }
 
 impl LoadBalancer {
-    pub fn new(balancing_algorithm: RoundRobin, threads: u16, debug: bool) -> Self {
+    pub fn new(default_pool: Arc<Pool>, threads: u16) -> Self {
         // prepare client lists for every thread
         let mut client_counts: Vec<Arc<RwLock<usize>>> = vec![];
         for _ in 0..threads {
@@ -53,24 +107,101 @@ impl LoadBalancer {
         }
         let client_lists_pending = Arc::new(RwLock::new(client_lists_pending));
 
-        let b = LoadBalancer {
+        LoadBalancer {
             client_counts,
             client_lists_pending,
             threads,
+            thread_handles: vec![],
             stopped: Arc::new(RwLock::new(false)),
-            debug: Arc::new(RwLock::new(debug)),
-            balancing_algorithm: Arc::new(RwLock::new(balancing_algorithm)),
-        };
+            drain_deadline: Arc::new(RwLock::new(None)),
+            pools: vec![Arc::clone(&default_pool)],
+            default_pool,
+            access_log: None,
+            metrics: Arc::new(Metrics::new()),
+            session_requests: Arc::new((0..threads).map(|_| Mutex::new(vec![])).collect()),
+            queue_size: 100,
+            queue_timeout: Duration::from_secs(10),
+            bandwidth: BandwidthLimits::default(),
+            client_hello_timeout: Duration::from_secs(5),
+            request_head_timeout: Duration::from_secs(10),
+        }
+    }
 
-        b
+    /**
+        Sets how many clients every thread lets wait when all hosts are at their connection limit, and for how long.
+        Must be called before [start]
+    */
+    pub fn set_queue(&mut self, queue_size: usize, queue_timeout: Duration) {
+        self.queue_size = queue_size;
+        self.queue_timeout = queue_timeout;
+    }
+
+    /**
+        Sets how long clients of listeners routing by SNI can take to send their ClientHello before they're closed
+    */
+    pub fn set_client_hello_timeout(&mut self, timeout: Duration) {
+        self.client_hello_timeout = timeout;
+    }
+
+    /**
+        Sets how long HTTP clients can take to send a request head before they get a 408 response
+    */
+    pub fn set_request_head_timeout(&mut self, timeout: Duration) {
+        self.request_head_timeout = timeout;
+    }
+
+    /**
+        Adds a pool clients can be routed to. Must be called before [start]
+    */
+    pub fn add_pool(&mut self, pool: Arc<Pool>) {
+        self.pools.push(pool);
+    }
+
+    /**
+        Sets bandwidth limits of every client (pool limits are set on pools), applies to clients added afterwards
+    */
+    pub fn set_bandwidth_limits(&mut self, limits: BandwidthLimits) {
+        self.bandwidth = limits;
+    }
+
+    /**
+        Enables writing a record for every finished session. Must be called before [start]
+    */
+    pub fn set_access_log(&mut self, access_log: AccessLog) {
+        self.access_log = Some(Arc::new(access_log));
     }
 
     pub fn start(&mut self) {
         self.spawn_threads();
     }
 
-    pub fn add_client(&mut self, stream: TcpStream) {
-        let client = TcpClient::new(stream);
+    /**
+        Hands a new client over to the worker with the least clients. [addresses] are the original client and listener
+        addresses if the connection was relayed by a proxy, the connection's own addresses are used otherwise.
+        Clients of listeners routing by SNI get their pool from the listener's router, others use the default pool
+    */
+    pub fn add_client(&mut self, stream: TcpStream, addresses: Option<(SocketAddr, SocketAddr)>, listener: &ListenerOptions, permit: ConnectionPermit) {
+        let upload = Throttle::new(self.bandwidth.client_upload);
+        let download = Throttle::new(self.bandwidth.client_download);
+        let mut client = TcpClient::new(stream, Arc::clone(&self.metrics), permit, Arc::clone(&self.default_pool), upload, download);
+        if let Some((source, destination)) = addresses {
+            client.address = source;
+            client.local_address = destination;
+        }
+        client.set_sni_router(listener.sni_router.clone(), self.client_hello_timeout);
+        if let Some(acceptor) = &listener.tls {
+            match acceptor.accept() {
+                Ok(connection) => client.set_tls(connection),
+                Err(e) => {
+                    error!(target: "Listener", "Failed to start TLS for {} -> {}", client.address, e);
+                    return;
+                }
+            }
+        }
+        if let Some(router) = &listener.http_router {
+            client.set_http(Arc::clone(router), listener.forwarded_headers.clone(), listener.balance_requests, self.request_head_timeout);
+        }
+        self.metrics.accepted_connections.fetch_add(1, Ordering::Relaxed);
 
         // pick client list with least clients and add it to pending list
         let client_counts = self.client_counts.read().unwrap();
@@ -87,16 +218,95 @@ impl LoadBalancer {
             }
         }
 
-        if *self.debug.read().unwrap() {
-            println!("[Thread {}] Connected from {}", min_index, client.address);
+        // the target of the worker is only formatted when it's logged
+        if log_enabled!(Level::Debug) {
+            debug!(target: &format!("Thread {}", min_index), "Connected from {}", client.address);
         }
 
         // add client to pending list
         client_lists_pending[min_index].write().unwrap().push(client);
     }
 
+    /**
+        Counts a connection that was closed right after accepting it
+    */
+    pub fn reject_client(&self, reason: RejectReason) {
+        self.metrics.reject(reason);
+    }
+
+    /**
+        Returns a function rendering current metrics in the Prometheus text format, can be called from any thread
+    */
+    pub fn metrics_renderer(&self) -> impl Fn() -> String + Send + Sync + 'static {
+        let metrics = Arc::clone(&self.metrics);
+        let client_counts = Arc::clone(&self.client_counts);
+        let pools = self.pools.clone();
+
+        move || {
+            let worker_clients: Vec<usize> = client_counts.read().unwrap().iter().map(|c| *c.read().unwrap()).collect();
+
+            let pools: Vec<PoolStatus> = pools
+                .iter()
+                .map(|pool| {
+                    let algorithm = pool.balancing_algorithm.read().unwrap();
+                    let host_manager = algorithm.get_host_manager();
+                    PoolStatus {
+                        name: pool.name.clone(),
+                        hosts: algorithm
+                            .get_hosts()
+                            .into_iter()
+                            .map(|h| (h, algorithm.is_on_cooldown(h), host_manager.get_state(h)))
+                            .collect(),
+                        active_tier: host_manager.get_active_tier(),
+                        tier_shifts: host_manager.get_tier_shifts(),
+                    }
+                })
+                .collect();
+
+            metrics.render(&worker_clients, &pools)
+        }
+    }
+
+    /**
+        Returns the admin API operating on this balancer, requests are authorized with [token] if set
+    */
+    pub fn admin(&self, token: Option<String>) -> Admin {
+        Admin::new(
+            self.pools.clone(),
+            Arc::clone(&self.metrics),
+            Arc::clone(&self.session_requests),
+            token,
+        )
+    }
+
+    /**
+        Returns the number of clients currently handled by all threads
+    */
+    pub fn client_count(&self) -> usize {
+        self.client_counts.read().unwrap().iter().map(|c| *c.read().unwrap()).sum()
+    }
+
+    /**
+        Stops all worker threads immediately, dropping any existing connections
+    */
     pub fn stop(&mut self) {
         *self.stopped.write().unwrap() = true;
+        self.join_threads();
+    }
+
+    /**
+        Lets worker threads finish serving existing clients (no new clients should be added at this point).
+        Blocks until all clients are done or [timeout] passes, after which remaining clients are disconnected.
+    */
+    pub fn drain(&mut self, timeout: Duration) {
+        *self.drain_deadline.write().unwrap() = Some(Instant::now() + timeout);
+        self.join_threads();
+    }
+
+    fn join_threads(&mut self) {
+        for handle in self.thread_handles.drain(..) {
+            handle.join().unwrap_or(());
+        }
     }
 
     fn spawn_threads(&mut self) {
@@ -105,19 +315,27 @@ impl LoadBalancer {
         // WORKERS
         for id in 0..th {
             let stopped = Arc::clone(&self.stopped);
-            let d = Arc::clone(&self.debug);
-            let b = Arc::clone(&self.balancing_algorithm);
+            let drain_deadline = Arc::clone(&self.drain_deadline);
             let client_counts = Arc::clone(&self.client_counts);
             let client_list_pending = Arc::clone(&self.client_lists_pending);
+            let access_log = self.access_log.clone();
+            let metrics = Arc::clone(&self.metrics);
+            let session_requests = Arc::clone(&self.session_requests);
+            let (queue_size, queue_timeout) = (self.queue_size, self.queue_timeout);
 
-            thread::spawn(move || {
+            let handle = thread::spawn(move || {
                 let mut connected_sockets: HashMap<Token, TcpClient> = HashMap::new();
+                // clients waiting for a host with free capacity, in order of arrival
+                let mut queue: VecDeque<Token> = VecDeque::new();
+                let mut throttled: ThrottledClients = BinaryHeap::new();
+                // keep-alive connections to backends between HTTP requests, shared by the worker's clients
+                let idle_connections = Arc::new(Mutex::new(IdleConnections::new()));
                 let mut next_token_id: usize = 0;
 
                 let mut get_next_token = || {
                     let token = Token(next_token_id);
                     next_token_id += 1;
-                    if next_token_id >= usize::MAX {
+                    if next_token_id == usize::MAX {
                         next_token_id = 1;
                     }
                     token
@@ -125,6 +343,10 @@ impl LoadBalancer {
 
                 let client_list_index = id as usize;
 
+                // log target for this worker
+                let target = format!("Thread {}", id);
+                let target = target.as_str();
+
                 let mut poll = Poll::new().unwrap();
                 let mut events = Events::with_capacity(1024);
 
@@ -134,17 +356,34 @@ impl LoadBalancer {
                         break;
                     }
 
+                    // when draining, exit once all clients are done or the deadline passes
+                    if let Some(deadline) = *drain_deadline.read().unwrap() {
+                        let pending = client_list_pending.read().unwrap()[client_list_index].read().unwrap().len();
+                        if connected_sockets.is_empty() && pending == 0 {
+                            break;
+                        }
+
+                        if Instant::now() > deadline {
+                            warn!(
+                                target: target,
+                                "Drain deadline passed, dropping {} remaining clients",
+                                connected_sockets.len() + pending
+                            );
+                            break;
+                        }
+                    }
+
                     // -------------------------------
                     // EVENT POLLING
                     // -------------------------------
                     match poll.poll(&mut events, Some(Duration::from_millis(10))) {
                         Ok(_) => {}
                         Err(ref e) if e.kind() == ErrorKind::Interrupted => {
-                            // this handler does not get called on Windows, so we use timeout and check it outside
-                            *stopped.write().unwrap() = true;
+                            // stopping is initiated by the listener, which may still want us to drain existing clients
+                            continue;
                         }
                         Err(e) => {
-                            println!("[Thread {}] Failed to poll for events! {}", id, e.to_string());
+                            error!(target: target, "Failed to poll for events! {}", e);
                             break;
                         }
                     };
@@ -171,10 +410,14 @@ impl LoadBalancer {
                             for i in 0..plen {
                                 let index = (plen - 1) - i;
                                 let mut client = pending.remove(index);
+                                client.set_idle_connections(Arc::clone(&idle_connections));
 
                                 let token = get_next_token();
 
-                                poll.registry().register(&mut client.stream, token, Interest::READABLE).unwrap();
+                                // writable too, so data the client couldn't take right away is sent once it can
+                                poll.registry()
+                                    .register(&mut client.stream, token, Interest::READABLE | Interest::WRITABLE)
+                                    .unwrap();
 
                                 // insert into hashmap for quick lookup
                                 connected_sockets.insert(token, client);
@@ -194,11 +437,32 @@ impl LoadBalancer {
                         for (token, client) in &mut connected_sockets {
                             // if client not connected, schedule for removal
                             if !client.is_client_connected() {
-                                let t = Box::new(token.clone());
+                                let t = Box::new(*token);
                                 tokens_to_remove.push(t);
                                 continue;
                             }
 
+                            // HANDLE QUEUE TIMEOUT
+                            if client.queued_since.is_some_and(|q| q.elapsed() > queue_timeout) {
+                                warn!(target: target, "No host with free capacity in time, disconnecting client ({})", client.address);
+                                client.close_connection(CloseReason::QueueTimeout);
+                                continue;
+                            }
+
+                            // HANDLE CLIENTHELLO TIMEOUT (clients routed by SNI)
+                            if client.hello_deadline.is_some_and(|d| Instant::now() > d) {
+                                debug!(target: target, "No ClientHello in time, disconnecting client ({})", client.address);
+                                client.close_connection(CloseReason::Timeout);
+                                continue;
+                            }
+
+                            // HANDLE REQUEST HEAD TIMEOUT (HTTP clients)
+                            if client.head_deadline.is_some_and(|d| Instant::now() > d) {
+                                debug!(target: target, "No request head in time, disconnecting client ({})", client.address);
+                                client.head_timed_out();
+                                continue;
+                            }
+
                             // if client not in IN_CONNECTING state, we can't check for time outs
                             if !client.is_connecting() {
                                 continue;
@@ -206,45 +470,49 @@ impl LoadBalancer {
 
                             // HANDLE TIMEOUT TO SINGLE TARGET
                             if client.started_connecting.elapsed() > CONNECTION_TIMEOUT {
-                                if *d.read().unwrap() {
-                                    println!(
-                                        "[Thread {}] Connection to target timed out ({} <-> {})",
-                                        id,
-                                        client.address,
-                                        client.get_target_addr().unwrap()
-                                    );
-                                }
+                                warn!(
+                                    target: target,
+                                    "Connection to target timed out ({} <-> {})",
+                                    client.address,
+                                    client.get_target_addr().unwrap()
+                                );
+
+                                metrics.backend(client.get_target_addr().unwrap()).connect_timeouts.fetch_add(1, Ordering::Relaxed);
 
                                 // we timed out! Let's try another host
                                 client.close_connection_to_target(true);
-                                LoadBalancer::report_target_error(client, Arc::clone(&b));
-                                LoadBalancer::start_connection(id, token.clone(), client, &poll, Arc::clone(&d), Arc::clone(&b));
+                                LoadBalancer::report_target_error(client, client.balancing_algorithm());
+                                if !LoadBalancer::start_connection(target, *token, client, &poll, client.balancing_algorithm()) {
+                                    LoadBalancer::queue_client(target, *token, client, &mut queue, queue_size);
+                                }
                             }
 
                             // HANDLE TOTAL TIMEOUT
                             if client.last_connection_loss.elapsed() > TOTAL_CONNECTION_TIMEOUT {
-                                if *d.read().unwrap() {
-                                    println!("[Thread {}] Timed out ({})", id, client.address);
-                                }
+                                debug!(target: target, "Timed out ({})", client.address);
 
                                 // we timed out completely!
-                                client.close_connection();
+                                client.close_connection(CloseReason::Timeout);
                             }
                         }
 
+                        idle_connections.lock().unwrap().expire();
+
                         // now remove the marked clients
-                        if tokens_to_remove.len() > 0 {
+                        if !tokens_to_remove.is_empty() {
                             for token in tokens_to_remove {
                                 let mut client = connected_sockets.remove(&token).unwrap();
                                 poll.registry().deregister(&mut client.stream).unwrap();
 
-                                if *d.read().unwrap() {
-                                    println!(
-                                        "[Thread {}] Connection ended ({}) [Remaining clients: {}]",
-                                        id,
-                                        client.address,
-                                        connected_sockets.len()
-                                    );
+                                debug!(
+                                    target: target,
+                                    "Connection ended ({}) [Remaining clients: {}]",
+                                    client.address,
+                                    connected_sockets.len()
+                                );
+
+                                if let Some(log) = &access_log {
+                                    log.log(id, &client);
                                 }
                             }
 
@@ -253,6 +521,69 @@ impl LoadBalancer {
                         }
                     }
 
+                    // -------------------------------
+                    // PROCESS QUEUED CLIENTS (in order, as long as hosts of their pool have free capacity)
+                    // -------------------------------
+                    if !queue.is_empty() {
+                        // once a client of a pool has to keep waiting, later clients of that pool have to as well
+                        let mut full_pools: Vec<Arc<Pool>> = vec![];
+                        queue.retain(|token| {
+                            let client = match connected_sockets.get_mut(token) {
+                                Some(c) if c.is_client_connected() && c.queued_since.is_some() => c,
+                                _ => return false,
+                            };
+
+                            if full_pools.iter().any(|p| Arc::ptr_eq(p, client.get_pool())) {
+                                return true;
+                            }
+                            if !LoadBalancer::start_connection(target, *token, client, &poll, client.balancing_algorithm()) {
+                                full_pools.push(Arc::clone(client.get_pool()));
+                                return true;
+                            }
+
+                            client.queued_since = None;
+                            false
+                        });
+                    }
+
+                    // -------------------------------
+                    // ANSWER SESSION LISTING (admin API)
+                    // -------------------------------
+                    if let Ok(mut requests) = session_requests[client_list_index].try_lock() {
+                        if !requests.is_empty() {
+                            let sessions: Vec<SessionInfo> = connected_sockets.values().map(SessionInfo::new).collect();
+                            for request in requests.drain(..) {
+                                request.send(sessions.clone()).unwrap_or(());
+                            }
+                        }
+                    }
+
+                    // -------------------------------
+                    // RESUME THROTTLED CLIENTS (bandwidth limits allow reading again)
+                    // -------------------------------
+                    let now = Instant::now();
+                    while let Some(Reverse((_, token))) = throttled.peek().filter(|Reverse((at, _))| *at <= now).copied() {
+                        throttled.pop();
+
+                        let client = match connected_sockets.get_mut(&token) {
+                            Some(c) if c.is_client_connected() => c,
+                            _ => continue,
+                        };
+
+                        // entry of a client that was resumed already and throttled again since
+                        if client.throttled_until.is_none_or(|t| t > now) {
+                            continue;
+                        }
+                        client.throttled_until = None;
+                        if client.is_connected() {
+                            LoadBalancer::process_client(token, client, &mut throttled, client.balancing_algorithm());
+
+                            if client.needs_target() && !LoadBalancer::start_connection(target, token, client, &poll, client.balancing_algorithm()) {
+                                LoadBalancer::queue_client(target, token, client, &mut queue, queue_size);
+                            }
+                        }
+                    }
+
                     // ------------------------------
                     // EVENT LOOP
                     // ------------------------------
@@ -260,44 +591,62 @@ impl LoadBalancer {
                         continue;
                     }
                     for event in events.iter() {
-                        match event.token() {
-                            token => {
-                                let client = match connected_sockets.get_mut(&token) {
-                                    Some(c) => c,
-                                    None => {
-                                        // println!("ERROR - Tried getting client that was not present in hash map! -> token: {:?}", token);
-                                        // TODO: maybe deregister from poll if this is ever even called
-                                        continue;
-                                    }
-                                };
-
-                                if !client.is_client_connected() {
-                                    // ignore, will be handled in later loop and cleaned
-                                    continue;
-                                }
+                        let token = event.token();
+                        let client = match connected_sockets.get_mut(&token) {
+                            Some(c) => c,
+                            None => {
+                                // trace!(target: target, "Tried getting client that was not present in hash map! -> token: {:?}", token);
+                                // TODO: maybe deregister from poll if this is ever even called
+                                continue;
+                            }
+                        };
 
-                                // if client is in process of connecting, check if connection has been established
-                                if client.is_connecting() {
-                                    LoadBalancer::try_confirm_connection(id, client, Arc::clone(&d), Arc::clone(&b));
-                                }
+                        if !client.is_client_connected() {
+                            // ignore, will be handled in later loop and cleaned
+                            continue;
+                        }
 
-                                // if connected, process it normally, otherwise start a new connection to next host
-                                if client.is_connected() {
-                                    LoadBalancer::process_client(client, Arc::clone(&b));
-                                } else if !client.is_connecting() {
-                                    LoadBalancer::start_connection(id, token, client, &poll, Arc::clone(&d), Arc::clone(&b));
-                                }
+                        // if client is in process of connecting, check if connection has been established
+                        if client.is_connecting() {
+                            LoadBalancer::try_confirm_connection(target, client, client.balancing_algorithm());
+                        }
+
+                        // if connected, process it normally, otherwise start a new connection to next host once the client sends data (unless waiting in queue)
+                        // clients of listeners routing by SNI are routed once their ClientHello arrived
+                        if client.is_connected() {
+                            LoadBalancer::process_client(token, client, &mut throttled, client.balancing_algorithm());
+
+                            // HTTP clients need a new target once a request goes to another pool, or the target closed between requests
+                            if client.needs_target() && !LoadBalancer::start_connection(target, token, client, &poll, client.balancing_algorithm()) {
+                                LoadBalancer::queue_client(target, token, client, &mut queue, queue_size);
                             }
+                        } else if event.is_readable()
+                            && !client.is_connecting()
+                            && client.queued_since.is_none()
+                            && client.route()
+                            && !LoadBalancer::start_connection(target, token, client, &poll, client.balancing_algorithm())
+                        {
+                            LoadBalancer::queue_client(target, token, client, &mut queue, queue_size);
                         }
                     }
                 }
+
+                // worker stopped, any remaining clients are disconnected
+                for (_, mut client) in connected_sockets.drain() {
+                    client.close_connection(CloseReason::Shutdown);
+                    if let Some(log) = &access_log {
+                        log.log(id, &client);
+                    }
+                }
             });
+
+            self.thread_handles.push(handle);
         }
     }
 
-    fn try_confirm_connection(id: u32, client: &mut TcpClient, d: Arc<RwLock<bool>>, b: Arc<RwLock<RoundRobin>>) {
+    fn try_confirm_connection(target: &str, client: &mut TcpClient, b: Arc<RwLock<RoundRobin>>) {
         let server_connected = client.check_target_connected().unwrap_or_else(|e| {
-            println!("Not connected unknown error -> {}", e.to_string());
+            warn!(target: target, "Not connected unknown error -> {}", e);
             // TODO: should probably disconnect - there was an error while connecting other than NotConnected
             false
         });
@@ -305,9 +654,7 @@ impl LoadBalancer {
         if server_connected {
             let addr = client.get_target_addr().unwrap();
 
-            if *d.read().unwrap() && !client.is_connecting() {
-                println!("[Thread {}] Client connected to target ({} -> {})", id, client.address, addr);
-            }
+            debug!(target: target, "Client connected to target ({} -> {})", client.address, addr);
 
             // report success if connection succeeded
             if b.read().unwrap().is_on_cooldown(addr) {
@@ -316,10 +663,21 @@ impl LoadBalancer {
         }
     }
 
-    fn process_client(client: &mut TcpClient, b: Arc<RwLock<RoundRobin>>) {
+    /**
+        Forwards data of the client, scheduling it to be processed again if it gets throttled
+    */
+    fn process_client(token: Token, client: &mut TcpClient, throttled: &mut ThrottledClients, b: Arc<RwLock<RoundRobin>>) {
+        let throttled_until = client.throttled_until;
         let success = client.process();
 
-        if success == false {
+        // clients are added to the heap again when they have to be resumed earlier, the later entry is skipped then
+        if let Some(at) = client.throttled_until {
+            if throttled_until.is_none_or(|t| at < t) {
+                throttled.push(Reverse((at, token)));
+            }
+        }
+
+        if !success {
             // connection to either server or client has failed
 
             // removal from list is handled later
@@ -328,25 +686,35 @@ impl LoadBalancer {
         }
     }
 
-    fn start_connection(id: u32, token: Token, client: &mut TcpClient, poll: &Poll, d: Arc<RwLock<bool>>, b: Arc<RwLock<RoundRobin>>) {
+    /**
+        Starts connecting the client to the next host. Returns [false] if all hosts are at their connection limit, the client should then wait
+    */
+    fn start_connection(target: &str, token: Token, client: &mut TcpClient, poll: &Poll, b: Arc<RwLock<RoundRobin>>) -> bool {
         // determine target host to connect to, using the balancing algorithm!
-        let target_socket = match client.get_target_addr() {
-            Some(s) => s,
-            None => b.write().unwrap().get_next_host(),
+        // the connection is counted while the algorithm is still locked, so other threads see it when selecting
+        let (target_socket, slot) = {
+            let mut algorithm = b.write().unwrap();
+            match client.get_target_addr().or_else(|| algorithm.get_next_host()) {
+                Some(s) => (s, algorithm.get_host_manager_mut().acquire(s)),
+                None if algorithm.get_host_manager().has_full_hosts() => return false,
+                None => {
+                    warn!(target: target, "No hosts available, disconnecting client ({})", client.address);
+                    client.close_connection(CloseReason::NoHosts);
+                    return true;
+                }
+            }
         };
 
-        if *d.read().unwrap() && !client.is_connecting() {
-            println!("[Thread {}] Connecting client ({} -> {})", id, client.address, target_socket);
-        }
+        debug!(target: target, "Connecting client ({} -> {})", client.address, target_socket);
 
         // connect to target
-        let success = match client.connect_to_target(target_socket) {
+        let success = match client.connect_to_target(target_socket, slot) {
             Ok(s) => s,
             Err(e) => {
-                println!(
-                    "[Thread {}] Unexpected error while trying to start a connection! {} ({} -> {})",
-                    id,
-                    e.to_string(),
+                error!(
+                    target: target,
+                    "Unexpected error while trying to start a connection! {} ({} -> {})",
+                    e,
                     client.address,
                     target_socket
                 );
@@ -357,18 +725,37 @@ impl LoadBalancer {
         if success {
             // connection to target host started
             // add server to poll (with same token as client)
-            client.register_target_with_poll(&poll, token);
+            client.register_target_with_poll(poll, token);
         } else {
             // report host error to host manager
             LoadBalancer::report_target_error(client, Arc::clone(&b));
         }
+
+        true
+    }
+
+    /**
+        Lets the client wait for a host with free capacity, or disconnects it if the queue is full
+    */
+    fn queue_client(target: &str, token: Token, client: &mut TcpClient, queue: &mut VecDeque<Token>, queue_size: usize) {
+        if queue.len() >= queue_size {
+            warn!(target: target, "All hosts are at their connection limit and queue is full, disconnecting client ({})", client.address);
+            client.close_connection(CloseReason::QueueFull);
+            return;
+        }
+
+        debug!(target: target, "All hosts are at their connection limit, client is waiting ({})", client.address);
+        client.queued_since = Some(Instant::now());
+        queue.push_back(token);
     }
 
     fn report_target_error(client: &mut TcpClient, b: Arc<RwLock<RoundRobin>>) {
         // report host error to host manager
         let last_t = client.get_last_target_addr();
-        if client.last_target_errored() && last_t.is_some() {
-            b.write().unwrap().report_error(last_t.unwrap());
+        if let Some(addr) = last_t {
+            if client.last_target_errored() {
+                b.write().unwrap().report_error(addr);
+            }
         }
     }
 }
@@ -1,9 +1,12 @@
 use std::net::SocketAddr;
+
+use super::HostManager;
+
 pub trait BalancingAlgorithm: Sync + Send {
     /**
-        Returns the next host for the client to try to connect to    
+        Returns the next host for the client to try to connect to, [None] if there are no hosts available    
     */
-    fn get_next_host(&mut self) -> SocketAddr;
+    fn get_next_host(&mut self) -> Option<SocketAddr>;
     /**
         Reports error for the given host address. Host can then be placed on cooldown, this can affect the [get_next_host] call
     */
@@ -16,4 +19,13 @@ pub trait BalancingAlgorithm: Sync + Send {
         Checks if host is currently on cooldown or in any way affected by the reported errors
     */
     fn is_on_cooldown(&self, addr: SocketAddr) -> bool;
+    /**
+        Returns all hosts currently known to the algorithm
+    */
+    fn get_hosts(&self) -> Vec<SocketAddr>;
+    /**
+        Returns the host manager, holding host states and weights
+    */
+    fn get_host_manager(&self) -> &HostManager;
+    fn get_host_manager_mut(&mut self) -> &mut HostManager;
 }
@@ -0,0 +1,111 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{RateLimit, TokenBucket};
+
+// throttled reads are deferred until at least this many bytes can be forwarded (or the whole burst, if it's smaller),
+// so slow connections aren't woken up for every few bytes
+const MIN_READ: f64 = 1024.0;
+
+/**
+    Bandwidth limits in bytes per second, [None] is unlimited. Upload is from clients to backends, download from backends to clients.
+    Client limits apply to every connection on its own, pool limits to all connections to the backend pool together
+*/
+#[derive(Clone, Copy, Default)]
+pub struct BandwidthLimits {
+    pub client_upload: Option<RateLimit>,
+    pub client_download: Option<RateLimit>,
+    pub pool_upload: Option<RateLimit>,
+    pub pool_download: Option<RateLimit>,
+}
+
+/**
+    Token bucket shared by all connections it limits, used from every worker thread
+*/
+#[derive(Clone)]
+pub struct SharedBucket {
+    limit: RateLimit,
+    bucket: Arc<Mutex<TokenBucket>>,
+}
+
+impl SharedBucket {
+    /**
+        Returns [None] if there is no limit
+    */
+    pub fn new(limit: Option<RateLimit>) -> Option<Self> {
+        limit.map(|limit| SharedBucket {
+            limit,
+            bucket: Arc::new(Mutex::new(TokenBucket::new(&limit, Instant::now()))),
+        })
+    }
+}
+
+/**
+    Bandwidth throttling of a single direction of a connection
+*/
+pub struct Throttle {
+    client: Option<(RateLimit, TokenBucket)>,
+    pool: Option<SharedBucket>,
+}
+
+impl Throttle {
+    pub fn new(client: Option<RateLimit>) -> Self {
+        Throttle {
+            client: client.map(|l| (l, TokenBucket::new(&l, Instant::now()))),
+            pool: None,
+        }
+    }
+
+    /**
+        Sets the bucket shared with all clients of the backend pool the client is routed to
+    */
+    pub fn set_pool(&mut self, pool: Option<SharedBucket>) {
+        self.pool = pool;
+    }
+
+    /**
+        Returns how many bytes can be forwarded right now, at most [max]
+    */
+    pub fn available(&mut self, max: usize) -> usize {
+        let now = Instant::now();
+        let mut available = max as f64;
+
+        if let Some((limit, bucket)) = &mut self.client {
+            available = available.min(bucket.available(limit, now));
+        }
+        if let Some(pool) = &self.pool {
+            available = available.min(pool.bucket.lock().unwrap().available(&pool.limit, now));
+        }
+
+        available.max(0.0) as usize
+    }
+
+    /**
+        Counts forwarded bytes. Other connections may have used the pool bucket since [available] was called, it then goes into debt
+    */
+    pub fn consume(&mut self, bytes: usize) {
+        if let Some((_, bucket)) = &mut self.client {
+            bucket.take(bytes as f64);
+        }
+        if let Some(pool) = &self.pool {
+            pool.bucket.lock().unwrap().take(bytes as f64);
+        }
+    }
+
+    /**
+        Returns when it's worth reading again, once all buckets have refilled enough
+    */
+    pub fn resume_at(&self) -> Instant {
+        let now = Instant::now();
+        let mut wait = Duration::ZERO;
+
+        if let Some((limit, bucket)) = &self.client {
+            wait = wait.max(bucket.time_until(limit, MIN_READ, now));
+        }
+        if let Some(pool) = &self.pool {
+            wait = wait.max(pool.bucket.lock().unwrap().time_until(&pool.limit, MIN_READ, now));
+        }
+
+        now + wait
+    }
+}
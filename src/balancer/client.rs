@@ -4,20 +4,100 @@ use std::io::Result;
 use std::net::Shutdown;
 use std::net::SocketAddr;
 
-use std::time::Instant;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
+use log::{debug, warn};
 use mio::net::TcpStream;
 use mio::Interest;
 use mio::Poll;
 use mio::Token;
+use rustls::Connection;
+
+use super::http::{self, HttpSession, Upload};
+use super::sni::{self, ParsedHello};
+use super::tls::MaybeTlsStream;
+use super::{BackendMetrics, ConnectionPermit, ConnectionSlot, ForwardedHeaders, HttpRouter, IdleConnection, IdleConnections, Metrics, Pool};
+use super::{RoundRobin, SniRouter, Throttle};
+
+/**
+    Why the client connection was closed
+*/
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CloseReason {
+    ClientClosed,
+    ClientError,
+    /**
+        The backend's response ended the connection, or the backend failed during an HTTP request
+    */
+    ServerClosed,
+    Timeout,
+    NoHosts,
+    QueueFull,
+    QueueTimeout,
+    Shutdown,
+}
+
+impl CloseReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CloseReason::ClientClosed => "client_closed",
+            CloseReason::ClientError => "client_error",
+            CloseReason::ServerClosed => "server_closed",
+            CloseReason::Timeout => "timeout",
+            CloseReason::NoHosts => "no_hosts",
+            CloseReason::QueueFull => "queue_full",
+            CloseReason::QueueTimeout => "queue_timeout",
+            CloseReason::Shutdown => "shutdown",
+        }
+    }
+}
+
+/**
+    Counters for a single proxied session, used for access logging
+*/
+pub struct ClientStats {
+    pub started_at: SystemTime,
+    pub started: Instant,
+    /**
+        Every target a connection was attempted to, in order
+    */
+    pub targets: Vec<SocketAddr>,
+    /**
+        How long it took to establish the last successful connection to a target
+    */
+    pub connect_latency: Option<Duration>,
+    /**
+        Bytes received from the client (and forwarded to targets)
+    */
+    pub bytes_in: u64,
+    /**
+        Bytes sent to the client (received from targets)
+    */
+    pub bytes_out: u64,
+    pub close_reason: Option<CloseReason>,
+}
 
 pub struct TcpClient {
     pub stream: TcpStream,
     buffer: [u8; 4096],
 
     pub address: SocketAddr,
+    /**
+        Address of the listener the client connected to
+    */
+    pub local_address: SocketAddr,
     target: Option<SocketAddr>,
     target_stream: Option<TcpStream>,
+    /**
+        Set if the target's pool talks TLS with its backends
+    */
+    target_tls: Option<Connection>,
+    /**
+        Data sent to the target as is before anything else, even before TLS
+    */
+    target_preamble: Vec<u8>,
     is_connected: bool,
     is_connecting: bool,
     is_client_connected: bool,
@@ -25,18 +105,82 @@ pub struct TcpClient {
     pub started_connecting: Instant,
     last_target: Option<SocketAddr>,
     last_target_error: bool,
+    pub stats: ClientStats,
+    metrics: Arc<Metrics>,
+    target_metrics: Option<Arc<BackendMetrics>>,
+    connection_slot: Option<ConnectionSlot>,
+    /**
+        Set while the client waits for a host with free capacity
+    */
+    pub queued_since: Option<Instant>,
+    /**
+        Bandwidth limits of data from the client and data to the client
+    */
+    upload: Throttle,
+    download: Throttle,
+    /**
+        Data read from one side that the other side couldn't take yet
+    */
+    upload_pending: Vec<u8>,
+    download_pending: Vec<u8>,
+    /**
+        Set when a read was deferred because of bandwidth limits, the client should be processed again at this time
+    */
+    pub throttled_until: Option<Instant>,
+    /**
+        Pool the client's targets are picked from
+    */
+    pool: Arc<Pool>,
+    /**
+        Set until the client is routed to a pool by the server name in its TLS ClientHello
+    */
+    sni_router: Option<Arc<SniRouter>>,
+    /**
+        Set while the client is routed by SNI, the client is closed if its ClientHello didn't arrive by then
+    */
+    pub hello_deadline: Option<Instant>,
+    /**
+        What's been peeked of the ClientHello while routing by SNI, only grows as needed
+    */
+    hello_buffer: Vec<u8>,
+    /**
+        Set if the client's TLS is terminated, data is forwarded decrypted
+    */
+    tls: Option<Connection>,
+    /**
+        Set if the client speaks HTTP, its requests are routed one by one
+    */
+    http: Option<HttpSession>,
+    /**
+        Set while an HTTP client is expected to send its next request head, it gets a 408 response if the head didn't
+        arrive by then (or is closed if it sent nothing of it)
+    */
+    pub head_deadline: Option<Instant>,
+    head_timeout: Duration,
+    /**
+        Idle keep-alive connections of the client's worker, targets of HTTP clients are taken from and returned to them
+    */
+    idle_connections: Option<Arc<Mutex<IdleConnections>>>,
+    /**
+        Counts the client towards the client connection limits until it's dropped
+    */
+    _permit: ConnectionPermit,
 }
 
 impl TcpClient {
-    pub fn new(stream: TcpStream) -> Self {
+    pub fn new(stream: TcpStream, metrics: Arc<Metrics>, permit: ConnectionPermit, pool: Arc<Pool>, upload: Throttle, download: Throttle) -> Self {
         let addr: SocketAddr = stream.peer_addr().unwrap();
+        let local_addr: SocketAddr = stream.local_addr().unwrap();
 
-        TcpClient {
-            stream: stream,
+        let mut client = TcpClient {
+            stream,
             buffer: [0; 4096],
             target: None,
             target_stream: None,
+            target_tls: None,
+            target_preamble: vec![],
             address: addr,
+            local_address: local_addr,
             is_connected: false,
             is_connecting: false,
             is_client_connected: true,
@@ -44,13 +188,221 @@ impl TcpClient {
             started_connecting: Instant::now(),
             last_target: None,
             last_target_error: false,
+            stats: ClientStats {
+                started_at: SystemTime::now(),
+                started: Instant::now(),
+                targets: vec![],
+                connect_latency: None,
+                bytes_in: 0,
+                bytes_out: 0,
+                close_reason: None,
+            },
+            metrics,
+            target_metrics: None,
+            connection_slot: None,
+            queued_since: None,
+            upload,
+            download,
+            upload_pending: vec![],
+            download_pending: vec![],
+            throttled_until: None,
+            pool: Arc::clone(&pool),
+            sni_router: None,
+            hello_deadline: None,
+            hello_buffer: vec![],
+            tls: None,
+            http: None,
+            head_deadline: None,
+            head_timeout: Duration::ZERO,
+            idle_connections: None,
+            _permit: permit,
+        };
+        client.set_pool(pool);
+
+        client
+    }
+
+    /**
+        Picks targets from [pool] from now on
+    */
+    pub fn set_pool(&mut self, pool: Arc<Pool>) {
+        self.upload.set_pool(pool.bandwidth.0.clone());
+        self.download.set_pool(pool.bandwidth.1.clone());
+        self.pool = pool;
+    }
+
+    pub fn get_pool(&self) -> &Arc<Pool> {
+        &self.pool
+    }
+
+    /**
+        Returns the balancing algorithm of the client's pool
+    */
+    pub fn balancing_algorithm(&self) -> Arc<RwLock<RoundRobin>> {
+        Arc::clone(&self.pool.balancing_algorithm)
+    }
+
+    /**
+        Routes the client by the server name in its TLS ClientHello before a target is picked, the ClientHello has to arrive
+        within [timeout]
+    */
+    pub fn set_sni_router(&mut self, sni_router: Option<Arc<SniRouter>>, timeout: Duration) {
+        self.hello_deadline = sni_router.as_ref().map(|_| Instant::now() + timeout);
+        self.sni_router = sni_router;
+    }
+
+    /**
+        Picks the pool of a client waiting to be routed by SNI. The ClientHello is only peeked at, so it's forwarded unmodified
+        together with everything after it. Returns [false] while more data is needed
+    */
+    pub fn route(&mut self) -> bool {
+        if self.http.is_some() {
+            let routed = self.route_request();
+            self.update_head_deadline();
+            return routed;
+        }
+
+        let router = match &self.sni_router {
+            Some(r) => Arc::clone(r),
+            None => return true,
+        };
+
+        if self.hello_buffer.is_empty() {
+            self.hello_buffer = vec![0u8; sni::INITIAL_CLIENT_HELLO];
+        }
+
+        let name = loop {
+            let peeked = match self.stream.peek(&mut self.hello_buffer) {
+                Ok(0) => {
+                    self.close_connection(CloseReason::ClientClosed);
+                    return false;
+                }
+                Ok(p) => p,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return false,
+                Err(_) => {
+                    self.close_connection(CloseReason::ClientError);
+                    return false;
+                }
+            };
+
+            match sni::parse_client_hello(&self.hello_buffer[..peeked]) {
+                ParsedHello::Complete(name) => break name,
+                ParsedHello::Incomplete if peeked < self.hello_buffer.len() => return false,
+                // the buffer is full, there may be more of the ClientHello waiting
+                ParsedHello::Incomplete if peeked < sni::MAX_CLIENT_HELLO => {
+                    let size = (self.hello_buffer.len() * 2).min(sni::MAX_CLIENT_HELLO);
+                    self.hello_buffer.resize(size, 0);
+                }
+                // not TLS or a ClientHello too large to wait for
+                _ => break None,
+            }
+        };
+        self.hello_buffer = vec![];
+
+        let pool = Arc::clone(router.route(name.as_deref()));
+        debug!(target: "Client", "Routed {} to pool '{}' (server name {})", self.address, pool.name, name.as_deref().unwrap_or("-"));
+        self.set_pool(pool);
+        self.sni_router = None;
+        self.hello_deadline = None;
+        true
+    }
+
+    /**
+        Handles the client's data as HTTP requests, each one is routed to a pool by [router] and gets [forwarded_headers].
+        With [balance_requests], every request goes to the host the balancing algorithm picks next. Every request head has to
+        arrive within [head_timeout], counted from when the client can send it
+    */
+    pub fn set_http(&mut self, router: Arc<HttpRouter>, forwarded_headers: Option<Arc<ForwardedHeaders>>, balance_requests: bool, head_timeout: Duration) {
+        let mut session = HttpSession::new(router);
+        if balance_requests {
+            session.set_balance_requests();
+        }
+        if let Some(headers) = forwarded_headers {
+            session.set_forwarded_headers(headers, self.address, self.local_address, self.tls.is_some());
         }
+        self.http = Some(session);
+        self.head_timeout = head_timeout;
+        self.head_deadline = Some(Instant::now() + head_timeout);
+    }
+
+    /**
+        Shares the idle keep-alive connections of the worker handling the client
+    */
+    pub fn set_idle_connections(&mut self, idle_connections: Arc<Mutex<IdleConnections>>) {
+        self.idle_connections = Some(idle_connections);
+    }
+
+    /**
+        Reads the next request of an HTTP client that has no target and picks its pool. Returns [false] while more data is needed
+    */
+    fn route_request(&mut self) -> bool {
+        loop {
+            match self.http.as_mut().unwrap().upload(None, &mut self.upload_pending) {
+                Upload::SwitchTarget => {
+                    self.switch_target();
+                    return true;
+                }
+                // the head is small, waiting for bandwidth would stall the client as it isn't connected to be processed again
+                Upload::NeedInput => match self.read_request(false) {
+                    None => {}
+                    Some(Forwarded::Closed) => {
+                        self.close_connection(CloseReason::ClientClosed);
+                        return false;
+                    }
+                    Some(Forwarded::ReadError) => {
+                        self.close_connection(CloseReason::ClientError);
+                        return false;
+                    }
+                    Some(_) => return false,
+                },
+                Upload::Reject(status) => {
+                    self.reject(status);
+                    return false;
+                }
+                Upload::Data | Upload::Blocked => return false,
+            }
+        }
+    }
+
+    /**
+        Checks if an HTTP client has a routed request waiting for a target to be connected
+    */
+    pub fn needs_target(&self) -> bool {
+        !self.is_connected
+            && !self.is_connecting
+            && self.is_client_connected
+            && self.queued_since.is_none()
+            && self.http.as_ref().is_some_and(|h| h.next_pool().is_some())
+    }
+
+    /**
+        Lets the next request of an HTTP client go to a new target of the pool it's routed to
+    */
+    fn switch_target(&mut self) {
+        self.close_connection_to_target(false);
+        let pool = Arc::clone(self.http.as_ref().unwrap().next_pool().unwrap());
+        self.set_pool(pool);
+
+        // the time to connect counts from the request on, the client may have been idle for long
+        self.last_connection_loss = Instant::now();
+    }
+
+    /**
+        Terminates the client's TLS, everything read from and written to the client goes through [connection]
+    */
+    pub fn set_tls(&mut self, connection: Connection) {
+        self.tls = Some(connection);
     }
 
     pub fn register_target_with_poll(&mut self, poll: &Poll, token: Token) -> Option<()> {
         let mut str = self.target_stream.take()?;
 
-        poll.registry().register(&mut str, token, Interest::READABLE | Interest::WRITABLE).unwrap();
+        // reused keep-alive connections are still registered, with the token of the client that used them last
+        if self.is_connected {
+            poll.registry().reregister(&mut str, token, Interest::READABLE | Interest::WRITABLE).unwrap();
+        } else {
+            poll.registry().register(&mut str, token, Interest::READABLE | Interest::WRITABLE).unwrap();
+        }
 
         self.target_stream = Some(str);
 
@@ -81,30 +433,102 @@ impl TcpClient {
         self.is_client_connected
     }
 
-    pub fn connect_to_target(&mut self, target: SocketAddr) -> Result<bool> {
+    /**
+        Starts connecting to the target, [slot] counts the connection towards the target's connection limit until it's closed
+    */
+    pub fn connect_to_target(&mut self, target: SocketAddr, slot: ConnectionSlot) -> Result<bool> {
         if self.is_connecting {
-            println!("[WARNING] Already connecting, this shouldn't happen");
+            warn!(target: "Client", "Already connecting, this shouldn't happen");
             return Ok(false);
         }
 
         self.close_connection_to_target(false);
+        self.stats.targets.push(target);
+        let target_metrics = self.metrics.backend(target);
+
+        // HTTP clients take an idle connection to the target if there is one, it's connected already
+        if let Some(connection) = self.checkout_target(target) {
+            debug!(target: "Client", "Reusing idle connection to {} for {}", target, self.address);
+            target_metrics.reused_connections.fetch_add(1, Ordering::Relaxed);
+            target_metrics.active_connections.fetch_add(1, Ordering::Relaxed);
+            self.http.as_mut().unwrap().set_target();
+
+            self.is_connected = true;
+            self.target = Some(target);
+            self.target_stream = Some(connection.stream);
+            self.target_tls = connection.tls;
+            self.target_metrics = Some(target_metrics);
+            self.connection_slot = Some(slot);
+            return Ok(true);
+        }
 
-        // start connecting
+        // start connecting, the TLS handshake starts once connected
         let stream = match TcpStream::connect(target) {
             Ok(t) => t,
             Err(_) => {
+                target_metrics.connect_failures.fetch_add(1, Ordering::Relaxed);
                 return Ok(false);
             }
         };
+        let target_tls = match &self.pool.tls {
+            Some(connector) => match connector.connect(target) {
+                Ok(c) => Some(c),
+                Err(e) => {
+                    warn!(target: "Client", "Failed to start TLS with {} -> {}", target, e);
+                    target_metrics.connect_failures.fetch_add(1, Ordering::Relaxed);
+                    return Ok(false);
+                }
+            },
+            None => None,
+        };
+
+        if let Some(http) = &mut self.http {
+            http.set_target();
+        }
 
         self.is_connecting = true;
         self.target = Some(target);
         self.target_stream = Some(stream);
+        self.target_tls = target_tls;
+        self.target_metrics = Some(target_metrics);
+        self.connection_slot = Some(slot);
         self.started_connecting = Instant::now();
 
         Ok(true)
     }
 
+    /**
+        Takes an idle connection to [target] of the client's pool, if it's an HTTP client and the pool keeps connections alive
+    */
+    fn checkout_target(&self, target: SocketAddr) -> Option<IdleConnection> {
+        if self.http.is_none() || self.pool.keepalive.is_none() {
+            return None;
+        }
+
+        self.idle_connections.as_ref()?.lock().unwrap().checkout(&self.pool, target)
+    }
+
+    /**
+        Hands the connection to the target over to the worker's idle connections, if the pool keeps connections alive and
+        it's between requests with nothing left to send. Returns [false] if the connection has to be closed instead
+    */
+    fn release_target(&mut self) -> bool {
+        let reusable = self.pool.keepalive.is_some()
+            && self.http.as_ref().is_some_and(|h| h.target_reusable())
+            && self.upload_pending.is_empty()
+            && self.target_preamble.is_empty()
+            && !self.target_tls.as_ref().is_some_and(|c| c.wants_write());
+        let idle_connections = match self.idle_connections.as_ref().filter(|_| reusable) {
+            Some(i) => i,
+            None => return false,
+        };
+
+        let connection = IdleConnection::new(Arc::clone(&self.pool), self.target.unwrap(), self.target_stream.take().unwrap(), self.target_tls.take());
+        debug!(target: "Client", "Keeping idle connection to {} of {}", connection.target, self.address);
+        idle_connections.lock().unwrap().release(connection);
+        true
+    }
+
     pub fn check_target_connected(&mut self) -> Result<bool> {
         let stream = self.target_stream.as_ref().unwrap();
 
@@ -125,6 +549,19 @@ impl TcpClient {
     fn set_connected(&mut self) {
         self.is_connected = true;
         self.is_connecting = false;
+        self.stats.connect_latency = Some(self.started_connecting.elapsed());
+
+        if let Some(m) = &self.target_metrics {
+            m.active_connections.fetch_add(1, Ordering::Relaxed);
+            m.total_connections.fetch_add(1, Ordering::Relaxed);
+            m.connect_latency.observe(self.started_connecting.elapsed());
+        }
+
+        // nothing was sent to the target yet, so the header is the first thing it gets (before the TLS handshake).
+        // It's not counted as client data
+        if let Some(proxy_protocol) = &self.pool.proxy_protocol {
+            self.target_preamble = proxy_protocol.header(self.address, self.local_address);
+        }
     }
 
     /**
@@ -132,6 +569,38 @@ impl TcpClient {
         Equivalent of calling [forward_to_target] and [forward_from_target] methods
     */
     pub fn process(&mut self) -> bool {
+        // encrypted data the client couldn't take earlier goes out first
+        if MaybeTlsStream::new(&mut self.stream, self.tls.as_mut()).flush().is_err() {
+            self.close_connection(CloseReason::ClientError);
+            return false;
+        }
+
+        // the preamble, then handshake messages and encrypted data the target couldn't take earlier
+        let target_stream = self.target_stream.as_mut().unwrap();
+        let flushed = match write_available(target_stream, &self.target_preamble) {
+            Ok(w) => {
+                self.target_preamble.drain(..w);
+                self.target_preamble.is_empty()
+            }
+            Err(_) => {
+                self.close_connection_to_target(true);
+                return false;
+            }
+        };
+        if !flushed {
+            return true;
+        }
+        if MaybeTlsStream::new(target_stream, self.target_tls.as_mut()).flush().is_err() {
+            self.close_connection_to_target(true);
+            return false;
+        }
+
+        if self.http.is_some() {
+            let processed = self.forward_request() && self.forward_response() && self.next_request();
+            self.update_head_deadline();
+            return processed;
+        }
+
         if !self.forward_to_target() {
             return false;
         }
@@ -140,88 +609,365 @@ impl TcpClient {
             return false;
         }
 
-        return true;
+        true
     }
 
     /**
         Forwards client messages to connected target. (Reads from client stream and writes to target stream)
     */
     pub fn forward_to_target(&mut self) -> bool {
-        let mut str = self.target_stream.as_ref().unwrap();
+        let target_stream = self.target_stream.as_mut().unwrap();
 
-        // READ FROM CLIENT
-        let read: i32 = match self.stream.read(&mut self.buffer) {
-            Ok(r) => r as i32,
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => -1,
-            Err(_) => {
+        let mut written = 0;
+        let result = forward(
+            &mut MaybeTlsStream::new(&mut self.stream, self.tls.as_mut()),
+            &mut MaybeTlsStream::new(target_stream, self.target_tls.as_mut()),
+            &mut self.buffer,
+            &mut self.upload_pending,
+            &mut self.upload,
+            &mut written,
+        );
+        self.stats.bytes_in += written as u64;
+        self.metrics.bytes_in.fetch_add(written as u64, Ordering::Relaxed);
+
+        match result {
+            Forwarded::Paused => true,
+            Forwarded::Throttled => {
+                let resume_at = self.upload.resume_at();
+                self.throttle(resume_at);
+                true
+            }
+            Forwarded::Closed => {
+                self.close_connection(CloseReason::ClientClosed);
+                false
+            }
+            Forwarded::ReadError => {
                 // error with connection to client
-                self.close_connection();
-                return false;
+                self.close_connection(CloseReason::ClientError);
+                false
             }
-        };
-
-        // WRITE TO SERVER
-        if read > 0 {
-            match str.write(&self.buffer[..(read as usize)]) {
-                Ok(_) => {}
-                Err(_e) => {
-                    // error with connection to server
-                    self.close_connection_to_target(true);
-                    return false;
-                }
+            Forwarded::WriteError => {
+                // error with connection to server
+                self.close_connection_to_target(true);
+                false
             }
-        } else if read == 0 {
-            self.close_connection();
-            return false;
         }
-
-        return true;
     }
 
-     /**
+    /**
         Forwards connected target messages to client. (Reads from target stream and writes to client stream)
     */
     pub fn forward_from_target(&mut self) -> bool {
-        let mut str = self.target_stream.as_ref().unwrap();
+        let target_stream = self.target_stream.as_mut().unwrap();
+
+        let mut written = 0;
+        let result = forward(
+            &mut MaybeTlsStream::new(target_stream, self.target_tls.as_mut()),
+            &mut MaybeTlsStream::new(&mut self.stream, self.tls.as_mut()),
+            &mut self.buffer,
+            &mut self.download_pending,
+            &mut self.download,
+            &mut written,
+        );
+        self.stats.bytes_out += written as u64;
+        self.metrics.bytes_out.fetch_add(written as u64, Ordering::Relaxed);
 
-        // READ FROM SERVER
-        let reads: i32 = match str.read(&mut self.buffer) {
-            Ok(r) => r as i32,
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => -1,
-            Err(_e) => {
+        match result {
+            Forwarded::Paused => true,
+            Forwarded::Throttled => {
+                let resume_at = self.download.resume_at();
+                self.throttle(resume_at);
+                true
+            }
+            Forwarded::Closed => {
+                self.close_connection_to_target(false);
+                false
+            }
+            Forwarded::ReadError => {
                 // error with connection to server
                 self.close_connection_to_target(true);
-                return false;
+                false
             }
-        };
+            Forwarded::WriteError => {
+                // error with connection to client
+                self.close_connection(CloseReason::ClientError);
+                false
+            }
+        }
+    }
+
+    /**
+        Reads what the client sent into its HTTP session, [limited] by the upload bandwidth limits. Returns [None] if data was read
+    */
+    fn read_request(&mut self, limited: bool) -> Option<Forwarded> {
+        let allowed = if limited { self.upload.available(self.buffer.len()) } else { self.buffer.len() };
+        if allowed == 0 {
+            return Some(Forwarded::Throttled);
+        }
+
+        loop {
+            match MaybeTlsStream::new(&mut self.stream, self.tls.as_mut()).read(&mut self.buffer[..allowed]) {
+                Ok(0) => return Some(Forwarded::Closed),
+                Ok(r) => {
+                    self.upload.consume(r);
+                    self.http.as_mut().unwrap().input.extend_from_slice(&self.buffer[..r]);
+                    return None;
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Some(Forwarded::Paused),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return Some(Forwarded::ReadError),
+            }
+        }
+    }
 
-        // WRITE TO CLIENT
-        if reads > 0 {
-            match self.stream.write(&self.buffer[..(reads as usize)]) {
-                Ok(_) => {}
+    /**
+        Forwards the requests of an HTTP client to the connected target, one request at a time
+    */
+    fn forward_request(&mut self) -> bool {
+        loop {
+            if !self.upload_pending.is_empty() {
+                let target_stream = self.target_stream.as_mut().unwrap();
+                match write_available(&mut MaybeTlsStream::new(target_stream, self.target_tls.as_mut()), &self.upload_pending) {
+                    Ok(w) => {
+                        self.stats.bytes_in += w as u64;
+                        self.metrics.bytes_in.fetch_add(w as u64, Ordering::Relaxed);
+                        self.upload_pending.drain(..w);
+                    }
+                    Err(_) => {
+                        self.fail_request(true);
+                        return false;
+                    }
+                }
+
+                if !self.upload_pending.is_empty() {
+                    return true;
+                }
+            }
+
+            let http = self.http.as_mut().unwrap();
+            // the next request waits until the previous response reached the client
+            if http.is_idle() && !self.download_pending.is_empty() {
+                return true;
+            }
+
+            match http.upload(Some(&self.pool), &mut self.upload_pending) {
+                Upload::Data => {}
+                Upload::Blocked => return true,
+                Upload::NeedInput => match self.read_request(true) {
+                    None => {}
+                    Some(Forwarded::Throttled) => {
+                        let resume_at = self.upload.resume_at();
+                        self.throttle(resume_at);
+                        return true;
+                    }
+                    Some(Forwarded::Closed) => {
+                        self.close_connection(CloseReason::ClientClosed);
+                        return false;
+                    }
+                    Some(Forwarded::ReadError) => {
+                        self.close_connection(CloseReason::ClientError);
+                        return false;
+                    }
+                    Some(_) => return true,
+                },
+                Upload::SwitchTarget => {
+                    self.switch_target();
+                    return false;
+                }
+                Upload::Reject(status) => {
+                    self.reject(status);
+                    return false;
+                }
+            }
+        }
+    }
+
+    /**
+        Forwards the responses of the connected target to an HTTP client
+    */
+    fn forward_response(&mut self) -> bool {
+        loop {
+            if !self.download_pending.is_empty() {
+                match write_available(&mut MaybeTlsStream::new(&mut self.stream, self.tls.as_mut()), &self.download_pending) {
+                    Ok(w) => {
+                        self.stats.bytes_out += w as u64;
+                        self.metrics.bytes_out.fetch_add(w as u64, Ordering::Relaxed);
+                        self.download_pending.drain(..w);
+                    }
+                    Err(_) => {
+                        self.close_connection(CloseReason::ClientError);
+                        return false;
+                    }
+                }
+
+                if !self.download_pending.is_empty() {
+                    return true;
+                }
+            }
+
+            // the connection stays open until everything reached the client, but there's nothing more to read
+            if self.http.as_ref().unwrap().target_closed() {
+                return true;
+            }
+
+            let allowed = self.download.available(self.buffer.len());
+            if allowed == 0 {
+                let resume_at = self.download.resume_at();
+                self.throttle(resume_at);
+                return true;
+            }
+
+            let target_stream = self.target_stream.as_mut().unwrap();
+            let read = match MaybeTlsStream::new(target_stream, self.target_tls.as_mut()).read(&mut self.buffer[..allowed]) {
+                Ok(r) => r,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return true,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
                 Err(_) => {
-                    // error with connection to client
-                    self.close_connection();
+                    self.fail_request(true);
                     return false;
                 }
             };
-        } else if reads == 0 {
+            self.download.consume(read);
+
+            let http = self.http.as_mut().unwrap();
+            if read == 0 {
+                if !http.close_target() {
+                    self.fail_request(true);
+                    return false;
+                }
+            } else if !http.download(&self.buffer[..read], &mut self.download_pending) {
+                warn!(target: "Client", "Invalid HTTP response from {} for {}", self.target.unwrap(), self.address);
+                self.fail_request(true);
+                return false;
+            }
+        }
+    }
+
+    /**
+        Moves on once the last response reached an HTTP client: ends the connections the response ended, or continues
+        with the next request
+    */
+    fn next_request(&mut self) -> bool {
+        if !self.download_pending.is_empty() {
+            return true;
+        }
+
+        let http = self.http.as_ref().unwrap();
+        if let Some(reason) = http.close_after().filter(|_| !http.in_flight()) {
+            self.close_connection(reason);
+            return false;
+        }
+        if http.is_idle() && http.target_closed() {
             self.close_connection_to_target(false);
             return false;
         }
 
-        return true;
+        // the response may have let the next request through, or opened a tunnel for data that came with the request
+        self.forward_request()
+    }
+
+    /**
+        Handles a failed target of an HTTP client. Between requests, the next one just gets a new target. Otherwise the request
+        is lost and the client is disconnected, with an error response if it got nothing of the response yet
+    */
+    fn fail_request(&mut self, target_errored: bool) {
+        let http = self.http.as_ref().unwrap();
+        let (idle, respond) = (http.is_idle(), http.can_respond());
+
+        self.close_connection_to_target(target_errored);
+        if idle {
+            return;
+        }
+
+        if respond {
+            self.respond(502, CloseReason::ServerClosed);
+        } else {
+            self.close_connection(CloseReason::ServerClosed);
+        }
+    }
+
+    /**
+        Starts the time an HTTP client has for its next request head once everything before it is done, and stops it once
+        the head arrived
+    */
+    fn update_head_deadline(&mut self) {
+        let http = self.http.as_ref().unwrap();
+        if self.is_client_connected && http.awaits_head() && self.download_pending.is_empty() {
+            self.head_deadline.get_or_insert(Instant::now() + self.head_timeout);
+        } else {
+            self.head_deadline = None;
+        }
+    }
+
+    /**
+        Handles an HTTP client whose request head didn't arrive in time. It gets a 408 response if it started sending the
+        head, idle connections between requests are just closed
+    */
+    pub fn head_timed_out(&mut self) {
+        self.head_deadline = None;
+        let http = self.http.as_ref().unwrap();
+        if http.input.is_empty() {
+            self.close_connection(CloseReason::Timeout);
+        } else {
+            self.respond(408, CloseReason::Timeout);
+        }
+    }
+
+    /**
+        Disconnects an HTTP client that sent a malformed request, telling it why unless it already got part of a response
+    */
+    fn reject(&mut self, status: u16) {
+        let http = self.http.as_ref().unwrap();
+        debug!(target: "Client", "Rejected HTTP request of {} ({})", self.address, status);
+
+        if !http.in_flight() || http.can_respond() {
+            self.respond(status, CloseReason::ClientError);
+        } else {
+            self.close_connection(CloseReason::ClientError);
+        }
+    }
+
+    /**
+        Sends an error response as far as the client takes it without blocking, then disconnects it. Nothing else is waiting
+        to be sent to the client at this point, the next request is only read once the previous response was sent
+    */
+    fn respond(&mut self, status: u16, reason: CloseReason) {
+        write_available(&mut MaybeTlsStream::new(&mut self.stream, self.tls.as_mut()), &http::error_response(status)).unwrap_or(0);
+        self.close_connection(reason);
+    }
+
+    /**
+        Marks the client to be processed again at [resume_at], or earlier if it's already waiting for an earlier time
+    */
+    fn throttle(&mut self, resume_at: Instant) {
+        self.throttled_until = Some(self.throttled_until.map_or(resume_at, |t| t.min(resume_at)));
     }
 
     pub fn close_connection_to_target(&mut self, target_errored: bool) {
         // if connected to target, disconnect - mark last connection loss
+        // (targets of HTTP clients between requests are kept open for later requests instead)
         if self.is_connected {
-            let str = self.target_stream.as_ref().unwrap();
-            str.shutdown(Shutdown::Both).unwrap_or(());
+            if target_errored || !self.release_target() {
+                let str = self.target_stream.as_mut().unwrap();
+                if let Some(connection) = &mut self.target_tls {
+                    connection.send_close_notify();
+                    MaybeTlsStream::new(str, Some(connection)).flush().unwrap_or(());
+                }
+                str.shutdown(Shutdown::Both).unwrap_or(());
+            }
 
             self.last_connection_loss = Instant::now();
         }
 
+        // update target metrics
+        if let Some(m) = self.target_metrics.take() {
+            if self.is_connected {
+                m.active_connections.fetch_sub(1, Ordering::Relaxed);
+            } else if self.is_connecting && target_errored {
+                m.connect_failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
         // mark error
         if target_errored {
             self.last_target = self.target;
@@ -234,17 +980,39 @@ impl TcpClient {
         // reset
         self.target = None;
         self.target_stream = None;
+        self.target_tls = None;
+        self.connection_slot = None;
+        // data for the closed target is lost, a new target gets the client's data from here on
+        self.upload_pending.clear();
+        self.target_preamble.clear();
 
         self.is_connected = false;
         self.is_connecting = false;
     }
 
-    pub fn close_connection(&mut self) {
+    pub fn close_connection(&mut self, reason: CloseReason) {
         if self.is_client_connected {
+            // HTTP clients are told why their request couldn't be sent anywhere
+            let status = match reason {
+                CloseReason::NoHosts | CloseReason::QueueFull | CloseReason::QueueTimeout => Some(503),
+                CloseReason::Timeout => Some(504),
+                _ => None,
+            };
+            if let Some(status) = status.filter(|_| self.http.as_ref().is_some_and(|h| h.next_pool().is_some())) {
+                write_available(&mut MaybeTlsStream::new(&mut self.stream, self.tls.as_mut()), &http::error_response(status)).unwrap_or(0);
+            }
+
+            // TLS clients are told the connection ends on purpose, as far as the stream takes it without blocking
+            if let Some(connection) = &mut self.tls {
+                connection.send_close_notify();
+                MaybeTlsStream::new(&mut self.stream, Some(connection)).flush().unwrap_or(());
+            }
+
             let str = &self.stream;
             str.shutdown(Shutdown::Both).unwrap_or(());
 
             self.is_client_connected = false;
+            self.stats.close_reason = Some(reason);
 
             // also close connection to target if connected - there is no reason to stay connected if client is not
             self.close_connection_to_target(false);
@@ -252,8 +1020,91 @@ impl TcpClient {
     }
 }
 
+/**
+    How forwarding from one stream to another stopped
+*/
+enum Forwarded {
+    /**
+        Nothing more to read, or the writer can't take more data right now (both are signalled by a new event)
+    */
+    Paused,
+    /**
+        Bandwidth limits don't allow reading more right now
+    */
+    Throttled,
+    Closed,
+    ReadError,
+    WriteError,
+}
+
+/**
+    Reads from [from] and writes to [to] until there's nothing more to read. Data [to] can't take without blocking is kept in [pending]
+    and written first next time, nothing new is read until then. [written] is increased by the number of bytes written
+*/
+fn forward(from: &mut impl Read, to: &mut impl Write, buffer: &mut [u8], pending: &mut Vec<u8>, throttle: &mut Throttle, written: &mut usize) -> Forwarded {
+    if !pending.is_empty() {
+        match write_available(to, pending) {
+            Ok(w) => {
+                *written += w;
+                pending.drain(..w);
+            }
+            Err(_) => return Forwarded::WriteError,
+        }
+
+        if !pending.is_empty() {
+            return Forwarded::Paused;
+        }
+    }
+
+    // events are edge triggered, so everything has to be read now (or once the bandwidth limits allow it)
+    loop {
+        let allowed = throttle.available(buffer.len());
+        if allowed == 0 {
+            return Forwarded::Throttled;
+        }
+
+        let read = match from.read(&mut buffer[..allowed]) {
+            Ok(0) => return Forwarded::Closed,
+            Ok(r) => r,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Forwarded::Paused,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(_) => return Forwarded::ReadError,
+        };
+        throttle.consume(read);
+
+        let w = match write_available(to, &buffer[..read]) {
+            Ok(w) => w,
+            Err(_) => return Forwarded::WriteError,
+        };
+        *written += w;
+
+        if w < read {
+            pending.extend_from_slice(&buffer[w..read]);
+            return Forwarded::Paused;
+        }
+    }
+}
+
+/**
+    Writes as much of [data] as the stream takes without blocking, returns how much was written
+*/
+fn write_available(stream: &mut impl Write, data: &[u8]) -> Result<usize> {
+    let mut written = 0;
+    while written < data.len() {
+        match stream.write(&data[written..]) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(w) => written += w,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(written)
+}
+
 impl Drop for TcpClient {
     fn drop(&mut self) {
-        self.close_connection();
+        self.close_connection(CloseReason::Shutdown);
     }
 }
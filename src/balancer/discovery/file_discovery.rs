@@ -0,0 +1,173 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use log::warn;
+use serde::Deserialize;
+
+use super::HostManager;
+use super::ServiceDiscovery;
+
+#[derive(Deserialize)]
+struct ServiceFile {
+    backends: Vec<String>,
+}
+
+/**
+    Watches a directory of per-service files. Every `.json` or `.toml` file describes one service and lists its backends:
+
+    `{ "backends": ["10.0.0.1:80", "10.0.0.2:80"] }` or `backends = ["10.0.0.1:80", "10.0.0.2:80"]`
+*/
+pub struct FileDiscovery {
+    directory: PathBuf,
+    interval: Duration,
+    last_state: Option<Vec<(PathBuf, SystemTime, u64)>>,
+}
+
+impl FileDiscovery {
+    pub fn new(directory: &str, interval: Duration) -> Self {
+        FileDiscovery {
+            directory: PathBuf::from(directory),
+            interval,
+            last_state: None,
+        }
+    }
+
+    /**
+        Lists all service files with their modification time and size, used to detect changes without parsing the files
+    */
+    fn read_state(&self) -> Result<Vec<(PathBuf, SystemTime, u64)>> {
+        let mut state = vec![];
+
+        for entry in fs::read_dir(&self.directory)? {
+            let path = entry?.path();
+            if !FileDiscovery::is_service_file(&path) {
+                continue;
+            }
+
+            let metadata = fs::metadata(&path)?;
+            state.push((path, metadata.modified()?, metadata.len()));
+        }
+
+        state.sort();
+        Ok(state)
+    }
+
+    fn is_service_file(path: &Path) -> bool {
+        if !path.is_file() {
+            return false;
+        }
+
+        matches!(path.extension().and_then(|e| e.to_str()), Some("json") | Some("toml"))
+    }
+
+    fn parse_service_file(path: &Path) -> Result<Vec<SocketAddr>> {
+        let content = fs::read_to_string(path)?;
+
+        let service: ServiceFile = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::from_str(&content).map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+            _ => toml::from_str(&content).map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+        };
+
+        let mut hosts = vec![];
+        for backend in service.backends {
+            match HostManager::resolve_host(&backend) {
+                Some(addr) => hosts.push(addr),
+                None => warn!(target: "Discovery", "Invalid host in '{}': '{}'", path.display(), backend),
+            }
+        }
+
+        Ok(hosts)
+    }
+}
+
+impl ServiceDiscovery for FileDiscovery {
+    fn name(&self) -> String {
+        format!("directory '{}'", self.directory.display())
+    }
+
+    fn discover(&mut self) -> Result<Option<Vec<SocketAddr>>> {
+        let state = self.read_state()?;
+        if self.last_state.as_ref() == Some(&state) {
+            return Ok(None);
+        }
+
+        let mut hosts = vec![];
+        for (path, _, _) in &state {
+            match FileDiscovery::parse_service_file(path) {
+                Ok(h) => hosts.extend(h),
+                Err(err) => warn!(target: "Discovery", "Failed to parse service file '{}' -> {}", path.display(), err),
+            }
+        }
+
+        self.last_state = Some(state);
+        Ok(Some(hosts))
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+        Empty directory that's removed again when the test ends
+    */
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("lb-discovery-{}-{}", name, std::process::id()));
+            fs::remove_dir_all(&path).unwrap_or(());
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.0).unwrap_or(());
+        }
+    }
+
+    #[test]
+    fn reads_json_and_toml_service_files() {
+        let dir = TempDir::new("formats");
+        fs::write(dir.0.join("a.json"), r#"{ "backends": ["10.0.0.1:80", "10.0.0.2:80"] }"#).unwrap();
+        fs::write(dir.0.join("b.toml"), r#"backends = ["10.0.0.3:81", "invalid"]"#).unwrap();
+        fs::write(dir.0.join("c.txt"), "ignored").unwrap();
+        fs::write(dir.0.join("d.json"), "not json").unwrap();
+
+        let mut discovery = FileDiscovery::new(dir.0.to_str().unwrap(), Duration::from_secs(1));
+        let hosts = discovery.discover().unwrap().unwrap();
+        let expected: Vec<SocketAddr> = ["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:81"].iter().map(|h| h.parse().unwrap()).collect();
+        assert_eq!(hosts, expected);
+    }
+
+    #[test]
+    fn reports_only_changes() {
+        let dir = TempDir::new("changes");
+        fs::write(dir.0.join("a.json"), r#"{ "backends": ["10.0.0.1:80"] }"#).unwrap();
+
+        let mut discovery = FileDiscovery::new(dir.0.to_str().unwrap(), Duration::from_secs(1));
+        assert_eq!(discovery.discover().unwrap().unwrap().len(), 1);
+        assert!(discovery.discover().unwrap().is_none());
+
+        fs::write(dir.0.join("a.json"), r#"{ "backends": ["10.0.0.1:80", "10.0.0.2:80"] }"#).unwrap();
+        assert_eq!(discovery.discover().unwrap().unwrap().len(), 2);
+
+        fs::remove_file(dir.0.join("a.json")).unwrap();
+        assert_eq!(discovery.discover().unwrap(), Some(vec![]));
+    }
+
+    #[test]
+    fn fails_for_missing_directory() {
+        let mut discovery = FileDiscovery::new("/nonexistent/lb-discovery", Duration::from_secs(1));
+        assert!(discovery.discover().is_err());
+    }
+}
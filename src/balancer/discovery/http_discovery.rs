@@ -0,0 +1,309 @@
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use log::warn;
+use serde::Deserialize;
+
+use super::HostManager;
+use super::ServiceDiscovery;
+
+// timeout used for connecting, reading and writing to the discovery endpoint
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// larger responses are rejected, so a misbehaving endpoint can't use up memory
+const MAX_RESPONSE: u64 = 1024 * 1024;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BackendList {
+    List(Vec<String>),
+    Object { backends: Vec<String> },
+}
+
+struct HttpResponse {
+    status: u16,
+    /**
+        Response headers, names are lowercase
+    */
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+/**
+    Polls an HTTP endpoint that returns a JSON list of backends, either `["10.0.0.1:80", ...]` or `{ "backends": [...] }`.
+    The `ETag` of the last response is sent back with `If-None-Match`, so unchanged lists are not transferred again.
+*/
+pub struct HttpDiscovery {
+    url: String,
+    host: String,
+    path: String,
+    interval: Duration,
+    etag: Option<String>,
+}
+
+impl HttpDiscovery {
+    pub fn new(url: &str, interval: Duration) -> Result<Self> {
+        // only plain HTTP is supported: http://host[:port][/path]
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "only http:// URLs are supported"))?;
+
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+
+        if host.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "missing host in URL"));
+        }
+
+        Ok(HttpDiscovery {
+            url: url.to_string(),
+            host: host.to_string(),
+            path: path.to_string(),
+            interval,
+            etag: None,
+        })
+    }
+
+    fn connect(&self) -> Result<TcpStream> {
+        let host = if self.host.contains(':') {
+            self.host.clone()
+        } else {
+            format!("{}:80", self.host)
+        };
+
+        let mut last_error = Error::new(ErrorKind::NotFound, format!("could not resolve '{}'", host));
+        for addr in host.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, REQUEST_TIMEOUT) {
+                Ok(s) => return Ok(s),
+                Err(e) => last_error = e,
+            }
+        }
+
+        Err(last_error)
+    }
+
+    fn get(&self) -> Result<HttpResponse> {
+        let mut stream = self.connect()?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+        let mut request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nAccept: application/json\r\nConnection: close\r\n",
+            self.path, self.host
+        );
+        if let Some(etag) = &self.etag {
+            request.push_str(&format!("If-None-Match: {}\r\n", etag));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())?;
+
+        let mut response = vec![];
+        stream.take(MAX_RESPONSE + 1).read_to_end(&mut response)?;
+        if response.len() as u64 > MAX_RESPONSE {
+            return Err(Error::new(ErrorKind::InvalidData, format!("response is larger than {} bytes", MAX_RESPONSE)));
+        }
+
+        HttpDiscovery::parse_response(&response)
+    }
+
+    fn parse_response(response: &[u8]) -> Result<HttpResponse> {
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_string());
+
+        let head_end = response
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or_else(|| invalid("incomplete response head"))?;
+        let head = std::str::from_utf8(&response[..head_end]).map_err(|_| invalid("response head is not valid UTF-8"))?;
+        let body = &response[head_end + 4..];
+
+        let mut lines = head.split("\r\n");
+        let status: u16 = lines
+            .next()
+            .and_then(|l| l.split(' ').nth(1))
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| invalid("invalid status line"))?;
+
+        let headers: Vec<(String, String)> = lines
+            .filter_map(|l| {
+                let i = l.find(':')?;
+                Some((l[..i].trim().to_lowercase(), l[i + 1..].trim().to_string()))
+            })
+            .collect();
+
+        let chunked = headers.iter().any(|(k, v)| k == "transfer-encoding" && v.to_lowercase().contains("chunked"));
+        let body = if chunked { HttpDiscovery::decode_chunked(body)? } else { body.to_vec() };
+
+        Ok(HttpResponse { status, headers, body })
+    }
+
+    fn decode_chunked(mut body: &[u8]) -> Result<Vec<u8>> {
+        let invalid = || Error::new(ErrorKind::InvalidData, "invalid chunked body");
+        let mut decoded = vec![];
+
+        loop {
+            let line_end = body.windows(2).position(|w| w == b"\r\n").ok_or_else(invalid)?;
+            let size_line = std::str::from_utf8(&body[..line_end]).map_err(|_| invalid())?;
+            let size_hex = size_line.split(';').next().unwrap_or("").trim();
+            let size = usize::from_str_radix(size_hex, 16).map_err(|_| invalid())?;
+
+            body = &body[line_end + 2..];
+            if size == 0 {
+                return Ok(decoded);
+            }
+            if body.len() < size + 2 {
+                return Err(invalid());
+            }
+
+            decoded.extend_from_slice(&body[..size]);
+            body = &body[size + 2..];
+        }
+    }
+}
+
+impl ServiceDiscovery for HttpDiscovery {
+    fn name(&self) -> String {
+        format!("endpoint '{}'", self.url)
+    }
+
+    fn discover(&mut self) -> Result<Option<Vec<SocketAddr>>> {
+        let response = self.get()?;
+
+        // not modified since last poll
+        if response.status == 304 {
+            return Ok(None);
+        }
+
+        if response.status != 200 {
+            return Err(Error::other(format!("unexpected status code {}", response.status)));
+        }
+
+        let list: BackendList = serde_json::from_slice(&response.body).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let backends = match list {
+            BackendList::List(b) => b,
+            BackendList::Object { backends } => backends,
+        };
+
+        let mut hosts = vec![];
+        for backend in backends {
+            match HostManager::resolve_host(&backend) {
+                Some(addr) => hosts.push(addr),
+                None => warn!(target: "Discovery", "Invalid host from '{}': '{}'", self.url, backend),
+            }
+        }
+
+        self.etag = response.headers.into_iter().find(|(k, _)| k == "etag").map(|(_, v)| v);
+
+        Ok(Some(hosts))
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    /**
+        Answers one connection per response in [responses], returns the endpoint URL and the received requests
+    */
+    fn serve(responses: Vec<Vec<u8>>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/backends", listener.local_addr().unwrap());
+
+        let handle = thread::spawn(move || {
+            let mut requests = vec![];
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = vec![];
+                let mut buf = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let read = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..read]);
+                }
+                stream.write_all(&response).unwrap();
+                requests.push(String::from_utf8(request).unwrap());
+            }
+            requests
+        });
+
+        (url, handle)
+    }
+
+    fn ok(headers: &str, body: &str) -> Vec<u8> {
+        format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n{}\r\n{}", body.len(), headers, body).into_bytes()
+    }
+
+    #[test]
+    fn parses_backend_lists() {
+        let (url, server) = serve(vec![
+            ok("", r#"["10.0.0.1:80", "10.0.0.2:8080"]"#),
+            ok("", r#"{ "backends": ["[::1]:80", "not a host"] }"#),
+        ]);
+        let mut discovery = HttpDiscovery::new(&url, Duration::from_secs(1)).unwrap();
+
+        let hosts = discovery.discover().unwrap().unwrap();
+        assert_eq!(hosts, vec!["10.0.0.1:80".parse().unwrap(), "10.0.0.2:8080".parse().unwrap()]);
+        let hosts = discovery.discover().unwrap().unwrap();
+        assert_eq!(hosts, vec!["[::1]:80".parse().unwrap()]);
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET /backends HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn decodes_chunked_body() {
+        let body = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\n[\"10.\r\n10\r\n0.0.1:80\", \"10.0\r\n8\r\n.0.2:80\"\r\n1\r\n]\r\n0\r\n\r\n";
+        let (url, server) = serve(vec![body.as_bytes().to_vec()]);
+        let mut discovery = HttpDiscovery::new(&url, Duration::from_secs(1)).unwrap();
+
+        let hosts = discovery.discover().unwrap().unwrap();
+        assert_eq!(hosts, vec!["10.0.0.1:80".parse().unwrap(), "10.0.0.2:80".parse().unwrap()]);
+        server.join().unwrap();
+
+        assert!(HttpDiscovery::decode_chunked(b"5\r\nabc").is_err());
+        assert!(HttpDiscovery::decode_chunked(b"zz\r\n").is_err());
+    }
+
+    #[test]
+    fn sends_etag_and_handles_not_modified() {
+        let (url, server) = serve(vec![
+            ok("ETag: \"v1\"\r\n", r#"["10.0.0.1:80"]"#),
+            b"HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\n\r\n".to_vec(),
+            b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n".to_vec(),
+        ]);
+        let mut discovery = HttpDiscovery::new(&url, Duration::from_secs(1)).unwrap();
+
+        assert!(discovery.discover().unwrap().is_some());
+        assert!(discovery.discover().unwrap().is_none());
+        assert!(discovery.discover().is_err());
+
+        let requests = server.join().unwrap();
+        assert!(!requests[0].to_lowercase().contains("if-none-match"));
+        assert!(requests[1].contains("If-None-Match: \"v1\"\r\n"));
+    }
+
+    #[test]
+    fn rejects_oversized_responses() {
+        let body = format!("[\"{}\"]", "1".repeat(MAX_RESPONSE as usize));
+        let (url, server) = serve(vec![ok("", &body)]);
+        let mut discovery = HttpDiscovery::new(&url, Duration::from_secs(1)).unwrap();
+
+        let error = discovery.discover().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_urls() {
+        assert!(HttpDiscovery::new("https://example.com/", Duration::from_secs(1)).is_err());
+        assert!(HttpDiscovery::new("http:///backends", Duration::from_secs(1)).is_err());
+    }
+}
@@ -0,0 +1,29 @@
+mod file_discovery;
+mod http_discovery;
+
+pub use file_discovery::FileDiscovery;
+pub use http_discovery::HttpDiscovery;
+
+use std::io::Result;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use super::HostManager;
+
+/**
+    Source of backend hosts that can change while the balancer is running. Providers are polled from a dedicated thread.
+*/
+pub trait ServiceDiscovery: Send {
+    /**
+        Name of the provider, used when logging
+    */
+    fn name(&self) -> String;
+    /**
+        Returns the current list of backends, or [None] if nothing changed since the last call
+    */
+    fn discover(&mut self) -> Result<Option<Vec<SocketAddr>>>;
+    /**
+        How long to wait between two [discover] calls
+    */
+    fn interval(&self) -> Duration;
+}
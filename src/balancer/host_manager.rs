@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::BufRead;
 use std::io::BufReader;
@@ -6,31 +7,416 @@ use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
 use std::path::Path;
 use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+
+use super::ServiceDiscovery;
+
+// weight of hosts that had no weight set
+const DEFAULT_WEIGHT: u32 = 1;
+
+// share of the full weight a host starts with when slow start begins
+const SLOW_START_MIN_FRACTION: f64 = 0.1;
+
+/**
+    Administrative state of a host, changed at runtime through the admin API
+*/
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HostState {
+    /**
+        Host receives new connections
+    */
+    Active,
+    /**
+        Host receives no new connections, existing ones are kept
+    */
+    Draining,
+    /**
+        Host is taken out of rotation
+    */
+    Disabled,
+}
+
+impl HostState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HostState::Active => "active",
+            HostState::Draining => "draining",
+            HostState::Disabled => "disabled",
+        }
+    }
+}
+
+/**
+    Counts a connection towards the connection limit of a host for as long as it's kept
+*/
+pub struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
 
 pub struct HostManager {
     pub hosts: Vec<SocketAddr>,
+    /**
+        Hosts without an entry are active. Kept for hosts removed by discovery, so the state applies if they come back
+    */
+    states: HashMap<SocketAddr, HostState>,
+    weights: HashMap<SocketAddr, u32>,
+    /**
+        When hosts recovered or were added, their effective weight ramps up during [slow_start] after that
+    */
+    recovered_at: HashMap<SocketAddr, Instant>,
+    slow_start: Duration,
+    /**
+        Priority tier of every host (0 if not set), higher tiers only get traffic when lower ones are unavailable
+    */
+    tiers: HashMap<SocketAddr, u32>,
+    /**
+        Share of (active) hosts in a tier that must be available for the tier to get traffic
+    */
+    failover_min_available: f64,
+    active_tier: Option<u32>,
+    tier_shifts: u64,
+    /**
+        Maximum concurrent connections of hosts that have a limit
+    */
+    max_conns: HashMap<SocketAddr, usize>,
+    /**
+        Current connections (including ones being established) of every host, see [ConnectionSlot]
+    */
+    connections: HashMap<SocketAddr, Arc<AtomicUsize>>,
+    static_hosts: Vec<SocketAddr>,
+    /**
+        Latest host list reported by every discovery provider (indexed by provider)
+    */
+    discovered_hosts: Arc<Mutex<Vec<Vec<SocketAddr>>>>,
+    /**
+        Incremented by discovery threads whenever a provider reports a change
+    */
+    discovery_generation: Arc<AtomicUsize>,
+    applied_generation: usize,
+    providers: usize,
 }
 
 impl HostManager {
     pub fn new(hostfile: &str) -> Self {
+        let mut manager = HostManager {
+            hosts: vec![],
+            states: HashMap::new(),
+            weights: HashMap::new(),
+            recovered_at: HashMap::new(),
+            slow_start: Duration::ZERO,
+            tiers: HashMap::new(),
+            failover_min_available: 0.0,
+            active_tier: None,
+            tier_shifts: 0,
+            max_conns: HashMap::new(),
+            connections: HashMap::new(),
+            static_hosts: vec![],
+            discovered_hosts: Arc::new(Mutex::new(vec![])),
+            discovery_generation: Arc::new(AtomicUsize::new(0)),
+            applied_generation: 0,
+            providers: 0,
+        };
+
         if !Path::exists(Path::new(hostfile)) {
-            println!("[Parser] Host file '{}' does not exist. Please create it and try again.", hostfile);
+            warn!(target: "Parser", "Host file '{}' does not exist. Please create it and try again.", hostfile);
 
-            return HostManager { hosts: vec![] };
+            return manager;
         }
 
-        let hosts = match HostManager::parse_hosts(hostfile) {
+        manager.static_hosts = match manager.parse_hosts(hostfile) {
             Ok(h) => h,
             Err(err) => {
-                println!("[Parser] Failed to parse host file '{}' -> {}", hostfile, err.to_string());
+                error!(target: "Parser", "Failed to parse host file '{}' -> {}", hostfile, err);
                 vec![]
             }
         };
+        manager.hosts = manager.static_hosts.clone();
+
+        manager
+    }
+
+    /**
+        Registers a discovery provider and starts polling it on a separate thread. Discovered hosts are merged with the ones from the host file
+    */
+    pub fn add_discovery(&mut self, mut provider: Box<dyn ServiceDiscovery>) {
+        let index = self.providers;
+        self.providers += 1;
+        self.discovered_hosts.lock().unwrap().push(vec![]);
+
+        let discovered_hosts = Arc::clone(&self.discovered_hosts);
+        let generation = Arc::clone(&self.discovery_generation);
+
+        info!(target: "Discovery", "Watching {}", provider.name());
+
+        thread::spawn(move || loop {
+            match provider.discover() {
+                Ok(Some(hosts)) => {
+                    let mut discovered = discovered_hosts.lock().unwrap();
+                    if discovered[index] != hosts {
+                        info!(target: "Discovery", "{} reported {} hosts", provider.name(), hosts.len());
+                        discovered[index] = hosts;
+                        generation.fetch_add(1, Ordering::Release);
+                    }
+                }
+                Ok(None) => {}
+                Err(err) => warn!(target: "Discovery", "Failed to query {} -> {}", provider.name(), err),
+            }
+
+            thread::sleep(provider.interval());
+        });
+    }
+
+    pub fn has_discovery(&self) -> bool {
+        self.providers > 0
+    }
+
+    /**
+        Applies any changes reported by discovery providers. Returns [true] if the host list changed
+    */
+    pub fn refresh(&mut self) -> bool {
+        let generation = self.discovery_generation.load(Ordering::Acquire);
+        if generation == self.applied_generation {
+            return false;
+        }
+        self.applied_generation = generation;
+
+        let mut hosts = self.static_hosts.clone();
+        for provider_hosts in self.discovered_hosts.lock().unwrap().iter() {
+            for host in provider_hosts {
+                if !hosts.contains(host) {
+                    hosts.push(*host);
+                }
+            }
+        }
+
+        if hosts == self.hosts {
+            return false;
+        }
+
+        info!(target: "Discovery", "Host list updated, {} hosts available", hosts.len());
+        for host in &hosts {
+            if !self.hosts.contains(host) {
+                self.mark_recovered(*host);
+            }
+        }
+        self.hosts = hosts;
+        true
+    }
+
+    pub fn get_state(&self, addr: SocketAddr) -> HostState {
+        *self.states.get(&addr).unwrap_or(&HostState::Active)
+    }
+
+    pub fn set_state(&mut self, addr: SocketAddr, state: HostState) {
+        info!(target: "Hosts", "Host {} is now {}", addr, state.as_str());
+        if state == HostState::Active && self.get_state(addr) != HostState::Active {
+            self.mark_recovered(addr);
+        }
+
+        self.states.insert(addr, state);
+    }
+
+    /**
+        Returns the relative share of new connections the host should get
+    */
+    pub fn get_weight(&self, addr: SocketAddr) -> u32 {
+        *self.weights.get(&addr).unwrap_or(&DEFAULT_WEIGHT)
+    }
+
+    pub fn set_weight(&mut self, addr: SocketAddr, weight: u32) {
+        info!(target: "Hosts", "Host {} weight set to {}", addr, weight);
+        self.weights.insert(addr, weight);
+    }
+
+    /**
+        Sets how long it takes for a recovered or newly added host to get its full weight, zero disables slow start
+    */
+    pub fn set_slow_start(&mut self, slow_start: Duration) {
+        self.slow_start = slow_start;
+    }
+
+    /**
+        Starts slow start for the host, should be called by balancing algorithms when a host comes back from errors
+    */
+    pub fn mark_recovered(&mut self, addr: SocketAddr) {
+        if self.slow_start.is_zero() {
+            return;
+        }
+
+        let now = Instant::now();
+        let slow_start = self.slow_start;
+        self.recovered_at.retain(|_, t| now.duration_since(*t) < slow_start);
+        self.recovered_at.insert(addr, now);
+    }
+
+    /**
+        Returns the weight balancing algorithms should use for the host. During slow start it ramps linearly from a small
+        fraction of the weight to the full weight
+    */
+    pub fn get_effective_weight(&self, addr: SocketAddr) -> f64 {
+        let weight = self.get_weight(addr) as f64;
+
+        let elapsed = match self.recovered_at.get(&addr) {
+            Some(t) => t.elapsed(),
+            None => return weight,
+        };
+        if elapsed >= self.slow_start {
+            return weight;
+        }
+
+        let fraction = elapsed.as_secs_f64() / self.slow_start.as_secs_f64();
+        weight * fraction.max(SLOW_START_MIN_FRACTION)
+    }
+
+    pub fn get_tier(&self, addr: SocketAddr) -> u32 {
+        *self.tiers.get(&addr).unwrap_or(&0)
+    }
+
+    /**
+        Sets the share of hosts in a tier that must be available for it to get traffic, 0 means traffic only moves to the
+        next tier once all hosts are unavailable
+    */
+    pub fn set_failover_min_available(&mut self, fraction: f64) {
+        self.failover_min_available = fraction.clamp(0.0, 1.0);
+    }
+
+    /**
+        Returns the tier that currently gets traffic, [None] before the first host was selected
+    */
+    pub fn get_active_tier(&self) -> Option<u32> {
+        self.active_tier
+    }
+
+    /**
+        Returns how many times traffic moved between tiers
+    */
+    pub fn get_tier_shifts(&self) -> u64 {
+        self.tier_shifts
+    }
+
+    /**
+        Picks the tier new connections should go to: the lowest one where enough active hosts are available (as decided
+        by [is_available]), otherwise the lowest one with any available host. Returns [None] if no host is available.
+        Balancing algorithms should only pick hosts from the returned tier.
+    */
+    pub fn select_tier<F>(&mut self, is_available: F) -> Option<u32>
+    where
+        F: Fn(SocketAddr) -> bool,
+    {
+        let mut tiers: Vec<u32> = self.hosts.iter().map(|h| self.get_tier(*h)).collect();
+        tiers.sort_unstable();
+        tiers.dedup();
+
+        let mut selected = None;
+        let mut fallback = None;
+        for tier in tiers {
+            let active: Vec<SocketAddr> = self
+                .hosts
+                .iter()
+                .filter(|h| self.get_tier(**h) == tier && self.get_state(**h) == HostState::Active)
+                .copied()
+                .collect();
+            let available = active.iter().filter(|h| is_available(**h)).count();
+            if available == 0 {
+                continue;
+            }
+
+            if available as f64 / active.len() as f64 >= self.failover_min_available {
+                selected = Some(tier);
+                break;
+            }
+            fallback.get_or_insert(tier);
+        }
+
+        let selected = selected.or(fallback)?;
+        if let Some(previous) = self.active_tier {
+            if previous < selected {
+                warn!(target: "Hosts", "Not enough hosts available in tier {}, traffic moved to tier {}", previous, selected);
+            } else if previous > selected {
+                info!(target: "Hosts", "Hosts in tier {} available again, traffic moved back from tier {}", selected, previous);
+            }
+
+            if previous != selected {
+                self.tier_shifts += 1;
+            }
+        }
+        self.active_tier = Some(selected);
+
+        Some(selected)
+    }
+
+    pub fn get_max_conns(&self, addr: SocketAddr) -> Option<usize> {
+        self.max_conns.get(&addr).copied()
+    }
+
+    pub fn get_connections(&self, addr: SocketAddr) -> usize {
+        self.connections.get(&addr).map_or(0, |c| c.load(Ordering::Relaxed))
+    }
+
+    /**
+        Checks if the host has reached its connection limit
+    */
+    pub fn is_full(&self, addr: SocketAddr) -> bool {
+        self.get_max_conns(addr).is_some_and(|m| self.get_connections(addr) >= m)
+    }
+
+    /**
+        Checks if any active host has reached its connection limit, clients should then wait instead of being disconnected
+    */
+    pub fn has_full_hosts(&self) -> bool {
+        self.hosts.iter().any(|h| self.get_state(*h) == HostState::Active && self.is_full(*h))
+    }
+
+    /**
+        Counts a new connection to the host until the returned slot is dropped. Should be called while selecting the host
+        (with the balancing algorithm locked), so concurrent selections can't go over the limit
+    */
+    pub fn acquire(&mut self, addr: SocketAddr) -> ConnectionSlot {
+        let count = self.connections.entry(addr).or_insert_with(|| Arc::new(AtomicUsize::new(0)));
+        count.fetch_add(1, Ordering::Relaxed);
+
+        ConnectionSlot(Arc::clone(count))
+    }
+
+    /**
+        Validates and resolves the given `[HOSTNAME]:[PORT]`. If multiple IPs are resolved, IPv4 is preferred
+    */
+    pub fn resolve_host(host: &str) -> Option<SocketAddr> {
+        // validate IP address and port - either IPv4 or IPv6 with valid port number
+        // this also accepts domains and tries to resolve them, the first resolved IP is used
+        let addr: Vec<SocketAddr> = match host.to_socket_addrs() {
+            Ok(a) => a.collect(),
+            Err(_) => return None,
+        };
+
+        let mut resolved_addr: SocketAddr = *addr.first()?;
 
-        return HostManager { hosts: hosts };
+        // if there are more than 1 IP resolved, prioritize the IPv4
+        if addr.len() > 1 {
+            for a in addr {
+                if a.is_ipv4() {
+                    resolved_addr = a;
+                    break;
+                }
+            }
+        }
+
+        Some(resolved_addr)
     }
 
-    fn parse_hosts(hostfile: &str) -> Result<Vec<SocketAddr>> {
+    /**
+        Parses the host file, every line holds `[HOSTNAME]:[PORT]` optionally followed by host options (`backup`, `tier=N` or `max_conns=N`)
+    */
+    fn parse_hosts(&mut self, hostfile: &str) -> Result<Vec<SocketAddr>> {
         let mut hosts: Vec<SocketAddr> = vec![];
 
         let file = File::open(hostfile)?;
@@ -43,25 +429,28 @@ impl HostManager {
                 continue;
             }
 
-            // validate IP address and port - either IPv4 or IPv6 with valid port number
-            // this also accepts domains and tries to resolve them, the first resolved IP is used
-            let addr: Vec<SocketAddr> = match l.to_socket_addrs() {
-                Ok(a) => a.collect(),
-                Err(_) => {
-                    println!("[Parser] Invalid host: '{}'", l);
+            let mut parts = l.split_whitespace();
+            let host = parts.next().unwrap_or("");
+            let resolved_addr = match HostManager::resolve_host(host) {
+                Some(a) => a,
+                None => {
+                    warn!(target: "Parser", "Invalid host: '{}'", l);
                     continue;
                 }
             };
 
-            let mut resolved_addr: SocketAddr = addr[0];
-
-            // if there are more than 1 IP resolved, prioritize the IPv4
-            if addr.len() > 1 {
-                for a in addr {
-                    if a.is_ipv4() {
-                        resolved_addr = a;
-                        break;
+            for option in parts {
+                match option.split_once('=') {
+                    None if option == "backup" => {
+                        self.tiers.insert(resolved_addr, 1);
+                    }
+                    Some(("tier", t)) if t.parse::<u32>().is_ok() => {
+                        self.tiers.insert(resolved_addr, t.parse().unwrap());
+                    }
+                    Some(("max_conns", m)) if m.parse::<usize>().is_ok() => {
+                        self.max_conns.insert(resolved_addr, m.parse().unwrap());
                     }
+                    _ => warn!(target: "Parser", "Invalid option '{}' for host '{}'", option, host),
                 }
             }
 
@@ -69,7 +458,7 @@ impl HostManager {
             hosts.push(resolved_addr);
         }
 
-        println!("[Parser] Registered {} valid hosts", hosts.len());
+        info!(target: "Parser", "Registered {} valid hosts", hosts.len());
         Ok(hosts)
     }
 }
@@ -0,0 +1,1427 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use log::debug;
+
+use super::sni::NameMap;
+use super::{AccessList, CloseReason, Pool};
+
+/**
+    Longest request or response head (request or status line and headers) that is accepted
+*/
+const MAX_HEAD: usize = 64 * 1024;
+
+// longest chunk size line (with extensions) and trailer line of a chunked body
+const MAX_CHUNK_LINE: usize = 4096;
+
+/**
+    Routes of a single host, by path prefix. Longer prefixes are checked first
+*/
+struct PathRoutes {
+    routes: Vec<(String, Arc<Pool>)>,
+}
+
+impl PathRoutes {
+    fn new() -> Self {
+        PathRoutes { routes: vec![] }
+    }
+
+    fn add(&mut self, prefix: &str, pool: Arc<Pool>) {
+        self.routes.push((prefix.to_string(), pool));
+        self.routes.sort_by_key(|(p, _)| std::cmp::Reverse(p.len()));
+    }
+
+    fn get(&self, path: &str) -> Option<&Arc<Pool>> {
+        self.routes.iter().find(|(prefix, _)| path_matches(prefix, path)).map(|(_, pool)| pool)
+    }
+}
+
+/**
+    Prefixes match whole path segments, `/api` matches `/api` and `/api/users` but not `/apis`
+*/
+fn path_matches(prefix: &str, path: &str) -> bool {
+    // the root matches every target, also `*` of OPTIONS requests
+    if prefix == "/" {
+        return true;
+    }
+
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || prefix.ends_with('/') || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/**
+    Picks a pool for every HTTP request by its host and path. Hosts are matched like SNI routes (exactly first, then by the
+    longest matching wildcard), then the longest matching path prefix of the host wins. Requests no route of their host
+    matches try the routes without a host, then go to the default pool
+*/
+pub struct HttpRouter {
+    hosts: NameMap<PathRoutes>,
+    any_host: PathRoutes,
+    default: Arc<Pool>,
+}
+
+impl HttpRouter {
+    pub fn new(default: Arc<Pool>) -> Self {
+        HttpRouter {
+            hosts: NameMap::new(),
+            any_host: PathRoutes::new(),
+            default,
+        }
+    }
+
+    /**
+        Routes requests for [host] (an exact name, a wildcard like `*.example.com` or any host if not set) with a path
+        starting with [path] (any path if not set) to [pool]
+    */
+    pub fn add_route(&mut self, host: Option<&str>, path: Option<&str>, pool: Arc<Pool>) {
+        let path = path.unwrap_or("/");
+        match host {
+            Some(host) => match self.hosts.get_added_mut(host) {
+                Some(routes) => routes.add(path, pool),
+                None => {
+                    let mut routes = PathRoutes::new();
+                    routes.add(path, pool);
+                    self.hosts.insert(host, routes);
+                }
+            },
+            None => self.any_host.add(path, pool),
+        }
+    }
+
+    pub fn route(&self, request: &RequestHead) -> &Arc<Pool> {
+        let path = request.path();
+        request
+            .host()
+            .and_then(|h| self.hosts.get(&h))
+            .and_then(|routes| routes.get(path))
+            .or_else(|| self.any_host.get(path))
+            .unwrap_or(&self.default)
+    }
+}
+
+/**
+    Header fields of a request or response, in the order they were received. Values are kept as sent
+*/
+#[derive(Debug)]
+pub struct Headers {
+    fields: Vec<(String, Vec<u8>)>,
+}
+
+impl Headers {
+    /**
+        Returns the value of the first header with the given name (case insensitive)
+    */
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.fields.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_slice())
+    }
+
+    /**
+        Returns the values of all headers with the given name (case insensitive)
+    */
+    pub fn values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.fields.iter().filter(move |(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_slice())
+    }
+
+    /**
+        Returns the comma separated elements of all headers with the given name
+    */
+    fn elements<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.values(name)
+            .flat_map(|v| std::str::from_utf8(v).unwrap_or("").split(','))
+            .map(|e| e.trim_matches(|c| c == ' ' || c == '\t'))
+            .filter(|e| !e.is_empty())
+    }
+
+    /**
+        Checks if a list header (like `Connection`) contains [token] (case insensitive)
+    */
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.elements(name).any(|e| e.eq_ignore_ascii_case(token))
+    }
+
+    /**
+        Removes all headers with the given name (case insensitive), returns their values
+    */
+    pub fn remove(&mut self, name: &str) -> Vec<Vec<u8>> {
+        let mut removed = vec![];
+        self.fields.retain_mut(|(n, v)| {
+            if !n.eq_ignore_ascii_case(name) {
+                return true;
+            }
+            removed.push(std::mem::take(v));
+            false
+        });
+
+        removed
+    }
+
+    /**
+        Adds a header after all others
+    */
+    pub fn add(&mut self, name: &str, value: Vec<u8>) {
+        self.fields.push((name.to_string(), value));
+    }
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        for (name, value) in &self.fields {
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(b": ");
+            out.extend_from_slice(value);
+            out.extend_from_slice(b"\r\n");
+        }
+    }
+
+    /**
+        Parses header lines, returns [None] if any of them is malformed. Lines folded onto multiple lines (obsolete) and
+        whitespace between name and colon are rejected, proxies and backends could disagree about them
+    */
+    fn parse(lines: &[&[u8]]) -> Option<Self> {
+        let mut fields = vec![];
+        for line in lines {
+            let colon = line.iter().position(|&b| b == b':')?;
+            let name = &line[..colon];
+            if name.is_empty() || !name.iter().all(|&b| is_token(b)) {
+                return None;
+            }
+
+            let value = trim(&line[colon + 1..]);
+            if value.iter().any(|&b| b == b'\r' || b == b'\n' || b == 0) {
+                return None;
+            }
+
+            // names are tokens, so they are ASCII
+            fields.push((String::from_utf8(name.to_vec()).ok()?, value.to_vec()));
+        }
+
+        Some(Headers { fields })
+    }
+
+    /**
+        Returns the body length announced by `Content-Length`, [None] if there is none. Repeated values have to agree
+    */
+    fn content_length(&self) -> Result<Option<u64>, ()> {
+        let mut length = None;
+        for element in self.values("content-length").flat_map(|v| v.split(|&b| b == b',')) {
+            let element = trim(element);
+            if element.is_empty() || !element.iter().all(|b| b.is_ascii_digit()) {
+                return Err(());
+            }
+
+            let value = std::str::from_utf8(element).unwrap().parse().map_err(|_| ())?;
+            if length.is_some_and(|l| l != value) {
+                return Err(());
+            }
+            length = Some(value);
+        }
+
+        Ok(length)
+    }
+
+    /**
+        Checks the transfer codings, returns [None] without `Transfer-Encoding`, otherwise if `chunked` is the last one
+    */
+    fn chunked(&self) -> Option<bool> {
+        self.get("transfer-encoding")?;
+        Some(self.elements("transfer-encoding").last().is_some_and(|e| e.eq_ignore_ascii_case("chunked")))
+    }
+}
+
+/**
+    Request line and headers of an HTTP/1.x request
+*/
+#[derive(Debug)]
+pub struct RequestHead {
+    pub method: String,
+    pub target: String,
+    /**
+        Minor version, 0 for HTTP/1.0 and 1 for HTTP/1.1
+    */
+    pub version: u8,
+    pub headers: Headers,
+}
+
+impl RequestHead {
+    /**
+        Returns the lowercase host the request is for (without port), from an absolute target or the `Host` header
+    */
+    pub fn host(&self) -> Option<String> {
+        let authority = match self.authority() {
+            Some(a) => a,
+            None => std::str::from_utf8(self.headers.get("host")?).ok()?,
+        };
+
+        // IPv6 addresses are enclosed in brackets, the port comes after them
+        let host = match authority.rfind(':') {
+            Some(i) if !authority[i..].contains(']') => &authority[..i],
+            _ => authority,
+        };
+        if host.is_empty() {
+            return None;
+        }
+
+        Some(host.to_lowercase())
+    }
+
+    /**
+        Returns the authority of absolute (`http://host/path`) and CONNECT (`host:port`) targets
+    */
+    fn authority(&self) -> Option<&str> {
+        if self.method == "CONNECT" {
+            return Some(&self.target);
+        }
+
+        let (_, rest) = self.target.split_once("://")?;
+        Some(rest.split(['/', '?']).next().unwrap_or(""))
+    }
+
+    /**
+        Returns the path of the target, without query
+    */
+    pub fn path(&self) -> &str {
+        let path = match self.target.split_once("://") {
+            Some((_, rest)) => rest.find('/').map_or("/", |i| &rest[i..]),
+            None => &self.target,
+        };
+
+        path.split('?').next().unwrap_or("")
+    }
+
+    /**
+        Checks if the client wants to send further requests on the connection
+    */
+    pub fn keep_alive(&self) -> bool {
+        keep_alive(self.version, &self.headers)
+    }
+
+    /**
+        Checks if the client asks to switch the connection to another protocol (like WebSocket)
+    */
+    fn upgrade(&self) -> bool {
+        self.version > 0 && self.headers.get("upgrade").is_some() && self.headers.has_token("connection", "upgrade")
+    }
+
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(format!("{} {} HTTP/1.{}\r\n", self.method, self.target, self.version).as_bytes());
+        self.headers.write_to(out);
+        out.extend_from_slice(b"\r\n");
+    }
+
+    /**
+        Parses the request head at the start of [data]. Returns the head and its length, [None] if more data is needed,
+        or the status of the error response if the request is malformed
+    */
+    pub fn parse(data: &[u8]) -> Result<Option<(Self, usize)>, u16> {
+        let (lines, length) = match split_head(data) {
+            Some(h) => h,
+            None if data.len() > MAX_HEAD => return Err(431),
+            None => return Ok(None),
+        };
+        if length > MAX_HEAD {
+            return Err(431);
+        }
+
+        let mut parts = lines[0].split(|&b| b == b' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(m), Some(t), Some(v), None) => (m, t, v),
+            _ => return Err(400),
+        };
+        if method.is_empty() || !method.iter().all(|&b| is_token(b)) || target.is_empty() || !target.iter().all(|b| b.is_ascii_graphic()) {
+            return Err(400);
+        }
+        let version = match version {
+            b"HTTP/1.1" => 1,
+            b"HTTP/1.0" => 0,
+            v if v.starts_with(b"HTTP/") => return Err(505),
+            _ => return Err(400),
+        };
+
+        let headers = Headers::parse(&lines[1..]).ok_or(400u16)?;
+        // HTTP/1.1 requests need exactly one host
+        if version > 0 && headers.values("host").count() != 1 {
+            return Err(400);
+        }
+
+        let head = RequestHead {
+            method: String::from_utf8(method.to_vec()).unwrap(),
+            target: String::from_utf8(target.to_vec()).unwrap(),
+            version,
+            headers,
+        };
+        Ok(Some((head, length)))
+    }
+
+    /**
+        Determines how the request body is delimited, requests with unclear framing are rejected so they can't be
+        understood differently by the backend (request smuggling)
+    */
+    fn body(&self) -> Result<BodyReader, u16> {
+        let length = self.headers.content_length().map_err(|_| 400u16)?;
+        match self.headers.chunked() {
+            Some(true) if length.is_none() && self.version > 0 => Ok(BodyReader::chunked()),
+            Some(_) => Err(400),
+            None => Ok(BodyReader::Length(length.unwrap_or(0))),
+        }
+    }
+}
+
+/**
+    Status line and headers of an HTTP/1.x response
+*/
+struct ResponseHead {
+    status: u16,
+    version: u8,
+    headers: Headers,
+}
+
+impl ResponseHead {
+    fn parse(head: &[u8]) -> Option<Self> {
+        let (lines, _) = split_head(head)?;
+
+        let mut parts = lines[0].splitn(3, |&b| b == b' ');
+        let version = match parts.next()? {
+            b"HTTP/1.1" => 1,
+            b"HTTP/1.0" => 0,
+            _ => return None,
+        };
+        let status = parts.next()?;
+        if status.len() != 3 || !status.iter().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+
+        Some(ResponseHead {
+            status: std::str::from_utf8(status).unwrap().parse().unwrap(),
+            version,
+            headers: Headers::parse(&lines[1..])?,
+        })
+    }
+}
+
+/**
+    HTTP/1.1 connections are kept open unless told otherwise, HTTP/1.0 ones only if asked for
+*/
+fn keep_alive(version: u8, headers: &Headers) -> bool {
+    if version > 0 {
+        !headers.has_token("connection", "close")
+    } else {
+        headers.has_token("connection", "keep-alive")
+    }
+}
+
+/**
+    Splits a head ending with an empty line into its lines, returns them and the length of the head, or [None] if the
+    head is not complete yet
+*/
+fn split_head(data: &[u8]) -> Option<(Vec<&[u8]>, usize)> {
+    let end = data.windows(4).position(|w| w == b"\r\n\r\n")?;
+
+    // lines end with CRLF only, stray CRs and LFs are left in the lines to be rejected
+    let mut lines = vec![];
+    let mut rest = &data[..end];
+    while let Some(i) = rest.windows(2).position(|w| w == b"\r\n") {
+        lines.push(&rest[..i]);
+        rest = &rest[i + 2..];
+    }
+    lines.push(rest);
+
+    Some((lines, end + 4))
+}
+
+fn is_token(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+fn trim(value: &[u8]) -> &[u8] {
+    let start = value.iter().position(|&b| b != b' ' && b != b'\t').unwrap_or(value.len());
+    let end = value.iter().rposition(|&b| b != b' ' && b != b'\t').map_or(start, |i| i + 1);
+    &value[start..end]
+}
+
+/**
+    Position within a chunked body
+*/
+enum Chunked {
+    /**
+        In the chunk size line, [extension] once the size is followed by whitespace or an extension
+    */
+    Size {
+        size: u64,
+        digits: usize,
+        extension: bool,
+        length: usize,
+    },
+    SizeLf {
+        size: u64,
+    },
+    Data(u64),
+    DataCr,
+    DataLf,
+    /**
+        In a trailer line, the body ends with an empty one
+    */
+    Trailer {
+        length: usize,
+    },
+    TrailerLf {
+        empty: bool,
+    },
+    Done,
+}
+
+/**
+    Follows a message body to find where it ends. The body itself is forwarded as is
+*/
+enum BodyReader {
+    /**
+        Bytes still to come
+    */
+    Length(u64),
+    Chunked(Chunked),
+}
+
+impl BodyReader {
+    fn chunked() -> Self {
+        BodyReader::Chunked(Chunked::Size {
+            size: 0,
+            digits: 0,
+            extension: false,
+            length: 0,
+        })
+    }
+
+    fn is_done(&self) -> bool {
+        matches!(self, BodyReader::Length(0) | BodyReader::Chunked(Chunked::Done))
+    }
+
+    /**
+        Returns how many bytes at the start of [data] belong to the body, [None] if the body is malformed
+    */
+    fn consume(&mut self, data: &[u8]) -> Option<usize> {
+        let state = match self {
+            BodyReader::Length(remaining) => {
+                let taken = (*remaining).min(data.len() as u64);
+                *remaining -= taken;
+                return Some(taken as usize);
+            }
+            BodyReader::Chunked(state) => state,
+        };
+
+        let mut i = 0;
+        while i < data.len() {
+            let b = data[i];
+            *state = match *state {
+                Chunked::Size {
+                    size,
+                    digits,
+                    extension,
+                    length,
+                } => {
+                    if length >= MAX_CHUNK_LINE {
+                        return None;
+                    }
+                    let length = length + 1;
+                    match b {
+                        b'\r' if digits > 0 => Chunked::SizeLf { size },
+                        b'\r' | b'\n' => return None,
+                        // extensions are ignored, but their whitespace may only come after the size
+                        _ if extension => Chunked::Size {
+                            size,
+                            digits,
+                            extension,
+                            length,
+                        },
+                        b';' | b' ' | b'\t' if digits > 0 => Chunked::Size {
+                            size,
+                            digits,
+                            extension: true,
+                            length,
+                        },
+                        _ if b.is_ascii_hexdigit() && digits < 15 => Chunked::Size {
+                            size: size * 16 + (b as char).to_digit(16).unwrap() as u64,
+                            digits: digits + 1,
+                            extension,
+                            length,
+                        },
+                        _ => return None,
+                    }
+                }
+                Chunked::SizeLf { size } => match b {
+                    b'\n' if size == 0 => Chunked::Trailer { length: 0 },
+                    b'\n' => Chunked::Data(size),
+                    _ => return None,
+                },
+                Chunked::Data(remaining) => {
+                    let taken = remaining.min((data.len() - i) as u64);
+                    i += taken as usize;
+                    *state = match remaining - taken {
+                        0 => Chunked::DataCr,
+                        r => Chunked::Data(r),
+                    };
+                    continue;
+                }
+                Chunked::DataCr if b == b'\r' => Chunked::DataLf,
+                Chunked::DataLf if b == b'\n' => Chunked::Size {
+                    size: 0,
+                    digits: 0,
+                    extension: false,
+                    length: 0,
+                },
+                Chunked::DataCr | Chunked::DataLf => return None,
+                Chunked::Trailer { length } => match b {
+                    b'\r' => Chunked::TrailerLf { empty: length == 0 },
+                    b'\n' => return None,
+                    _ if length >= MAX_CHUNK_LINE => return None,
+                    _ => Chunked::Trailer { length: length + 1 },
+                },
+                Chunked::TrailerLf { empty } => match b {
+                    b'\n' if empty => Chunked::Done,
+                    b'\n' => Chunked::Trailer { length: 0 },
+                    _ => return None,
+                },
+                Chunked::Done => break,
+            };
+            i += 1;
+        }
+
+        Some(i)
+    }
+}
+
+/**
+    Request that was sent (or is being sent) to the target, and what is known about its response
+*/
+struct Exchange {
+    head_request: bool,
+    connect: bool,
+    upgrade: bool,
+    request_sent: bool,
+    /**
+        Set once the final response head was forwarded to the client
+    */
+    response_started: bool,
+}
+
+enum RequestState {
+    /**
+        Waiting for the next request head
+    */
+    Head,
+    Body(BodyReader),
+    /**
+        The request asked to switch protocols, what follows depends on the response
+    */
+    Upgrading,
+    /**
+        Everything is forwarded as is (after a protocol switch or CONNECT)
+    */
+    Tunnel,
+}
+
+enum ResponseState {
+    Head,
+    Body(BodyReader),
+    /**
+        The response has no length, it ends when the target closes the connection
+    */
+    UntilClose,
+    Tunnel,
+}
+
+/**
+    What the session wants to do next with the client's data
+*/
+#[derive(PartialEq, Debug)]
+pub enum Upload {
+    /**
+        Data to be forwarded to the target was added
+    */
+    Data,
+    /**
+        More data from the client is needed
+    */
+    NeedInput,
+    /**
+        Nothing can be forwarded until the target's response arrived
+    */
+    Blocked,
+    /**
+        The next request has to go to a new target of the pool returned by [HttpSession::next_pool]
+    */
+    SwitchTarget,
+    /**
+        The request is malformed, the client gets an error response with this status and is disconnected
+    */
+    Reject(u16),
+}
+
+/**
+    Headers telling backends about the client and how it connected, added to every request. The headers of trusted
+    proxies in front of the balancer are kept and appended to, other clients' ones are replaced as they could be forged
+*/
+pub struct ForwardedHeaders {
+    /**
+        `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Port`
+    */
+    x_forwarded: bool,
+    /**
+        `Forwarded` (RFC 7239)
+    */
+    forwarded: bool,
+    trusted_proxies: Option<AccessList>,
+}
+
+impl ForwardedHeaders {
+    /**
+        [trusted_proxies] should only have allow rules, nobody is trusted if it has none
+    */
+    pub fn new(x_forwarded: bool, forwarded: bool, trusted_proxies: AccessList) -> Self {
+        ForwardedHeaders {
+            x_forwarded,
+            forwarded,
+            trusted_proxies: Some(trusted_proxies).filter(|t| t.rule_count() > 0),
+        }
+    }
+
+    /**
+        Adds the headers to a request of [client] that connected to [listener], with TLS if [tls] is set
+    */
+    fn apply(&self, head: &mut RequestHead, client: SocketAddr, listener: SocketAddr, tls: bool) {
+        let trusted = self.trusted_proxies.as_ref().is_some_and(|t| t.is_allowed(client.ip()));
+        // IPv4 clients of dual-stack listeners are told as IPv4 addresses
+        let ip = match client.ip() {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(client.ip(), IpAddr::V4),
+            v4 => v4,
+        };
+        let proto = if tls { "https" } else { "http" };
+
+        if self.x_forwarded {
+            append(head, "X-Forwarded-For", ip.to_string(), trusted);
+            for (name, value) in [("X-Forwarded-Proto", proto.to_string()), ("X-Forwarded-Port", listener.port().to_string())] {
+                let existing = head.headers.remove(name);
+                match existing.into_iter().next() {
+                    Some(existing) if trusted => head.headers.add(name, existing),
+                    _ => head.headers.add(name, value.into_bytes()),
+                }
+            }
+        }
+
+        if self.forwarded {
+            let node = match ip {
+                IpAddr::V4(v4) => v4.to_string(),
+                IpAddr::V6(v6) => format!("\"[{}]\"", v6),
+            };
+            let mut element = format!("for={}", node);
+            if let Some(host) = head.headers.get("host").and_then(|h| std::str::from_utf8(h).ok()) {
+                element += &format!(";host={}", quote(host));
+            }
+            element += &format!(";proto={}", proto);
+
+            append(head, "Forwarded", element, trusted);
+        }
+    }
+}
+
+/**
+    Appends [element] to the list in the header [name] if [trusted], otherwise the header is replaced
+*/
+fn append(head: &mut RequestHead, name: &str, element: String, trusted: bool) {
+    let mut value = vec![];
+    if trusted {
+        for existing in head.headers.remove(name).iter().filter(|v| !v.is_empty()) {
+            value.extend_from_slice(existing);
+            value.extend_from_slice(b", ");
+        }
+    } else {
+        head.headers.remove(name);
+    }
+    value.extend_from_slice(element.as_bytes());
+
+    head.headers.add(name, value);
+}
+
+/**
+    Values of `Forwarded` parameters are tokens or quoted strings
+*/
+fn quote(value: &str) -> String {
+    if !value.is_empty() && value.bytes().all(is_token) {
+        return value.to_string();
+    }
+
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/**
+    Forwarding headers added to the requests of a single client
+*/
+struct Forwarding {
+    headers: Arc<ForwardedHeaders>,
+    client: SocketAddr,
+    listener: SocketAddr,
+    tls: bool,
+}
+
+/**
+    State of an HTTP/1.x client connection, whose requests are routed one by one. Requests the client sends before
+    getting the previous response (pipelining) wait until the response is complete, so every target only ever has
+    a single request in flight
+*/
+pub struct HttpSession {
+    router: Arc<HttpRouter>,
+    forwarding: Option<Forwarding>,
+    /**
+        Set if every request gets a target picked by the balancing algorithm, instead of reusing the previous one
+    */
+    balance_requests: bool,
+    /**
+        Data read from the client that wasn't forwarded yet
+    */
+    pub input: Vec<u8>,
+    request: RequestState,
+    /**
+        The next request, parsed and routed but not sent yet
+    */
+    next: Option<(RequestHead, Arc<Pool>)>,
+    exchange: Option<Exchange>,
+    response: ResponseState,
+    response_head: Vec<u8>,
+    /**
+        Cleared once the target announced to close the connection after the current response
+    */
+    target_reusable: bool,
+    target_closed: bool,
+    /**
+        Set once a request was sent to the target
+    */
+    target_used: bool,
+    /**
+        Set if the client connection ends once the current response reached it
+    */
+    close_after: Option<CloseReason>,
+}
+
+impl HttpSession {
+    pub fn new(router: Arc<HttpRouter>) -> Self {
+        HttpSession {
+            router,
+            forwarding: None,
+            balance_requests: false,
+            input: vec![],
+            request: RequestState::Head,
+            next: None,
+            exchange: None,
+            response: ResponseState::Head,
+            response_head: vec![],
+            target_reusable: true,
+            target_closed: false,
+            target_used: false,
+            close_after: None,
+        }
+    }
+
+    /**
+        Picks a new target for every request, so the requests of long-lived clients are spread over the pool's hosts
+    */
+    pub fn set_balance_requests(&mut self) {
+        self.balance_requests = true;
+    }
+
+    /**
+        Adds forwarding [headers] to the requests of [client], which connected to [listener] (with TLS if [tls] is set)
+    */
+    pub fn set_forwarded_headers(&mut self, headers: Arc<ForwardedHeaders>, client: SocketAddr, listener: SocketAddr, tls: bool) {
+        self.forwarding = Some(Forwarding {
+            headers,
+            client,
+            listener,
+            tls,
+        });
+    }
+
+    /**
+        Returns the pool of the next request, once it's routed
+    */
+    pub fn next_pool(&self) -> Option<&Arc<Pool>> {
+        self.next.as_ref().map(|(_, pool)| pool)
+    }
+
+    /**
+        Checks if the session is between requests, the previous response is complete and the next one wasn't sent yet
+    */
+    pub fn is_idle(&self) -> bool {
+        matches!(self.request, RequestState::Head) && self.exchange.is_none()
+    }
+
+    /**
+        Checks if the session waits for the client's next request head, nothing before it is left to do
+    */
+    pub fn awaits_head(&self) -> bool {
+        self.is_idle() && self.next.is_none() && self.close_after.is_none()
+    }
+
+    /**
+        Checks if a request was sent without getting its complete response
+    */
+    pub fn in_flight(&self) -> bool {
+        self.exchange.is_some()
+    }
+
+    /**
+        Checks if the client got nothing of the response to the current request yet, so it can still get an error response
+    */
+    pub fn can_respond(&self) -> bool {
+        self.exchange.as_ref().is_some_and(|e| !e.response_started)
+    }
+
+    pub fn target_closed(&self) -> bool {
+        self.target_closed
+    }
+
+    /**
+        Checks if the connection to the target can carry another request: it's between requests, and neither side
+        announced to close it
+    */
+    pub fn target_reusable(&self) -> bool {
+        self.target_reusable && !self.target_closed && self.is_idle()
+    }
+
+    /**
+        Returns why the client connection ends once everything reached it, if it does
+    */
+    pub fn close_after(&self) -> Option<CloseReason> {
+        self.close_after
+    }
+
+    /**
+        Starts over with a new connection to a target
+    */
+    pub fn set_target(&mut self) {
+        self.target_reusable = true;
+        self.target_closed = false;
+        self.target_used = false;
+        self.response = ResponseState::Head;
+        self.response_head.clear();
+    }
+
+    /**
+        Moves the next part of the client's data to [out], to be forwarded to the target. [target] is the pool of
+        the connected target, if there is one
+    */
+    pub fn upload(&mut self, target: Option<&Arc<Pool>>, out: &mut Vec<u8>) -> Upload {
+        match &mut self.request {
+            RequestState::Tunnel => {
+                if self.input.is_empty() {
+                    return Upload::NeedInput;
+                }
+                out.append(&mut self.input);
+                Upload::Data
+            }
+            RequestState::Upgrading => Upload::Blocked,
+            RequestState::Body(body) => {
+                if self.input.is_empty() {
+                    return Upload::NeedInput;
+                }
+                let taken = match body.consume(&self.input) {
+                    Some(t) => t,
+                    None => return Upload::Reject(400),
+                };
+                out.extend(self.input.drain(..taken));
+
+                if body.is_done() {
+                    self.request_sent();
+                }
+                Upload::Data
+            }
+            RequestState::Head => {
+                // no further requests are read once the connection is about to end
+                if self.exchange.is_some() || self.close_after.is_some() {
+                    return Upload::Blocked;
+                }
+
+                if self.next.is_none() {
+                    // empty lines between requests are ignored
+                    let blank = self.input.iter().position(|&b| b != b'\r' && b != b'\n').unwrap_or(self.input.len());
+                    self.input.drain(..blank);
+
+                    let (head, length) = match RequestHead::parse(&self.input) {
+                        Ok(Some(h)) => h,
+                        Ok(None) => return Upload::NeedInput,
+                        Err(status) => return Upload::Reject(status),
+                    };
+                    self.input.drain(..length);
+
+                    let pool = Arc::clone(self.router.route(&head));
+                    debug!(target: "HTTP", "{} {} (host {}) -> pool '{}'", head.method, head.target, head.host().as_deref().unwrap_or("-"), pool.name);
+                    self.next = Some((head, pool));
+                }
+
+                let (head, pool) = self.next.as_ref().unwrap();
+                if !target.is_some_and(|t| Arc::ptr_eq(t, pool)) || !self.target_reusable || self.target_closed {
+                    return Upload::SwitchTarget;
+                }
+                // the previous target is kept for later requests if the pool keeps connections alive
+                if self.balance_requests && self.target_used {
+                    return Upload::SwitchTarget;
+                }
+
+                let body = match head.body() {
+                    Ok(b) => b,
+                    Err(status) => return Upload::Reject(status),
+                };
+                let (mut head, _) = self.next.take().unwrap();
+                if let Some(f) = &self.forwarding {
+                    f.headers.apply(&mut head, f.client, f.listener, f.tls);
+                }
+                head.write_to(out);
+                self.target_used = true;
+
+                if !head.keep_alive() {
+                    self.target_reusable = false;
+                    self.close_after = Some(CloseReason::ClientClosed);
+                }
+                self.exchange = Some(Exchange {
+                    head_request: head.method == "HEAD",
+                    connect: head.method == "CONNECT",
+                    upgrade: head.upgrade(),
+                    request_sent: false,
+                    response_started: false,
+                });
+                self.request = RequestState::Body(body);
+                if let RequestState::Body(body) = &self.request {
+                    if body.is_done() {
+                        self.request_sent();
+                    }
+                }
+                Upload::Data
+            }
+        }
+    }
+
+    fn request_sent(&mut self) {
+        let exchange = self.exchange.as_mut().unwrap();
+        exchange.request_sent = true;
+        self.request = if exchange.upgrade || exchange.connect {
+            RequestState::Upgrading
+        } else {
+            RequestState::Head
+        };
+    }
+
+    /**
+        Follows the target's response in [data], which is added to [out] to be forwarded to the client. Returns [false]
+        if the response is malformed
+    */
+    pub fn download(&mut self, data: &[u8], out: &mut Vec<u8>) -> bool {
+        let mut rest = data;
+        while !rest.is_empty() {
+            match &mut self.response {
+                ResponseState::UntilClose | ResponseState::Tunnel => {
+                    out.extend_from_slice(rest);
+                    break;
+                }
+                ResponseState::Body(body) => {
+                    let taken = match body.consume(rest) {
+                        Some(t) => t,
+                        None => return false,
+                    };
+                    out.extend_from_slice(&rest[..taken]);
+                    rest = &rest[taken..];
+
+                    if body.is_done() {
+                        self.response_complete();
+                    }
+                }
+                ResponseState::Head => {
+                    // nothing was asked for, targets may send an error before closing idle connections
+                    if self.exchange.is_none() {
+                        self.target_reusable = false;
+                        break;
+                    }
+
+                    // only the part that can still complete the head is searched
+                    let searched = self.response_head.len().saturating_sub(3);
+                    self.response_head.extend_from_slice(rest);
+                    let end = match self.response_head[searched..].windows(4).position(|w| w == b"\r\n\r\n") {
+                        Some(i) => searched + i + 4,
+                        None if self.response_head.len() > MAX_HEAD => return false,
+                        None => break,
+                    };
+                    rest = &rest[rest.len() - (self.response_head.len() - end)..];
+                    self.response_head.truncate(end);
+
+                    let head = match ResponseHead::parse(&self.response_head) {
+                        Some(h) => h,
+                        None => return false,
+                    };
+                    out.append(&mut self.response_head);
+                    if !self.response_head_received(&head) {
+                        return false;
+                    }
+                }
+            }
+        }
+
+        true
+    }
+
+    /**
+        Picks how the response continues after its head, returns [false] if it makes no sense for the request
+    */
+    fn response_head_received(&mut self, head: &ResponseHead) -> bool {
+        let exchange = self.exchange.as_mut().unwrap();
+
+        // switching protocols, or a tunnel was established
+        if (head.status == 101 && exchange.upgrade) || (exchange.connect && (200..300).contains(&head.status)) {
+            self.exchange = None;
+            self.request = RequestState::Tunnel;
+            self.response = ResponseState::Tunnel;
+            self.target_reusable = false;
+            return true;
+        }
+        if head.status == 101 {
+            return false;
+        }
+        // informational responses (like 100 Continue) come before the final one
+        if head.status < 200 {
+            return true;
+        }
+
+        exchange.response_started = true;
+        if !keep_alive(head.version, &head.headers) {
+            self.target_reusable = false;
+            self.close_after.get_or_insert(CloseReason::ServerClosed);
+        }
+
+        // responses to CONNECT requests that didn't establish a tunnel have a body like any other
+        let body = if exchange.head_request || head.status == 204 || head.status == 304 {
+            BodyReader::Length(0)
+        } else {
+            match (head.headers.chunked(), head.headers.content_length()) {
+                (Some(true), _) => BodyReader::chunked(),
+                (None, Ok(Some(length))) => BodyReader::Length(length),
+                (None, Err(_)) => return false,
+                // without a length, the response ends with the connection
+                _ => {
+                    self.response = ResponseState::UntilClose;
+                    self.target_reusable = false;
+                    self.close_after.get_or_insert(CloseReason::ServerClosed);
+                    return true;
+                }
+            }
+        };
+
+        if body.is_done() {
+            self.response_complete();
+        } else {
+            self.response = ResponseState::Body(body);
+        }
+        true
+    }
+
+    fn response_complete(&mut self) {
+        let exchange = self.exchange.take().unwrap();
+        self.response = ResponseState::Head;
+
+        // the rest of a request the target answered early can't be told apart from the next request
+        if !exchange.request_sent {
+            self.request = RequestState::Head;
+            self.target_reusable = false;
+            self.close_after.get_or_insert(CloseReason::ServerClosed);
+        } else if let RequestState::Upgrading = self.request {
+            self.request = RequestState::Head;
+        }
+    }
+
+    /**
+        Notes that the target closed the connection. Returns [false] if that cut off a response
+    */
+    pub fn close_target(&mut self) -> bool {
+        self.target_closed = true;
+        match self.response {
+            ResponseState::UntilClose => {
+                self.response_complete();
+                true
+            }
+            ResponseState::Tunnel => {
+                self.close_after.get_or_insert(CloseReason::ServerClosed);
+                true
+            }
+            _ => self.exchange.is_none(),
+        }
+    }
+}
+
+/**
+    Response sent to clients whose request couldn't be forwarded, the connection is closed after it
+*/
+pub fn error_response(status: u16) -> Vec<u8> {
+    let reason = match status {
+        400 => "Bad Request",
+        408 => "Request Timeout",
+        431 => "Request Header Fields Too Large",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "Error",
+    };
+
+    format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+        status,
+        reason,
+        reason.len() + 1,
+        reason
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{BandwidthLimits, HostManager, RoundRobin};
+    use super::*;
+
+    fn pool(name: &str) -> Arc<Pool> {
+        let path = std::env::temp_dir().join(format!("lb-http-{}-{}", std::process::id(), name));
+        std::fs::write(&path, "").unwrap();
+        let host_manager = HostManager::new(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        Arc::new(Pool::new(name, RoundRobin::new(host_manager), &BandwidthLimits::default()))
+    }
+
+    /**
+        Session of a client that has [input] waiting, connected to a target of its pool
+    */
+    fn connected_session(input: &[u8]) -> (HttpSession, Arc<Pool>) {
+        let pool = pool("default");
+        let mut session = HttpSession::new(Arc::new(HttpRouter::new(Arc::clone(&pool))));
+        session.input.extend_from_slice(input);
+        assert_eq!(session.upload(None, &mut vec![]), Upload::SwitchTarget);
+        session.set_target();
+        (session, pool)
+    }
+
+    fn upload(session: &mut HttpSession, pool: &Arc<Pool>) -> (Upload, Vec<u8>) {
+        let mut out = vec![];
+        let result = session.upload(Some(pool), &mut out);
+        (result, out)
+    }
+
+    fn download(session: &mut HttpSession, data: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        assert!(session.download(data, &mut out));
+        out
+    }
+
+    fn parse(head: &[u8]) -> Result<Option<RequestHead>, u16> {
+        RequestHead::parse(head).map(|h| h.map(|(head, _)| head))
+    }
+
+    #[test]
+    fn parses_request_heads() {
+        let data = b"GET http://Example.com:8080/a/b?c HTTP/1.1\r\nHost: example.com\r\nX-Empty:\r\n\r\nbody";
+        let (head, length) = RequestHead::parse(data).unwrap().unwrap();
+        assert_eq!(length, data.len() - 4);
+        assert_eq!((head.method.as_str(), head.version), ("GET", 1));
+        assert_eq!(head.host().as_deref(), Some("example.com"));
+        assert_eq!(head.path(), "/a/b");
+        assert_eq!(head.headers.get("x-empty"), Some(&b""[..]));
+
+        assert!(parse(b"GET / HTTP/1.1\r\nHost: example.com\r\n").unwrap().is_none());
+        let head = parse(b"CONNECT [2001:db8::1]:443 HTTP/1.1\r\nHost: [2001:db8::1]:443\r\n\r\n").unwrap().unwrap();
+        assert_eq!(head.host().as_deref(), Some("[2001:db8::1]"));
+    }
+
+    #[test]
+    fn rejects_malformed_request_heads() {
+        // folded header lines and whitespace before the colon
+        assert_eq!(parse(b"GET / HTTP/1.1\r\nHost: example.com\r\nX-Folded: a\r\n b\r\n\r\n").unwrap_err(), 400);
+        assert_eq!(parse(b"GET / HTTP/1.1\r\nHost: example.com\r\n\tX-Folded: b\r\n\r\n").unwrap_err(), 400);
+        assert_eq!(parse(b"GET / HTTP/1.1\r\nHost : example.com\r\n\r\n").unwrap_err(), 400);
+        // bare line breaks, missing or repeated hosts, broken request lines
+        assert_eq!(parse(b"GET / HTTP/1.1\r\nHost: example.com\nX-Other: a\r\n\r\n").unwrap_err(), 400);
+        assert_eq!(parse(b"GET / HTTP/1.1\r\n\r\n").unwrap_err(), 400);
+        assert_eq!(parse(b"GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n").unwrap_err(), 400);
+        assert_eq!(parse(b"GET  / HTTP/1.1\r\nHost: a\r\n\r\n").unwrap_err(), 400);
+        assert_eq!(parse(b"GET / HTTP/2.0\r\nHost: a\r\n\r\n").unwrap_err(), 505);
+        assert!(parse(b"GET / HTTP/1.0\r\n\r\n").unwrap().is_some());
+    }
+
+    #[test]
+    fn rejects_oversized_request_heads() {
+        let mut head = b"GET / HTTP/1.1\r\nHost: example.com\r\nX-Large: ".to_vec();
+        head.resize(MAX_HEAD + 1, b'a');
+        assert_eq!(parse(&head).unwrap_err(), 431);
+
+        head.extend_from_slice(b"\r\n\r\n");
+        assert_eq!(parse(&head).unwrap_err(), 431);
+
+        head.truncate(MAX_HEAD - 4);
+        head.extend_from_slice(b"\r\n\r\n");
+        assert!(parse(&head).unwrap().is_some());
+    }
+
+    #[test]
+    fn rejects_ambiguous_request_bodies() {
+        let body = |head: &[u8]| parse(head).unwrap().unwrap().body();
+
+        // repeated lengths have to agree
+        assert!(matches!(body(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\n"), Ok(BodyReader::Length(5))));
+        assert!(matches!(body(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5, 5\r\n\r\n"), Ok(BodyReader::Length(5))));
+        assert_eq!(body(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n").err(), Some(400));
+        assert_eq!(body(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: +5\r\n\r\n").err(), Some(400));
+
+        // a length next to a transfer coding could be read either way
+        assert_eq!(body(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n").err(), Some(400));
+        assert_eq!(body(b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: gzip\r\n\r\n").err(), Some(400));
+        assert_eq!(body(b"POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n").err(), Some(400));
+        assert!(matches!(body(b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: gzip, chunked\r\n\r\n"), Ok(BodyReader::Chunked(_))));
+    }
+
+    #[test]
+    fn follows_chunked_bodies_split_anywhere() {
+        let body = b"5;name=value\r\nhello\r\n1A  \r\nabcdefghijklmnopqrstuvwxyz\r\n0\r\nX-Trailer: a\r\nX-Other: b\r\n\r\n";
+        let mut data = body.to_vec();
+        data.extend_from_slice(b"GET / HTTP/1.1\r\n");
+
+        for split in 0..=data.len() {
+            let mut reader = BodyReader::chunked();
+            let first = reader.consume(&data[..split]).unwrap();
+            let second = reader.consume(&data[first..]).unwrap();
+            assert_eq!(first + second, body.len(), "split at {}", split);
+            assert!(reader.is_done(), "split at {}", split);
+        }
+
+        let mut reader = BodyReader::chunked();
+        for (i, b) in body.iter().enumerate() {
+            assert!(!reader.is_done());
+            assert_eq!(reader.consume(&[*b]), Some(1), "byte {}", i);
+        }
+        assert!(reader.is_done());
+        assert_eq!(reader.consume(b"GET"), Some(0));
+    }
+
+    #[test]
+    fn rejects_malformed_chunked_bodies() {
+        let consume = |data: &[u8]| BodyReader::chunked().consume(data);
+
+        assert_eq!(consume(b"\r\n"), None);
+        assert_eq!(consume(b";ext\r\n"), None);
+        assert_eq!(consume(b"5\nhello"), None);
+        assert_eq!(consume(b"5\r\nhelloX"), None);
+        assert_eq!(consume(b"0x5\r\n"), None);
+        assert_eq!(consume(b"1000000000000000\r\n"), None);
+        assert_eq!(consume(b"0\r\nX-Trailer: a\n"), None);
+
+        let mut line = b"5;".to_vec();
+        line.resize(MAX_CHUNK_LINE + 1, b'a');
+        assert_eq!(consume(&line), None);
+    }
+
+    #[test]
+    fn forwards_pipelined_requests_one_at_a_time() {
+        let (mut session, pool) = connected_session(b"GET /a HTTP/1.1\r\nHost: a\r\n\r\nPOST /b HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\n\r\nxyz");
+
+        let (result, out) = upload(&mut session, &pool);
+        assert_eq!(result, Upload::Data);
+        assert!(out.starts_with(b"GET /a HTTP/1.1\r\n"));
+        assert!(!out.windows(4).any(|w| w == b"POST"));
+        assert_eq!(upload(&mut session, &pool).0, Upload::Blocked);
+
+        let out = download(&mut session, b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+        assert_eq!(out, b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+        assert!(session.is_idle() && session.target_reusable());
+
+        let (result, out) = upload(&mut session, &pool);
+        assert_eq!(result, Upload::Data);
+        assert!(out.starts_with(b"POST /b HTTP/1.1\r\n") && out.ends_with(b"\r\n\r\n"));
+        let (result, out) = upload(&mut session, &pool);
+        assert_eq!((result, out.as_slice()), (Upload::Data, &b"xyz"[..]));
+        assert_eq!(upload(&mut session, &pool).0, Upload::Blocked);
+        assert!(session.in_flight());
+    }
+
+    #[test]
+    fn forwards_informational_responses_before_the_final_one() {
+        let (mut session, pool) = connected_session(b"POST / HTTP/1.1\r\nHost: a\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\n");
+        upload(&mut session, &pool);
+
+        let response = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 103 Early Hints\r\nLink: </a.css>\r\n\r\n";
+        assert_eq!(download(&mut session, response), response);
+        assert!(session.can_respond());
+
+        session.input.extend_from_slice(b"ok");
+        assert_eq!(upload(&mut session, &pool), (Upload::Data, b"ok".to_vec()));
+        let response = b"HTTP/1.1 201 Created\r\nContent-Length: 4\r\n\r\ndone";
+        assert_eq!(download(&mut session, response), response);
+        assert!(session.is_idle() && session.target_reusable());
+    }
+
+    #[test]
+    fn responses_without_body() {
+        for (request, response) in [
+            (&b"HEAD / HTTP/1.1\r\nHost: a\r\n\r\n"[..], &b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n"[..]),
+            (b"GET / HTTP/1.1\r\nHost: a\r\n\r\n", b"HTTP/1.1 204 No Content\r\nContent-Length: 10\r\n\r\n"),
+            (b"GET / HTTP/1.1\r\nHost: a\r\n\r\n", b"HTTP/1.1 304 Not Modified\r\nTransfer-Encoding: chunked\r\n\r\n"),
+        ] {
+            let (mut session, pool) = connected_session(request);
+            upload(&mut session, &pool);
+            download(&mut session, response);
+            assert!(session.is_idle() && session.target_reusable(), "{}", String::from_utf8_lossy(response));
+        }
+
+        // without a length, the response ends with the connection
+        let (mut session, pool) = connected_session(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+        upload(&mut session, &pool);
+        download(&mut session, b"HTTP/1.1 200 OK\r\n\r\nuntil the end");
+        assert!(session.in_flight() && !session.target_reusable());
+        assert!(session.close_target());
+        assert!(session.is_idle());
+        assert_eq!(session.close_after(), Some(CloseReason::ServerClosed));
+    }
+
+    #[test]
+    fn switches_to_tunnel_on_upgrade() {
+        let (mut session, pool) = connected_session(b"GET /chat HTTP/1.1\r\nHost: a\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\nframe");
+        upload(&mut session, &pool);
+        // nothing after the request is forwarded before the target agreed to switch
+        assert_eq!(upload(&mut session, &pool).0, Upload::Blocked);
+
+        let out = download(&mut session, b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\nserver frame");
+        assert!(out.ends_with(b"\r\n\r\nserver frame"));
+        assert_eq!(upload(&mut session, &pool), (Upload::Data, b"frame".to_vec()));
+        assert_eq!(download(&mut session, b"\r\n\r\nHTTP/1.1 anything"), b"\r\n\r\nHTTP/1.1 anything");
+        assert!(!session.target_reusable());
+
+        // a refused upgrade continues with HTTP
+        let (mut session, pool) = connected_session(b"GET /chat HTTP/1.1\r\nHost: a\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n");
+        upload(&mut session, &pool);
+        download(&mut session, b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nno");
+        assert!(session.is_idle() && session.target_reusable());
+    }
+
+    #[test]
+    fn switches_to_tunnel_on_connect() {
+        let (mut session, pool) = connected_session(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n\x16\x03\x01");
+        upload(&mut session, &pool);
+        assert_eq!(upload(&mut session, &pool).0, Upload::Blocked);
+
+        download(&mut session, b"HTTP/1.1 200 Connection Established\r\n\r\n");
+        assert_eq!(upload(&mut session, &pool), (Upload::Data, b"\x16\x03\x01".to_vec()));
+        assert_eq!(download(&mut session, b"\x16\x03\x03"), b"\x16\x03\x03");
+
+        // refused tunnels have a body like any other response, the connection stays HTTP
+        let (mut session, pool) = connected_session(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n");
+        upload(&mut session, &pool);
+        download(&mut session, b"HTTP/1.1 407 Proxy Authentication Required\r\nContent-Length: 6\r\n\r\n");
+        assert!(session.in_flight());
+        download(&mut session, b"denied");
+        assert!(session.is_idle() && session.target_reusable());
+
+        session.input.extend_from_slice(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+        let (result, out) = upload(&mut session, &pool);
+        assert_eq!(result, Upload::Data);
+        assert!(out.starts_with(b"GET / HTTP/1.1\r\n"));
+    }
+}
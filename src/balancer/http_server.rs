@@ -0,0 +1,270 @@
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+
+// requests larger than this are rejected
+const MAX_REQUEST_SIZE: usize = 1024 * 1024;
+
+// timeout for reading a request and writing its response
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// connections handled at once, so a few idle connections don't block the endpoint
+const WORKERS: usize = 4;
+
+// how long to wait before retrying to bind, the address may still be used by a previous instance (binary upgrade)
+const BIND_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    /**
+        Header names are lowercase
+    */
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /**
+        Returns the value of the first header with the given (lowercase) name
+    */
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+}
+
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16, content_type: &'static str, body: String) -> Self {
+        HttpResponse { status, content_type, body }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            413 => "Payload Too Large",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+/**
+    Serves HTTP requests on the given address from a few separate threads, one request per connection.
+    Meant for small internal endpoints (metrics, administration), not for proxied traffic.
+*/
+pub fn serve<F>(name: &'static str, addr: SocketAddr, handler: F)
+where
+    F: Fn(&HttpRequest) -> HttpResponse + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+
+    thread::spawn(move || {
+        let listener = bind(name, addr);
+        info!(target: name, "Listening on http://{}", addr);
+
+        // every worker accepts on its own clone of the listener
+        for _ in 1..WORKERS {
+            match listener.try_clone() {
+                Ok(l) => {
+                    let handler = Arc::clone(&handler);
+                    thread::spawn(move || accept(name, l, handler.as_ref()));
+                }
+                Err(e) => warn!(target: name, "Failed to start worker -> {}", e),
+            }
+        }
+        accept(name, listener, handler.as_ref());
+    });
+}
+
+fn accept<F>(name: &str, listener: TcpListener, handler: &F)
+where
+    F: Fn(&HttpRequest) -> HttpResponse,
+{
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                warn!(target: name, "Failed to accept connection -> {}", e);
+                continue;
+            }
+        };
+
+        handle_connection(name, stream, handler).unwrap_or_else(|e| {
+            debug!(target: name, "Failed to handle request -> {}", e);
+        });
+    }
+}
+
+fn bind(name: &str, addr: SocketAddr) -> TcpListener {
+    let mut warned = false;
+    loop {
+        match TcpListener::bind(addr) {
+            Ok(l) => return l,
+            Err(e) => {
+                if !warned {
+                    warn!(target: name, "Failed to bind {}, retrying -> {}", addr, e);
+                    warned = true;
+                }
+                thread::sleep(BIND_RETRY_INTERVAL);
+            }
+        }
+    }
+}
+
+fn handle_connection<F>(name: &str, mut stream: TcpStream, handler: &F) -> Result<()>
+where
+    F: Fn(&HttpRequest) -> HttpResponse,
+{
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let response = match read_request(&mut stream, Instant::now() + REQUEST_TIMEOUT) {
+        Ok(request) => {
+            debug!(target: name, "{} {}", request.method, request.path);
+            handler(&request)
+        }
+        Err(ref e) if e.kind() == ErrorKind::InvalidData => HttpResponse::new(400, "text/plain", format!("{}\n", e)),
+        Err(e) => return Err(e),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.reason(),
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(response.body.as_bytes())?;
+    stream.flush()
+}
+
+/**
+    Reads a request, failing if it isn't complete by [deadline] (no matter how slowly data keeps arriving)
+*/
+fn read_request(stream: &mut TcpStream, deadline: Instant) -> Result<HttpRequest> {
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_string());
+    let read = |stream: &mut TcpStream, buffer: &mut [u8]| {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(Error::new(ErrorKind::TimedOut, "request took too long"));
+        }
+        stream.set_read_timeout(Some(remaining))?;
+        stream.read(buffer)
+    };
+
+    // read until the end of the head
+    let mut data = vec![];
+    let mut buffer = [0u8; 4096];
+    let head_end = loop {
+        if let Some(i) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i;
+        }
+        if data.len() > MAX_REQUEST_SIZE {
+            return Err(invalid("request head too large"));
+        }
+
+        let read = read(stream, &mut buffer)?;
+        if read == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed before request was complete"));
+        }
+        data.extend_from_slice(&buffer[..read]);
+    };
+
+    let head = std::str::from_utf8(&data[..head_end]).map_err(|_| invalid("request head is not valid UTF-8"))?;
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let method = request_line.next().unwrap_or("").to_string();
+    let target = request_line.next().ok_or_else(|| invalid("invalid request line"))?;
+    let path = target.split('?').next().unwrap_or("");
+
+    let mut headers = vec![];
+    for line in lines {
+        let (name, value) = line.split_once(':').ok_or_else(|| invalid("invalid header"))?;
+        headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+    }
+
+    // read the rest of the body
+    let content_length: usize = match headers.iter().find(|(n, _)| n == "content-length") {
+        Some((_, v)) => v.parse().map_err(|_| invalid("invalid content length"))?,
+        None => 0,
+    };
+    if content_length > MAX_REQUEST_SIZE {
+        return Err(invalid("request body too large"));
+    }
+
+    let mut body = data[head_end + 4..].to_vec();
+    while body.len() < content_length {
+        let read = read(stream, &mut buffer)?;
+        if read == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed before request was complete"));
+        }
+        body.extend_from_slice(&buffer[..read]);
+    }
+    body.truncate(content_length);
+
+    Ok(HttpRequest {
+        method,
+        path: path.to_string(),
+        headers,
+        body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+        Connects to a local listener, returns the accepted server side and the client side
+    */
+    fn connection() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (listener.accept().unwrap().0, client)
+    }
+
+    #[test]
+    fn reads_request_with_body() {
+        let (mut server, mut client) = connection();
+        client.write_all(b"PUT /log-level?x=1 HTTP/1.1\r\nContent-Length: 4\r\nAuthorization: Bearer t\r\n\r\nbody").unwrap();
+
+        let request = read_request(&mut server, Instant::now() + REQUEST_TIMEOUT).unwrap();
+        assert_eq!(request.method, "PUT");
+        assert_eq!(request.path, "/log-level");
+        assert_eq!(request.header("authorization"), Some("Bearer t"));
+        assert_eq!(request.body, b"body");
+    }
+
+    #[test]
+    fn slow_requests_time_out() {
+        let (mut server, mut client) = connection();
+        let writer = thread::spawn(move || {
+            for byte in b"GET /metrics HTTP/1.1\r\n" {
+                if client.write_all(&[*byte]).is_err() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+
+        let error = read_request(&mut server, Instant::now() + Duration::from_millis(100)).err().unwrap();
+        assert!(matches!(error.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock), "{:?}", error);
+        drop(server);
+        writer.join().unwrap();
+    }
+}
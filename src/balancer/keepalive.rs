@@ -0,0 +1,141 @@
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::net::{Shutdown, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use mio::net::TcpStream;
+use rustls::Connection;
+
+use super::tls::MaybeTlsStream;
+use super::Pool;
+
+/**
+    How backend connections of a pool are kept open between HTTP requests
+*/
+pub struct KeepAlive {
+    /**
+        Idle connections every worker keeps per host, further ones are closed
+    */
+    pub max_idle: usize,
+    /**
+        Idle connections are closed after this long, it should be shorter than the backends' own keep-alive timeout
+    */
+    pub idle_timeout: Duration,
+}
+
+/**
+    Backend connection whose last response is complete, waiting to carry the next request
+*/
+pub struct IdleConnection {
+    pub pool: Arc<Pool>,
+    pub target: SocketAddr,
+    pub stream: TcpStream,
+    pub tls: Option<Connection>,
+    since: Instant,
+}
+
+impl IdleConnection {
+    pub fn new(pool: Arc<Pool>, target: SocketAddr, stream: TcpStream, tls: Option<Connection>) -> Self {
+        IdleConnection {
+            pool,
+            target,
+            stream,
+            tls,
+            since: Instant::now(),
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        self.pool.keepalive.as_ref().is_none_or(|k| now.duration_since(self.since) > k.idle_timeout)
+    }
+
+    /**
+        Checks that the backend didn't close the connection or send anything while it was idle. TLS records that
+        carry no data (like session tickets) are fine
+    */
+    fn is_healthy(&mut self) -> bool {
+        let mut buf = [0u8; 1];
+        loop {
+            match MaybeTlsStream::new(&mut self.stream, self.tls.as_mut()).read(&mut buf) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return true,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                _ => return false,
+            }
+        }
+    }
+
+    fn close(mut self) {
+        if let Some(connection) = &mut self.tls {
+            connection.send_close_notify();
+            MaybeTlsStream::new(&mut self.stream, Some(connection)).flush().unwrap_or(());
+        }
+        self.stream.shutdown(Shutdown::Both).unwrap_or(());
+    }
+}
+
+/**
+    Idle keep-alive connections to backends of a single worker, reused by its HTTP clients. Connections stay registered
+    with the poll of the client that used them last until they're reused
+*/
+pub struct IdleConnections {
+    connections: Vec<IdleConnection>,
+}
+
+impl IdleConnections {
+    pub fn new() -> Self {
+        IdleConnections { connections: vec![] }
+    }
+
+    /**
+        Keeps [connection] for later requests, closing the oldest idle connection to the same host if there are too many
+    */
+    pub fn release(&mut self, connection: IdleConnection) {
+        let max_idle = connection.pool.keepalive.as_ref().map_or(0, |k| k.max_idle);
+        let same_host = |c: &IdleConnection| Arc::ptr_eq(&c.pool, &connection.pool) && c.target == connection.target;
+
+        if self.connections.iter().filter(|c| same_host(c)).count() >= max_idle {
+            match self.connections.iter().position(same_host) {
+                Some(oldest) => self.connections.remove(oldest).close(),
+                None => {
+                    connection.close();
+                    return;
+                }
+            }
+        }
+
+        self.connections.push(connection);
+    }
+
+    /**
+        Takes the most recently used healthy connection to [target] of [pool], if there is one. Stale connections found on the way are closed
+    */
+    pub fn checkout(&mut self, pool: &Arc<Pool>, target: SocketAddr) -> Option<IdleConnection> {
+        let now = Instant::now();
+        while let Some(index) = self.connections.iter().rposition(|c| Arc::ptr_eq(&c.pool, pool) && c.target == target) {
+            let mut connection = self.connections.remove(index);
+            if !connection.is_expired(now) && connection.is_healthy() {
+                return Some(connection);
+            }
+            connection.close();
+        }
+
+        None
+    }
+
+    /**
+        Closes connections that were idle for longer than their pool's idle timeout
+    */
+    pub fn expire(&mut self) {
+        if self.connections.is_empty() {
+            return;
+        }
+
+        let now = Instant::now();
+        let (expired, idle): (Vec<_>, Vec<_>) = std::mem::take(&mut self.connections).into_iter().partition(|c| c.is_expired(now));
+        self.connections = idle;
+        for connection in expired {
+            connection.close();
+        }
+    }
+}
@@ -0,0 +1,423 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// per-address counters are spread over this many separately locked maps, so workers closing connections rarely wait on each other
+const SHARDS: usize = 64;
+
+// how often idle rate limiting buckets are removed
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+// the oldest bucket is dropped for a new one once there are this many, so memory and sweeps stay bounded
+const MAX_BUCKETS: usize = 100_000;
+
+// prefix lengths clients are grouped by for the per-prefix limit
+const IPV4_PREFIX: u8 = 24;
+const IPV6_PREFIX: u8 = 64;
+
+/**
+    Why a connection was closed right after it was accepted
+*/
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RejectReason {
+    AccessDenied,
+    ConnectionLimit,
+    IpLimit,
+    PrefixLimit,
+    RateLimit,
+    GlobalRateLimit,
+    /**
+        The PROXY protocol header expected on the listener was invalid or didn't arrive in time
+    */
+    ProxyProtocol,
+}
+
+impl RejectReason {
+    pub const ALL: [RejectReason; 7] = [
+        RejectReason::AccessDenied,
+        RejectReason::ConnectionLimit,
+        RejectReason::IpLimit,
+        RejectReason::PrefixLimit,
+        RejectReason::RateLimit,
+        RejectReason::GlobalRateLimit,
+        RejectReason::ProxyProtocol,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RejectReason::AccessDenied => "access_denied",
+            RejectReason::ConnectionLimit => "connection_limit",
+            RejectReason::IpLimit => "ip_limit",
+            RejectReason::PrefixLimit => "prefix_limit",
+            RejectReason::RateLimit => "rate_limit",
+            RejectReason::GlobalRateLimit => "global_rate_limit",
+            RejectReason::ProxyProtocol => "proxy_protocol",
+        }
+    }
+}
+
+/**
+    Limits on concurrent connections, 0 means unlimited
+*/
+#[derive(Clone, Copy, Default)]
+pub struct ConnectionLimits {
+    pub total: usize,
+    pub per_ip: usize,
+    pub per_prefix: usize,
+}
+
+/**
+    Address (or prefix, with its length) connections are counted for
+*/
+type CounterKey = (IpAddr, u8);
+
+struct LimiterState {
+    limits: ConnectionLimits,
+    total: AtomicUsize,
+    shards: Vec<Mutex<HashMap<CounterKey, usize>>>,
+}
+
+/**
+    Tracks concurrent connections globally and per client address, checked when accepting connections
+*/
+pub struct ConnectionLimiter {
+    state: Arc<LimiterState>,
+}
+
+/**
+    Counts a connection towards the limits for as long as it's kept
+*/
+pub struct ConnectionPermit {
+    state: Arc<LimiterState>,
+    keys: Vec<CounterKey>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        ConnectionLimiter {
+            state: Arc::new(LimiterState {
+                limits,
+                total: AtomicUsize::new(0),
+                shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            }),
+        }
+    }
+
+    /**
+        Counts a new connection from the given address, or returns which limit it would exceed
+    */
+    pub fn try_acquire(&self, ip: IpAddr) -> std::result::Result<ConnectionPermit, RejectReason> {
+        let state = &self.state;
+        let limits = state.limits;
+
+        let total = state.total.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut permit = ConnectionPermit {
+            state: Arc::clone(state),
+            keys: vec![],
+        };
+        if limits.total > 0 && total > limits.total {
+            return Err(RejectReason::ConnectionLimit);
+        }
+
+        // already counted keys are released by dropping the permit
+        if limits.per_ip > 0 {
+            let key = (ip, if ip.is_ipv4() { 32 } else { 128 });
+            if !state.increment(key, limits.per_ip) {
+                return Err(RejectReason::IpLimit);
+            }
+            permit.keys.push(key);
+        }
+
+        if limits.per_prefix > 0 {
+            let key = prefix(ip);
+            if !state.increment(key, limits.per_prefix) {
+                return Err(RejectReason::PrefixLimit);
+            }
+            permit.keys.push(key);
+        }
+
+        Ok(permit)
+    }
+}
+
+impl LimiterState {
+    fn shard(&self, key: &CounterKey) -> &Mutex<HashMap<CounterKey, usize>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+
+    /**
+        Increments the counter of the key, unless it's already at [limit]
+    */
+    fn increment(&self, key: CounterKey, limit: usize) -> bool {
+        let mut shard = self.shard(&key).lock().unwrap();
+        let count = shard.entry(key).or_insert(0);
+        if *count >= limit {
+            return false;
+        }
+
+        *count += 1;
+        true
+    }
+
+    fn decrement(&self, key: &CounterKey) {
+        let mut shard = self.shard(key).lock().unwrap();
+        if let Some(count) = shard.get_mut(key) {
+            *count -= 1;
+
+            // entries are removed once unused, so memory only grows with concurrent clients
+            if *count == 0 {
+                shard.remove(key);
+            }
+        }
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.state.total.fetch_sub(1, Ordering::Relaxed);
+        for key in &self.keys {
+            self.state.decrement(key);
+        }
+    }
+}
+
+/**
+    Returns the network prefix the address belongs to (/24 for IPv4, /64 for IPv6)
+*/
+fn prefix(ip: IpAddr) -> CounterKey {
+    match ip {
+        IpAddr::V4(v4) => {
+            let masked = u32::from(v4) & (u32::MAX << (32 - IPV4_PREFIX));
+            (IpAddr::V4(masked.into()), IPV4_PREFIX)
+        }
+        IpAddr::V6(v6) => {
+            let masked = u128::from(v6) & (u128::MAX << (128 - IPV6_PREFIX));
+            (IpAddr::V6(masked.into()), IPV6_PREFIX)
+        }
+    }
+}
+
+/**
+    Rate of events (new connections, or bytes for bandwidth limits): [rate] per second on average, with bursts of up to [burst] at once
+*/
+#[derive(Clone, Copy)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+impl RateLimit {
+    /**
+        Returns [None] if [rate] is not positive (unlimited). If [burst] is not positive, it defaults to the rate
+    */
+    pub fn new(rate: f64, burst: f64) -> Option<Self> {
+        if rate <= 0.0 {
+            return None;
+        }
+
+        // a burst smaller than one event would block everything
+        let burst = if burst > 0.0 { burst } else { rate };
+        Some(RateLimit { rate, burst: burst.max(1.0) })
+    }
+}
+
+pub struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: &RateLimit, now: Instant) -> Self {
+        TokenBucket {
+            tokens: limit.burst,
+            updated: now,
+        }
+    }
+
+    /**
+        Refills the bucket based on the time passed since the last call and returns the tokens in it
+    */
+    pub fn available(&mut self, limit: &RateLimit, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst);
+        self.updated = now;
+        self.tokens
+    }
+
+    /**
+        Takes a token if there is one
+    */
+    fn try_take(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        if self.available(limit, now) < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+
+    /**
+        Takes tokens without checking, the bucket can go into debt which is paid off before new tokens are available
+    */
+    pub fn take(&mut self, amount: f64) {
+        self.tokens -= amount;
+    }
+
+    /**
+        Returns how long it takes until the bucket holds [amount] tokens
+    */
+    pub fn time_until(&self, limit: &RateLimit, amount: f64, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        let missing = amount.min(limit.burst) - (self.tokens + elapsed * limit.rate);
+        Duration::from_secs_f64(missing.max(0.0) / limit.rate)
+    }
+
+    /**
+        Checks if the bucket would be full by now, it can then be dropped and created again when needed
+    */
+    fn is_idle(&self, limit: &RateLimit, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * limit.rate >= limit.burst
+    }
+}
+
+/**
+    Limits the rate of new connections per client IP address and globally, using token buckets.
+    Only used from the accepting thread, so it doesn't need any locking.
+*/
+pub struct RateLimiter {
+    per_ip: Option<RateLimit>,
+    global: Option<(RateLimit, TokenBucket)>,
+    buckets: HashMap<IpAddr, TokenBucket>,
+    /**
+        Addresses in the order their buckets were created, entries of buckets that were dropped in the meantime are skipped
+    */
+    created: VecDeque<IpAddr>,
+    last_sweep: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(per_ip: Option<RateLimit>, global: Option<RateLimit>) -> Self {
+        let now = Instant::now();
+
+        RateLimiter {
+            per_ip,
+            global: global.map(|g| (g, TokenBucket::new(&g, now))),
+            buckets: HashMap::new(),
+            created: VecDeque::new(),
+            last_sweep: now,
+        }
+    }
+
+    /**
+        Counts a new connection from the given address, or returns which rate limit it would exceed
+    */
+    pub fn check(&mut self, ip: IpAddr) -> std::result::Result<(), RejectReason> {
+        let now = Instant::now();
+
+        if let Some(limit) = &self.per_ip {
+            if now.duration_since(self.last_sweep) > SWEEP_INTERVAL {
+                let buckets = &mut self.buckets;
+                buckets.retain(|_, b| !b.is_idle(limit, now));
+                self.created.retain(|ip| buckets.contains_key(ip));
+                self.last_sweep = now;
+            }
+
+            if !self.buckets.contains_key(&ip) {
+                // the oldest buckets are the most likely to be idle, their addresses start over with a full bucket
+                if self.buckets.len() >= MAX_BUCKETS {
+                    while let Some(oldest) = self.created.pop_front() {
+                        if self.buckets.remove(&oldest).is_some() {
+                            break;
+                        }
+                    }
+                }
+                self.created.push_back(ip);
+            }
+
+            let bucket = self.buckets.entry(ip).or_insert_with(|| TokenBucket::new(limit, now));
+            if !bucket.try_take(limit, now) {
+                return Err(RejectReason::RateLimit);
+            }
+        }
+
+        if let Some((limit, bucket)) = &mut self.global {
+            if !bucket.try_take(limit, now) {
+                return Err(RejectReason::GlobalRateLimit);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn evicts_oldest_buckets_beyond_max_buckets() {
+        let limit = RateLimit::new(1.0, 1.0);
+        let mut limiter = RateLimiter::new(limit, None);
+
+        for i in 0..MAX_BUCKETS as u32 {
+            assert_eq!(limiter.check(IpAddr::V4(Ipv4Addr::from(i))), Ok(()));
+        }
+        assert_eq!(limiter.buckets.len(), MAX_BUCKETS);
+
+        // every new address gets its own bucket, the oldest ones make room
+        assert_eq!(limiter.check("10.0.0.1".parse().unwrap()), Ok(()));
+        assert_eq!(limiter.check("10.0.0.2".parse().unwrap()), Ok(()));
+        assert_eq!(limiter.check("10.0.0.1".parse().unwrap()), Err(RejectReason::RateLimit));
+        assert_eq!(limiter.buckets.len(), MAX_BUCKETS);
+        assert!(!limiter.buckets.contains_key(&IpAddr::V4(Ipv4Addr::from(0))));
+        assert!(!limiter.buckets.contains_key(&IpAddr::V4(Ipv4Addr::from(1))));
+        assert_eq!(limiter.check(IpAddr::V4(Ipv4Addr::from(2))), Err(RejectReason::RateLimit));
+    }
+
+    #[test]
+    fn sweeps_idle_buckets_once_per_interval() {
+        let limit = RateLimit::new(1000.0, 1.0);
+        let mut limiter = RateLimiter::new(limit, None);
+
+        assert_eq!(limiter.check("10.0.0.1".parse().unwrap()), Ok(()));
+        assert_eq!(limiter.check("10.0.0.2".parse().unwrap()), Ok(()));
+        assert_eq!(limiter.buckets.len(), 2);
+
+        limiter.last_sweep -= SWEEP_INTERVAL * 2;
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(limiter.check("10.0.0.3".parse().unwrap()), Ok(()));
+        assert_eq!(limiter.buckets.len(), 1);
+    }
+
+    #[test]
+    fn applies_global_rate_limit() {
+        let mut limiter = RateLimiter::new(None, RateLimit::new(1.0, 2.0));
+        assert_eq!(limiter.check("10.0.0.1".parse().unwrap()), Ok(()));
+        assert_eq!(limiter.check("10.0.0.2".parse().unwrap()), Ok(()));
+        assert_eq!(limiter.check("10.0.0.3".parse().unwrap()), Err(RejectReason::GlobalRateLimit));
+    }
+
+    #[test]
+    fn counts_connections_per_ip_and_prefix() {
+        let limiter = ConnectionLimiter::new(ConnectionLimits {
+            total: 3,
+            per_ip: 1,
+            per_prefix: 2,
+        });
+        let first = limiter.try_acquire("10.0.0.1".parse().unwrap()).unwrap();
+        assert_eq!(limiter.try_acquire("10.0.0.1".parse().unwrap()).err(), Some(RejectReason::IpLimit));
+        let _second = limiter.try_acquire("10.0.0.2".parse().unwrap()).unwrap();
+        assert_eq!(limiter.try_acquire("10.0.0.3".parse().unwrap()).err(), Some(RejectReason::PrefixLimit));
+
+        drop(first);
+        assert!(limiter.try_acquire("10.0.0.1".parse().unwrap()).is_ok());
+    }
+}
@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use super::{HostState, RejectReason};
+
+// upper bounds (in seconds) of the connect latency histogram buckets
+const LATENCY_BUCKETS: [f64; 12] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram {
+            buckets: Default::default(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+            if secs <= *bound {
+                self.buckets[i].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/**
+    Metrics of a single backend host
+*/
+pub struct BackendMetrics {
+    pub active_connections: AtomicI64,
+    pub total_connections: AtomicU64,
+    /**
+        Requests sent over idle keep-alive connections instead of new ones
+    */
+    pub reused_connections: AtomicU64,
+    /**
+        Failed connection attempts, including timeouts
+    */
+    pub connect_failures: AtomicU64,
+    pub connect_timeouts: AtomicU64,
+    pub connect_latency: Histogram,
+}
+
+/**
+    State of a backend pool at the time metrics are rendered
+*/
+pub struct PoolStatus {
+    pub name: String,
+    /**
+        Current hosts with their cooldown and administrative state
+    */
+    pub hosts: Vec<(SocketAddr, bool, HostState)>,
+    /**
+        Which host tier currently gets traffic and how often that changed
+    */
+    pub active_tier: Option<u32>,
+    pub tier_shifts: u64,
+}
+
+/**
+    Shared metrics registry. All values are atomics, so they can be updated from worker threads without locking
+*/
+pub struct Metrics {
+    pub accepted_connections: AtomicU64,
+    /**
+        Connections closed right after accepting, by [RejectReason]
+    */
+    rejected_connections: [AtomicU64; RejectReason::ALL.len()],
+    /**
+        Bytes received from clients
+    */
+    pub bytes_in: AtomicU64,
+    /**
+        Bytes sent to clients
+    */
+    pub bytes_out: AtomicU64,
+    backends: RwLock<HashMap<SocketAddr, Arc<BackendMetrics>>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            accepted_connections: AtomicU64::new(0),
+            rejected_connections: Default::default(),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            backends: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn reject(&self, reason: RejectReason) {
+        self.rejected_connections[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /**
+        Returns metrics for the given backend, creating them on first use
+    */
+    pub fn backend(&self, addr: SocketAddr) -> Arc<BackendMetrics> {
+        if let Some(b) = self.backends.read().unwrap().get(&addr) {
+            return Arc::clone(b);
+        }
+
+        let mut backends = self.backends.write().unwrap();
+        let backend = backends.entry(addr).or_insert_with(|| {
+            Arc::new(BackendMetrics {
+                active_connections: AtomicI64::new(0),
+                total_connections: AtomicU64::new(0),
+                reused_connections: AtomicU64::new(0),
+                connect_failures: AtomicU64::new(0),
+                connect_timeouts: AtomicU64::new(0),
+                connect_latency: Histogram::new(),
+            })
+        });
+
+        Arc::clone(backend)
+    }
+
+    /**
+        Renders all metrics in the Prometheus text exposition format.
+        [worker_clients] holds the active connection count of every worker, [pools] the state of every backend pool
+    */
+    pub fn render(&self, worker_clients: &[usize], pools: &[PoolStatus]) -> String {
+        let mut out = String::new();
+
+        Metrics::header(&mut out, "lb_accepted_connections_total", "counter", "Total number of accepted client connections");
+        writeln!(out, "lb_accepted_connections_total {}", self.accepted_connections.load(Ordering::Relaxed)).unwrap();
+
+        Metrics::header(&mut out, "lb_rejected_connections_total", "counter", "Connections closed right after accepting, by reason");
+        for reason in RejectReason::ALL {
+            let count = self.rejected_connections[reason as usize].load(Ordering::Relaxed);
+            writeln!(out, "lb_rejected_connections_total{{reason=\"{}\"}} {}", reason.as_str(), count).unwrap();
+        }
+
+        Metrics::header(&mut out, "lb_bytes_received_total", "counter", "Total bytes received from clients");
+        writeln!(out, "lb_bytes_received_total {}", self.bytes_in.load(Ordering::Relaxed)).unwrap();
+
+        Metrics::header(&mut out, "lb_bytes_sent_total", "counter", "Total bytes sent to clients");
+        writeln!(out, "lb_bytes_sent_total {}", self.bytes_out.load(Ordering::Relaxed)).unwrap();
+
+        Metrics::header(&mut out, "lb_worker_active_connections", "gauge", "Active client connections per worker thread");
+        for (i, count) in worker_clients.iter().enumerate() {
+            writeln!(out, "lb_worker_active_connections{{worker=\"{}\"}} {}", i, count).unwrap();
+        }
+
+        Metrics::header(&mut out, "lb_backend_cooldown", "gauge", "Whether the backend is currently avoided because of errors");
+        for pool in pools {
+            for (addr, on_cooldown, _) in &pool.hosts {
+                writeln!(out, "lb_backend_cooldown{{pool=\"{}\",backend=\"{}\"}} {}", pool.name, addr, *on_cooldown as u8).unwrap();
+            }
+        }
+
+        Metrics::header(&mut out, "lb_backend_state", "gauge", "Administrative state of the backend (active, draining or disabled)");
+        for pool in pools {
+            for (addr, _, state) in &pool.hosts {
+                for s in [HostState::Active, HostState::Draining, HostState::Disabled] {
+                    let value = (*state == s) as u8;
+                    writeln!(out, "lb_backend_state{{pool=\"{}\",backend=\"{}\",state=\"{}\"}} {}", pool.name, addr, s.as_str(), value).unwrap();
+                }
+            }
+        }
+
+        Metrics::header(&mut out, "lb_active_tier", "gauge", "Host tier currently receiving new connections (0 is primary)");
+        for pool in pools {
+            if let Some(tier) = pool.active_tier {
+                writeln!(out, "lb_active_tier{{pool=\"{}\"}} {}", pool.name, tier).unwrap();
+            }
+        }
+
+        Metrics::header(&mut out, "lb_tier_shifts_total", "counter", "Number of times traffic moved between host tiers");
+        for pool in pools {
+            writeln!(out, "lb_tier_shifts_total{{pool=\"{}\"}} {}", pool.name, pool.tier_shifts).unwrap();
+        }
+
+        let mut backends: Vec<_> = self.backends.read().unwrap().iter().map(|(a, b)| (*a, Arc::clone(b))).collect();
+        backends.sort_by_key(|(a, _)| *a);
+
+        Metrics::header(&mut out, "lb_backend_active_connections", "gauge", "Active connections per backend");
+        for (addr, b) in &backends {
+            let active = b.active_connections.load(Ordering::Relaxed);
+            writeln!(out, "lb_backend_active_connections{{backend=\"{}\"}} {}", addr, active).unwrap();
+        }
+
+        Metrics::header(&mut out, "lb_backend_connections_total", "counter", "Total established connections per backend");
+        for (addr, b) in &backends {
+            let total = b.total_connections.load(Ordering::Relaxed);
+            writeln!(out, "lb_backend_connections_total{{backend=\"{}\"}} {}", addr, total).unwrap();
+        }
+
+        Metrics::header(&mut out, "lb_backend_reused_connections_total", "counter", "Idle keep-alive connections reused per backend");
+        for (addr, b) in &backends {
+            let reused = b.reused_connections.load(Ordering::Relaxed);
+            writeln!(out, "lb_backend_reused_connections_total{{backend=\"{}\"}} {}", addr, reused).unwrap();
+        }
+
+        Metrics::header(&mut out, "lb_backend_connect_failures_total", "counter", "Failed connection attempts per backend, including timeouts");
+        for (addr, b) in &backends {
+            let failures = b.connect_failures.load(Ordering::Relaxed);
+            writeln!(out, "lb_backend_connect_failures_total{{backend=\"{}\"}} {}", addr, failures).unwrap();
+        }
+
+        Metrics::header(&mut out, "lb_backend_connect_timeouts_total", "counter", "Timed out connection attempts per backend");
+        for (addr, b) in &backends {
+            let timeouts = b.connect_timeouts.load(Ordering::Relaxed);
+            writeln!(out, "lb_backend_connect_timeouts_total{{backend=\"{}\"}} {}", addr, timeouts).unwrap();
+        }
+
+        Metrics::header(&mut out, "lb_backend_connect_duration_seconds", "histogram", "Time to establish connections per backend");
+        for (addr, b) in &backends {
+            let h = &b.connect_latency;
+            for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+                let count = h.buckets[i].load(Ordering::Relaxed);
+                writeln!(out, "lb_backend_connect_duration_seconds_bucket{{backend=\"{}\",le=\"{}\"}} {}", addr, bound, count).unwrap();
+            }
+
+            let count = h.count.load(Ordering::Relaxed);
+            let sum = h.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+            writeln!(out, "lb_backend_connect_duration_seconds_bucket{{backend=\"{}\",le=\"+Inf\"}} {}", addr, count).unwrap();
+            writeln!(out, "lb_backend_connect_duration_seconds_sum{{backend=\"{}\"}} {}", addr, sum).unwrap();
+            writeln!(out, "lb_backend_connect_duration_seconds_count{{backend=\"{}\"}} {}", addr, count).unwrap();
+        }
+
+        out
+    }
+
+    fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+    }
+}
@@ -1,13 +1,50 @@
 mod client;
+#[allow(clippy::module_inception)]
 mod balancer;
 mod host_manager;
 mod balancing_algorithm;
 mod algorithms;
 mod poller;
+mod discovery;
+mod access_log;
+mod http_server;
+mod metrics;
+mod admin;
+mod limits;
+mod access_control;
+mod bandwidth;
+mod proxy_protocol;
+mod pool;
+mod sni;
+mod http;
+mod keepalive;
+mod tls;
+#[cfg(unix)]
+mod upgrade;
+#[cfg(unix)]
+mod systemd;
 
-pub use client::TcpClient;
+pub use client::{CloseReason, TcpClient};
 pub use balancer::LoadBalancer;
-pub use host_manager::HostManager;
+pub use host_manager::{ConnectionSlot, HostManager, HostState};
 pub use balancing_algorithm::BalancingAlgorithm;
 pub use algorithms::RoundRobin;
-pub use poller::Poller;
\ No newline at end of file
+pub use poller::{ListenerOptions, Poller};
+pub use discovery::{FileDiscovery, HttpDiscovery, ServiceDiscovery};
+pub use access_log::{format_timestamp, AccessLog, AccessLogFormat};
+pub use http_server::{serve as serve_http, HttpRequest, HttpResponse};
+pub use metrics::{BackendMetrics, Metrics, PoolStatus};
+pub use admin::{Admin, SessionInfo, SessionRequests};
+pub use access_control::{AccessAction, AccessControl, AccessList};
+pub use limits::{ConnectionLimiter, ConnectionLimits, ConnectionPermit, RateLimit, RateLimiter, RejectReason, TokenBucket};
+pub use bandwidth::{BandwidthLimits, SharedBucket, Throttle};
+pub use proxy_protocol::{ProxyProtocol, ProxyProtocolVersion};
+pub use pool::{Pool, DEFAULT_POOL};
+pub use sni::SniRouter;
+pub use http::{ForwardedHeaders, HttpRouter};
+pub use keepalive::{IdleConnection, IdleConnections, KeepAlive};
+pub use tls::{CertificateStore, TlsAcceptor, TlsConnector, TlsVersion};
+#[cfg(unix)]
+pub use systemd::take_listen_env;
+#[cfg(unix)]
+pub use upgrade::take_handoff_env;
\ No newline at end of file
@@ -1,26 +1,117 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::prelude::*;
 use std::io::{ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
-use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use mio::net::{TcpListener};
+use log::{debug, error, info, warn};
+use mio::net::{TcpListener, TcpStream};
 use mio::{Events, Interest, Poll, Token};
 
+use super::proxy_protocol::{self, ParsedHeader};
 use super::LoadBalancer;
+use super::{
+    AccessControl, AccessList, ConnectionLimiter, ConnectionLimits, ForwardedHeaders, HttpRouter, RateLimit, RateLimiter, RejectReason,
+    SniRouter, TlsAcceptor,
+};
+
+/**
+    Options of a single listener
+*/
+#[derive(Clone, Default)]
+pub struct ListenerOptions {
+    /**
+        Connections start with a PROXY protocol header (sent by another proxy in front of the balancer), its client address is used
+    */
+    pub accept_proxy_protocol: bool,
+    /**
+        Routes TLS clients to backend pools by the server name they ask for, instead of always using the default pool
+    */
+    pub sni_router: Option<Arc<SniRouter>>,
+    /**
+        Terminates TLS, backends get the decrypted data
+    */
+    pub tls: Option<Arc<TlsAcceptor>>,
+    /**
+        Handles clients as HTTP/1.x, routing every request to a backend pool by its host and path
+    */
+    pub http_router: Option<Arc<HttpRouter>>,
+    /**
+        Forwarding headers added to the requests of HTTP clients
+    */
+    pub forwarded_headers: Option<Arc<ForwardedHeaders>>,
+    /**
+        Picks a backend for every request of HTTP clients, instead of once per connection
+    */
+    pub balance_requests: bool,
+}
+
+/**
+    Accepted connection that didn't send its PROXY protocol header yet
+*/
+struct AwaitingHeader {
+    stream: TcpStream,
+    address: SocketAddr,
+    listener: usize,
+}
 
 pub struct Poller {
     balancer: LoadBalancer,
-    should_cancel: Arc<RwLock<bool>>,
+    listeners: Vec<TcpListener>,
+    should_cancel: Arc<AtomicBool>,
+    /**
+        Set on SIGUSR2, hands the listeners over to a freshly started instance of the balancer
+    */
+    should_upgrade: Arc<AtomicBool>,
+    drain_timeout: Duration,
+    /**
+        Checked for every accepted connection, connections over the limits are closed right away
+    */
+    limiter: ConnectionLimiter,
+    rate_limiter: RateLimiter,
+    /**
+        Checked first for every accepted connection, can be replaced while running (config reload)
+    */
+    access_control: Arc<RwLock<AccessControl>>,
+    /**
+        Options of listeners by port, listeners without their own use the default ones
+    */
+    listener_options: ListenerOptions,
+    port_options: HashMap<u16, ListenerOptions>,
+    /**
+        How long connections can take to send their PROXY protocol header
+    */
+    proxy_protocol_timeout: Duration,
+    /**
+        How many connections can wait for their PROXY protocol header at once, newer ones are closed right away
+    */
+    max_awaiting_header: usize,
+    #[cfg(unix)]
+    upgrade_handoff: Option<super::upgrade::UpgradeHandoff>,
 }
 
 impl Poller {
-    pub fn new(mut balancer: LoadBalancer) -> Self {
-        let should_cancel = Arc::new(RwLock::new(false));
+    pub fn new(mut balancer: LoadBalancer, drain_timeout: Duration) -> Self {
+        let should_cancel = Arc::new(AtomicBool::new(false));
         balancer.start();
 
         let mut p = Poller {
             balancer,
+            listeners: vec![],
             should_cancel,
+            should_upgrade: Arc::new(AtomicBool::new(false)),
+            drain_timeout,
+            limiter: ConnectionLimiter::new(ConnectionLimits::default()),
+            rate_limiter: RateLimiter::new(None, None),
+            access_control: Arc::new(RwLock::new(AccessControl::new(AccessList::new()))),
+            listener_options: ListenerOptions::default(),
+            port_options: HashMap::new(),
+            proxy_protocol_timeout: Duration::from_secs(5),
+            max_awaiting_header: 1024,
+            #[cfg(unix)]
+            upgrade_handoff: None,
         };
 
         p.initialize().unwrap();
@@ -32,30 +123,183 @@ impl Poller {
         // prepare the ctrl+c handler for graceful stop
         let cancel = Arc::clone(&self.should_cancel);
         ctrlc::set_handler(move || {
-            *cancel.write().unwrap() = true;
+            cancel.store(true, Ordering::Relaxed);
         })
         .expect("Failed to set Ctrl+C handler!");
 
+        // SIGTERM is handled the same way
+        #[cfg(unix)]
+        signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&self.should_cancel))?;
+
+        // prepare the SIGUSR2 handler for binary upgrades
+        #[cfg(unix)]
+        signal_hook::flag::register(signal_hook::consts::SIGUSR2, Arc::clone(&self.should_upgrade))?;
+
         Ok(())
     }
 
-    pub fn start_listening(&mut self, listening_port: i32) -> Result<()> {
+    /**
+        Sets limits on concurrent client connections, must be called before [start_listening]
+    */
+    pub fn set_connection_limits(&mut self, limits: ConnectionLimits) {
+        self.limiter = ConnectionLimiter::new(limits);
+    }
+
+    /**
+        Sets limits on the rate of new connections per client IP address and globally, must be called before [start_listening]
+    */
+    pub fn set_rate_limits(&mut self, per_ip: Option<RateLimit>, global: Option<RateLimit>) {
+        self.rate_limiter = RateLimiter::new(per_ip, global);
+    }
+
+    /**
+        Sets allowed and denied client networks, the lock can be used to replace them at any time
+    */
+    pub fn set_access_control(&mut self, access_control: Arc<RwLock<AccessControl>>) {
+        self.access_control = access_control;
+    }
+
+    /**
+        Sets options of all listeners, [ports] holds options of listeners on specific ports replacing the default ones.
+        Must be called before [start_listening]
+    */
+    pub fn set_listener_options(&mut self, default: ListenerOptions, ports: HashMap<u16, ListenerOptions>) {
+        self.listener_options = default;
+        self.port_options = ports;
+    }
+
+    /**
+        Sets how long connections can take to send their PROXY protocol header before they're closed
+    */
+    pub fn set_proxy_protocol_timeout(&mut self, timeout: Duration) {
+        self.proxy_protocol_timeout = timeout;
+    }
+
+    /**
+        Sets how many connections can wait for their PROXY protocol header at once
+    */
+    pub fn set_max_awaiting_header(&mut self, max: usize) {
+        self.max_awaiting_header = max;
+    }
+
+    /**
+        Binds a new listener on the given port
+    */
+    pub fn bind(&mut self, listening_port: i32) -> Result<()> {
         let addr = format!("0.0.0.0:{}", listening_port).parse().unwrap();
-        let mut listener = TcpListener::bind(addr)?;
+        let listener = TcpListener::bind(addr)?;
+        self.listeners.push(listener);
+
+        Ok(())
+    }
+
+    /**
+        Uses an already opened listener instead of binding a new one
+    */
+    pub fn adopt(&mut self, listener: std::net::TcpListener) -> Result<()> {
+        listener.set_nonblocking(true)?;
+        self.listeners.push(TcpListener::from_std(listener));
+
+        Ok(())
+    }
+
+    /**
+        If this instance was started by a binary upgrade, adopts the listeners handed over by the previous instance. Returns [false] otherwise
+    */
+    #[cfg(unix)]
+    pub fn adopt_upgraded(&mut self) -> Result<bool> {
+        let (listeners, handoff) = match super::upgrade::receive_listeners()? {
+            Some(l) => l,
+            None => return Ok(false),
+        };
 
+        for listener in listeners {
+            self.adopt(listener)?;
+        }
+        self.upgrade_handoff = Some(handoff);
+
+        Ok(true)
+    }
+
+    #[cfg(not(unix))]
+    pub fn adopt_upgraded(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+
+    /**
+        If this instance was started by systemd socket activation, adopts the passed listening sockets. Returns [false] otherwise
+    */
+    #[cfg(unix)]
+    pub fn adopt_systemd(&mut self) -> Result<bool> {
+        let listeners = super::systemd::listen_fds()?;
+        if listeners.is_empty() {
+            return Ok(false);
+        }
+
+        info!(target: "Systemd", "Using {} listening sockets passed by systemd", listeners.len());
+        for listener in listeners {
+            self.adopt(listener)?;
+        }
+
+        Ok(true)
+    }
+
+    #[cfg(not(unix))]
+    pub fn adopt_systemd(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+
+    pub fn start_listening(&mut self) -> Result<()> {
         let mut poll = Poll::new().unwrap();
         let mut events = Events::with_capacity(512);
-        poll.registry().register(&mut listener, Token(0), Interest::READABLE)?;
-        
+        for (i, listener) in self.listeners.iter_mut().enumerate() {
+            poll.registry().register(listener, Token(i), Interest::READABLE)?;
+        }
+
+        // local port of every listener, used to pick its access list and options
+        let listener_ports: Vec<u16> = self.listeners.iter().map(|l| l.local_addr().map_or(0, |a| a.port())).collect();
+        let listener_options: Vec<ListenerOptions> = listener_ports
+            .iter()
+            .map(|p| self.port_options.get(p).unwrap_or(&self.listener_options).clone())
+            .collect();
+
+        // connections waiting for their PROXY protocol header, their tokens follow the listener tokens
+        let mut awaiting_header: HashMap<Token, AwaitingHeader> = HashMap::new();
+        // their deadlines in the order they were accepted, entries of connections no longer waiting are skipped
+        let mut header_deadlines: VecDeque<(Instant, Token)> = VecDeque::new();
+        let mut next_token = self.listeners.len();
+        let mut header_buffer = vec![0u8; proxy_protocol::MAX_HEADER_LENGTH];
+
         // START LISTENING
-        println!("[Listener] Started listening on port {}", listening_port);
+        let mut ports = vec![];
+        for listener in &self.listeners {
+            match listener.local_addr() {
+                Ok(a) => {
+                    info!(target: "Listener", "Started listening on port {}", a.port());
+                    ports.push(a.port().to_string());
+                }
+                Err(e) => info!(target: "Listener", "Started listening on unknown address ({})", e),
+            }
+        }
+
+        // if we were started by a binary upgrade, the previous instance can now stop accepting
+        #[cfg(unix)]
+        if let Some(handoff) = self.upgrade_handoff.take() {
+            handoff.confirm_ready().unwrap_or_else(|e| {
+                error!(target: "Upgrade", "Failed to notify previous instance -> {}", e);
+            });
+        }
+        Poller::notify(&format!("READY=1\nSTATUS=Listening on port {}", ports.join(", ")));
+
+        // set once a new instance took over, it's the one systemd tracks from then on
+        let mut upgraded = false;
         loop {
-            if *self.should_cancel.read().unwrap() {
-                self.balancer.stop();
-                println!("[Listener] Listening stopped");
+            if self.should_cancel.load(Ordering::Relaxed) {
+                break;
+            }
 
-                // sleep a bit to allow all threads to exit gracefully
-                thread::sleep(Duration::from_millis(10));
+            if self.should_upgrade.swap(false, Ordering::Relaxed) && self.upgrade() {
+                upgraded = true;
                 break;
             }
 
@@ -64,39 +308,228 @@ impl Poller {
                 Ok(_) => {}
                 Err(ref e) if e.kind() == ErrorKind::Interrupted => {
                     // this handler does not get called on Windows, so we use timeout and check it outside
-                    *self.should_cancel.write().unwrap() = true;  
+                    // on Unix, other signals (such as SIGUSR2 for upgrades) interrupt polling too, those are checked above
+                    if cfg!(not(unix)) {
+                        self.should_cancel.store(true, Ordering::Relaxed);
+                    }
+                    continue;
                 }
                 Err(e) => {
-                    println!("Failed to poll for events! {}", e.to_string());
-                    break;
+                    error!(target: "Listener", "Failed to poll for events! {}", e);
+                    self.balancer.stop();
+                    return Err(e);
                 }
             };
 
+            // connections that didn't send their header in time are closed
+            let now = Instant::now();
+            while let Some(&(deadline, token)) = header_deadlines.front() {
+                if deadline > now {
+                    break;
+                }
+
+                header_deadlines.pop_front();
+                if let Some(mut awaiting) = awaiting_header.remove(&token) {
+                    poll.registry().deregister(&mut awaiting.stream).unwrap_or(());
+                    debug!(target: "Listener", "Rejected connection from {} (no PROXY protocol header in time)", awaiting.address);
+                    self.balancer.reject_client(RejectReason::ProxyProtocol);
+                }
+            }
+
             if events.is_empty() {
                 continue;
             }
 
             for event in events.iter() {
-                match event.token() {
-                    _ => {
-                        // accept a new client   
-                        let connection = match listener.accept() {
-                            Ok(c) => c,  
-                            Err(ref e) if e.kind() == ErrorKind::WouldBlock => { continue; },
-                            Err(e) => {
-                                println!("Failed to accept socket! {}", e.to_string());
-                                continue;
-                            }
-                        };
-                        
-                        // we need to reregister to set the Interest again, othewise we won't get any more readiness events (only on Windows)
-                        poll.registry().reregister(&mut listener, Token(0), Interest::READABLE).unwrap();
-                        self.balancer.add_client(connection.0);                    
+                // data from a connection that didn't send its complete PROXY protocol header yet
+                if event.token().0 >= self.listeners.len() {
+                    let awaiting = match awaiting_header.get_mut(&event.token()) {
+                        Some(a) => a,
+                        None => continue,
+                    };
+
+                    let addresses = match Poller::read_proxy_header(awaiting, &mut header_buffer) {
+                        ParsedHeader::Incomplete => continue,
+                        ParsedHeader::Complete { addresses, .. } => addresses,
+                        ParsedHeader::Invalid(reason) => {
+                            let mut awaiting = awaiting_header.remove(&event.token()).unwrap();
+                            poll.registry().deregister(&mut awaiting.stream).unwrap_or(());
+                            debug!(target: "Listener", "Rejected connection from {} (invalid PROXY protocol header: {})", awaiting.address, reason);
+                            self.balancer.reject_client(RejectReason::ProxyProtocol);
+                            continue;
+                        }
+                    };
+
+                    // the stream is registered with a worker from here on, counted for the client address
+                    let AwaitingHeader {
+                        mut stream, address, listener, ..
+                    } = awaiting_header.remove(&event.token()).unwrap();
+                    poll.registry().deregister(&mut stream).unwrap_or(());
+                    let addr = addresses.map_or(address, |(source, _)| source);
+                    self.admit(stream, addr, addresses, listener_ports[listener], &listener_options[listener]);
+                    continue;
+                }
+
+                let listener = &mut self.listeners[event.token().0];
+
+                // accept a new client
+                let connection = match listener.accept() {
+                    Ok(c) => c,
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!(target: "Listener", "Failed to accept socket! {}", e);
+                        continue;
                     }
+                };
+
+                // we need to reregister to set the Interest again, othewise we won't get any more readiness events (only on Windows)
+                poll.registry().reregister(listener, event.token(), Interest::READABLE).unwrap();
+
+                let (mut stream, addr) = connection;
+
+                // the client is only known once the header arrives, it's checked then
+                if listener_options[event.token().0].accept_proxy_protocol {
+                    // all of them come from the proxy's address, so they're limited in total rather than per address
+                    if awaiting_header.len() >= self.max_awaiting_header {
+                        debug!(target: "Listener", "Rejected connection from {} (too many connections waiting for a PROXY protocol header)", addr);
+                        self.balancer.reject_client(RejectReason::ProxyProtocol);
+                        continue;
+                    }
+
+                    let token = Token(next_token);
+                    next_token += 1;
+
+                    if let Err(e) = poll.registry().register(&mut stream, token, Interest::READABLE) {
+                        warn!(target: "Listener", "Failed to wait for PROXY protocol header from {} -> {}", addr, e);
+                        continue;
+                    }
+                    awaiting_header.insert(
+                        token,
+                        AwaitingHeader {
+                            stream,
+                            address: addr,
+                            listener: event.token().0,
+                        },
+                    );
+                    header_deadlines.push_back((Instant::now() + self.proxy_protocol_timeout, token));
+                    continue;
                 }
+
+                self.admit(stream, addr, None, listener_ports[event.token().0], &listener_options[event.token().0]);
             }
         }
 
+        // connections still waiting for their header are dropped
+        for (_, mut awaiting) in awaiting_header.drain() {
+            poll.registry().deregister(&mut awaiting.stream).unwrap_or(());
+        }
+
+        // stop accepting new clients, then let existing ones finish
+        for listener in &mut self.listeners {
+            poll.registry().deregister(listener)?;
+        }
+        self.listeners.clear();
+
+        let client_count = self.balancer.client_count();
+        info!(
+            target: "Listener",
+            "Listening stopped, draining {} connections (up to {}s)",
+            client_count,
+            self.drain_timeout.as_secs()
+        );
+        // after an upgrade the service keeps running in the new instance, it's only stopping on shutdown
+        match upgraded {
+            true => Poller::notify(&format!("STATUS=Draining {} connections", client_count)),
+            false => Poller::notify(&format!("STOPPING=1\nSTATUS=Draining {} connections", client_count)),
+        }
+
+        self.balancer.drain(self.drain_timeout);
+        info!(target: "Listener", "All connections closed");
+
         Ok(())
     }
+
+    /**
+        Hands the client over to the balancer, unless it's denied or over the rate or connection limits (it's then disconnected
+        right away by dropping the stream). [addresses] are the client and listener addresses from a PROXY protocol header
+    */
+    fn admit(&mut self, stream: TcpStream, addr: SocketAddr, addresses: Option<(SocketAddr, SocketAddr)>, port: u16, options: &ListenerOptions) {
+        let allowed = self.access_control.read().unwrap().is_allowed(port, addr.ip());
+        let permit = match allowed {
+            true => self.rate_limiter.check(addr.ip()).and_then(|_| self.limiter.try_acquire(addr.ip())),
+            false => Err(RejectReason::AccessDenied),
+        };
+
+        match permit {
+            Ok(permit) => self.balancer.add_client(stream, addresses, options, permit),
+            Err(reason) => {
+                debug!(target: "Listener", "Rejected connection from {} ({})", addr, reason.as_str());
+                self.balancer.reject_client(reason);
+            }
+        }
+    }
+
+    /**
+        Reads the PROXY protocol header once it's complete, leaving any data after it for the backend
+    */
+    fn read_proxy_header(awaiting: &mut AwaitingHeader, buffer: &mut [u8]) -> ParsedHeader {
+        // peeking leaves the data in the socket, so nothing after the header has to be kept around
+        let peeked = match awaiting.stream.peek(buffer) {
+            Ok(0) => return ParsedHeader::Invalid("connection closed"),
+            Ok(p) => p,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return ParsedHeader::Incomplete,
+            Err(_) => return ParsedHeader::Invalid("connection failed"),
+        };
+
+        let parsed = proxy_protocol::parse_header(&buffer[..peeked]);
+        if let ParsedHeader::Complete { length, .. } = parsed {
+            if awaiting.stream.read_exact(&mut buffer[..length]).is_err() {
+                return ParsedHeader::Invalid("connection failed");
+            }
+        }
+
+        parsed
+    }
+
+    /**
+        Hands listeners over to a new instance of the balancer. Returns [true] if the new instance took over and this one should drain and exit
+    */
+    #[cfg(unix)]
+    fn upgrade(&mut self) -> bool {
+        use std::os::unix::io::AsRawFd;
+
+        info!(target: "Upgrade", "Starting new instance");
+        let fds: Vec<_> = self.listeners.iter().map(|l| l.as_raw_fd()).collect();
+        match super::upgrade::spawn_upgraded(&fds) {
+            Ok(child) => {
+                info!(target: "Upgrade", "New instance (pid {}) took over listening", child.id());
+
+                // let systemd track the new instance as the main process (requires NotifyAccess=all)
+                Poller::notify(&format!("MAINPID={}", child.id()));
+                true
+            }
+            Err(e) => {
+                error!(target: "Upgrade", "Failed to start new instance, continuing -> {}", e);
+                false
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn upgrade(&mut self) -> bool {
+        false
+    }
+
+    /**
+        Notifies systemd about a state change, if running under it
+    */
+    fn notify(state: &str) {
+        #[cfg(unix)]
+        super::systemd::notify(state);
+
+        #[cfg(not(unix))]
+        let _ = state;
+    }
 }
@@ -0,0 +1,66 @@
+use std::sync::{Arc, RwLock};
+
+use super::{BandwidthLimits, KeepAlive, ProxyProtocol, RoundRobin, SharedBucket, TlsConnector};
+
+/**
+    Name of the pool made of the hosts in the `hosts` file, clients use it unless they're routed elsewhere
+*/
+pub const DEFAULT_POOL: &str = "default";
+
+/**
+    Group of backend hosts clients can be routed to, with its own balancing state and options
+*/
+pub struct Pool {
+    pub name: String,
+    pub balancing_algorithm: Arc<RwLock<RoundRobin>>,
+    /**
+        Header sent to every backend before the client's data, if enabled
+    */
+    pub proxy_protocol: Option<Arc<ProxyProtocol>>,
+    /**
+        Buckets shared by all clients of the pool for the pool bandwidth limits (upload and download)
+    */
+    pub bandwidth: (Option<SharedBucket>, Option<SharedBucket>),
+    /**
+        Starts TLS sessions with backends of this pool, if they only accept TLS
+    */
+    pub tls: Option<Arc<TlsConnector>>,
+    /**
+        Keeps backend connections of HTTP clients open for later requests, if enabled
+    */
+    pub keepalive: Option<KeepAlive>,
+}
+
+impl Pool {
+    pub fn new(name: &str, balancing_algorithm: RoundRobin, bandwidth: &BandwidthLimits) -> Self {
+        Pool {
+            name: name.to_string(),
+            balancing_algorithm: Arc::new(RwLock::new(balancing_algorithm)),
+            proxy_protocol: None,
+            bandwidth: (SharedBucket::new(bandwidth.pool_upload), SharedBucket::new(bandwidth.pool_download)),
+            tls: None,
+            keepalive: None,
+        }
+    }
+
+    /**
+        Enables sending a PROXY protocol header to backends of this pool, so they know the original client address
+    */
+    pub fn set_proxy_protocol(&mut self, proxy_protocol: ProxyProtocol) {
+        self.proxy_protocol = Some(Arc::new(proxy_protocol));
+    }
+
+    /**
+        Talks TLS with backends of this pool, independent of whether clients do
+    */
+    pub fn set_tls(&mut self, connector: TlsConnector) {
+        self.tls = Some(Arc::new(connector));
+    }
+
+    /**
+        Lets HTTP clients reuse connections to backends of this pool once their response is complete
+    */
+    pub fn set_keepalive(&mut self, keepalive: KeepAlive) {
+        self.keepalive = Some(keepalive);
+    }
+}
@@ -0,0 +1,431 @@
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+// every version 2 header starts with this
+const V2_SIGNATURE: [u8; 12] = [0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A];
+
+// protocol version 2 with the PROXY command (the connection is relayed for a client)
+const V2_PROXY: u8 = 0x21;
+
+// protocol version 2 with the LOCAL command (the connection was made by the proxy itself, e.g. a health check)
+const V2_LOCAL: u8 = 0x20;
+
+// address family and transport: TCP over IPv4 or IPv6
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+// longest possible version 1 header, including the line break
+const V1_MAX_LENGTH: usize = 107;
+
+/**
+    Longest possible header of any version, a buffer of this size can always hold a complete header
+*/
+pub const MAX_HEADER_LENGTH: usize = 16 + u16::MAX as usize;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ProxyProtocolVersion {
+    /**
+        Human readable header, has no room for TLVs
+    */
+    V1,
+    /**
+        Binary header
+    */
+    V2,
+}
+
+impl ProxyProtocolVersion {
+    pub fn parse(version: &str) -> Option<Self> {
+        match version {
+            "v1" | "1" => Some(ProxyProtocolVersion::V1),
+            "v2" | "2" => Some(ProxyProtocolVersion::V2),
+            _ => None,
+        }
+    }
+}
+
+/**
+    PROXY protocol header sent to backends before any client data, so they know the original client address.
+    See https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+*/
+#[derive(Clone)]
+pub struct ProxyProtocol {
+    version: ProxyProtocolVersion,
+    /**
+        Type and value of additional TLVs (version 2 only)
+    */
+    tlvs: Vec<(u8, Vec<u8>)>,
+}
+
+impl ProxyProtocol {
+    pub fn new(version: ProxyProtocolVersion) -> Self {
+        ProxyProtocol { version, tlvs: vec![] }
+    }
+
+    /**
+        Adds a TLV to every header. Only version 2 headers can carry TLVs, returns [false] for version 1 or if the header would get too long
+    */
+    pub fn add_tlv(&mut self, kind: u8, value: Vec<u8>) -> bool {
+        // the length of addresses (at most 36 bytes) and all TLVs has to fit into 16 bits
+        let length: usize = 36 + self.tlvs.iter().map(|(_, v)| 3 + v.len()).sum::<usize>() + 3 + value.len();
+        if self.version != ProxyProtocolVersion::V2 || length > u16::MAX as usize {
+            return false;
+        }
+
+        self.tlvs.push((kind, value));
+        true
+    }
+
+    /**
+        Returns the header for a connection from [source] that was accepted on [destination] (the listener address)
+    */
+    pub fn header(&self, source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+        let (source_ip, destination_ip) = same_family(source.ip(), destination.ip());
+
+        match self.version {
+            ProxyProtocolVersion::V1 => {
+                let family = if source_ip.is_ipv4() { "TCP4" } else { "TCP6" };
+                format!("PROXY {} {} {} {} {}\r\n", family, source_ip, destination_ip, source.port(), destination.port()).into_bytes()
+            }
+            ProxyProtocolVersion::V2 => {
+                let mut addresses = vec![];
+                let family = match (source_ip, destination_ip) {
+                    (IpAddr::V4(s), IpAddr::V4(d)) => {
+                        addresses.extend_from_slice(&s.octets());
+                        addresses.extend_from_slice(&d.octets());
+                        V2_TCP4
+                    }
+                    (s, d) => {
+                        addresses.extend_from_slice(&to_ipv6(s).octets());
+                        addresses.extend_from_slice(&to_ipv6(d).octets());
+                        V2_TCP6
+                    }
+                };
+                addresses.extend_from_slice(&source.port().to_be_bytes());
+                addresses.extend_from_slice(&destination.port().to_be_bytes());
+
+                for (kind, value) in &self.tlvs {
+                    addresses.push(*kind);
+                    addresses.extend_from_slice(&(value.len() as u16).to_be_bytes());
+                    addresses.extend_from_slice(value);
+                }
+
+                let mut header = Vec::with_capacity(16 + addresses.len());
+                header.extend_from_slice(&V2_SIGNATURE);
+                header.push(V2_PROXY);
+                header.push(family);
+                header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+                header.extend_from_slice(&addresses);
+                header
+            }
+        }
+    }
+}
+
+/**
+    Both addresses of a header have to be of the same family. IPv4 clients of dual-stack listeners are sent as plain IPv4 if possible
+*/
+fn same_family(source: IpAddr, destination: IpAddr) -> (IpAddr, IpAddr) {
+    let unmap = |ip: IpAddr| match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        v4 => v4,
+    };
+
+    match (unmap(source), unmap(destination)) {
+        (s @ IpAddr::V4(_), d @ IpAddr::V4(_)) => (s, d),
+        (s, d) => (IpAddr::V6(to_ipv6(s)), IpAddr::V6(to_ipv6(d))),
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+/**
+    Result of parsing the start of a connection as a PROXY protocol header
+*/
+#[derive(PartialEq, Debug)]
+pub enum ParsedHeader {
+    /**
+        More data is needed
+    */
+    Incomplete,
+    Invalid(&'static str),
+    /**
+        The header is [length] bytes long and carries the original client and destination addresses.
+        Addresses are [None] if the header doesn't describe a proxied TCP connection (like health checks of the proxy), the
+        connection's own addresses should be used then
+    */
+    Complete {
+        length: usize,
+        addresses: Option<(SocketAddr, SocketAddr)>,
+    },
+}
+
+/**
+    Parses a version 1 or 2 header at the start of [data]
+*/
+pub fn parse_header(data: &[u8]) -> ParsedHeader {
+    if data.is_empty() {
+        return ParsedHeader::Incomplete;
+    }
+
+    if data[0] == b'P' {
+        parse_v1(data)
+    } else if data[0] == V2_SIGNATURE[0] {
+        parse_v2(data)
+    } else {
+        ParsedHeader::Invalid("not a PROXY protocol header")
+    }
+}
+
+fn parse_v1(data: &[u8]) -> ParsedHeader {
+    let end = match data.windows(2).position(|w| w == b"\r\n") {
+        Some(e) => e,
+        None if data.len() >= V1_MAX_LENGTH => return ParsedHeader::Invalid("header too long"),
+        // everything received so far must still be the start of a header
+        None if !b"PROXY ".starts_with(&data[..data.len().min(6)]) => return ParsedHeader::Invalid("not a PROXY protocol header"),
+        None => return ParsedHeader::Incomplete,
+    };
+    if end + 2 > V1_MAX_LENGTH {
+        return ParsedHeader::Invalid("header too long");
+    }
+
+    let line = match std::str::from_utf8(&data[..end]) {
+        Ok(l) => l,
+        Err(_) => return ParsedHeader::Invalid("header is not valid text"),
+    };
+    let parts: Vec<&str> = line.split(' ').collect();
+    let length = end + 2;
+
+    let addresses = match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] => {
+            let parse = |ip: &str, port: &str| Some(SocketAddr::new(ip.parse().ok()?, port.parse().ok()?));
+            let (source, destination) = match (parse(source, source_port), parse(destination, destination_port)) {
+                (Some(s), Some(d)) => (s, d),
+                _ => return ParsedHeader::Invalid("invalid address"),
+            };
+
+            if source.is_ipv4() != (*family == "TCP4") || destination.is_ipv4() != (*family == "TCP4") {
+                return ParsedHeader::Invalid("address doesn't match the protocol family");
+            }
+            Some((source, destination))
+        }
+        _ => return ParsedHeader::Invalid("invalid header line"),
+    };
+
+    ParsedHeader::Complete { length, addresses }
+}
+
+fn parse_v2(data: &[u8]) -> ParsedHeader {
+    let compared = data.len().min(V2_SIGNATURE.len());
+    if data[..compared] != V2_SIGNATURE[..compared] {
+        return ParsedHeader::Invalid("not a PROXY protocol header");
+    }
+    if data.len() < 16 {
+        return ParsedHeader::Incomplete;
+    }
+
+    let length = 16 + u16::from_be_bytes([data[14], data[15]]) as usize;
+    if data.len() < length {
+        return ParsedHeader::Incomplete;
+    }
+
+    // TLVs after the addresses are skipped
+    let payload = &data[16..length];
+    let addresses = match (data[12], data[13]) {
+        (V2_LOCAL, _) => None,
+        (V2_PROXY, V2_TCP4) if payload.len() >= 12 => {
+            let ip = |i: usize| IpAddr::from([payload[i], payload[i + 1], payload[i + 2], payload[i + 3]]);
+            let port = |i: usize| u16::from_be_bytes([payload[i], payload[i + 1]]);
+            Some((SocketAddr::new(ip(0), port(8)), SocketAddr::new(ip(4), port(10))))
+        }
+        (V2_PROXY, V2_TCP6) if payload.len() >= 36 => {
+            let ip = |i: usize| {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&payload[i..i + 16]);
+                IpAddr::from(octets)
+            };
+            let port = |i: usize| u16::from_be_bytes([payload[i], payload[i + 1]]);
+            Some((SocketAddr::new(ip(0), port(32)), SocketAddr::new(ip(16), port(34))))
+        }
+        (V2_PROXY, V2_TCP4 | V2_TCP6) => return ParsedHeader::Invalid("addresses don't fit into the header"),
+        // other families and transports (UDP, unix sockets, unspecified) don't describe a TCP client
+        (V2_PROXY, _) => None,
+        _ => return ParsedHeader::Invalid("unsupported version or command"),
+    };
+
+    ParsedHeader::Complete { length, addresses }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2(command: u8, family: u8, payload: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(command);
+        header.push(family);
+        header.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        header.extend_from_slice(payload);
+        header
+    }
+
+    fn addresses(source: &str, destination: &str) -> Option<(SocketAddr, SocketAddr)> {
+        Some((source.parse().unwrap(), destination.parse().unwrap()))
+    }
+
+    #[test]
+    fn parses_v1_headers() {
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 443\r\nGET /";
+        assert_eq!(
+            parse_header(header),
+            ParsedHeader::Complete {
+                length: header.len() - 5,
+                addresses: addresses("192.0.2.1:56324", "198.51.100.2:443"),
+            }
+        );
+
+        let header = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n";
+        assert_eq!(
+            parse_header(header),
+            ParsedHeader::Complete {
+                length: header.len(),
+                addresses: addresses("[2001:db8::1]:56324", "[2001:db8::2]:443"),
+            }
+        );
+
+        let header = b"PROXY UNKNOWN ignored until the line break\r\n";
+        assert_eq!(parse_header(header), ParsedHeader::Complete { length: header.len(), addresses: None });
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_a_v1_header() {
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 443\r\n";
+        for end in 0..header.len() {
+            assert_eq!(parse_header(&header[..end]), ParsedHeader::Incomplete, "{} bytes", end);
+        }
+        assert!(matches!(parse_header(header), ParsedHeader::Complete { .. }));
+    }
+
+    #[test]
+    fn rejects_invalid_v1_headers() {
+        // the longest valid header has 107 bytes, also when its line break already arrived
+        let mut header = b"PROXY UNKNOWN ".to_vec();
+        header.resize(V1_MAX_LENGTH - 2, b'x');
+        header.extend_from_slice(b"\r\n");
+        assert!(matches!(parse_header(&header), ParsedHeader::Complete { .. }));
+
+        header.insert(20, b'x');
+        assert_eq!(parse_header(&header), ParsedHeader::Invalid("header too long"));
+        assert_eq!(parse_header(&header[..V1_MAX_LENGTH]), ParsedHeader::Invalid("header too long"));
+
+        assert_eq!(parse_header(b"PROXY TCP4 2001:db8::1 198.51.100.2 1 2\r\n"), ParsedHeader::Invalid("address doesn't match the protocol family"));
+        assert_eq!(parse_header(b"PROXY TCP6 192.0.2.1 2001:db8::2 1 2\r\n"), ParsedHeader::Invalid("address doesn't match the protocol family"));
+        assert_eq!(parse_header(b"PROXY TCP4 192.0.2.1 198.51.100.2 1\r\n"), ParsedHeader::Invalid("invalid header line"));
+        assert_eq!(parse_header(b"PROXY TCP4 192.0.2.1 198.51.100.2 1 70000\r\n"), ParsedHeader::Invalid("invalid address"));
+        assert_eq!(parse_header(b"PRXY"), ParsedHeader::Invalid("not a PROXY protocol header"));
+        assert_eq!(parse_header(b"GET / HTTP/1.1\r\n"), ParsedHeader::Invalid("not a PROXY protocol header"));
+    }
+
+    #[test]
+    fn parses_v2_headers() {
+        let mut payload = vec![192, 0, 2, 1, 198, 51, 100, 2];
+        payload.extend_from_slice(&56324u16.to_be_bytes());
+        payload.extend_from_slice(&443u16.to_be_bytes());
+        let header = v2(V2_PROXY, V2_TCP4, &payload);
+        assert_eq!(
+            parse_header(&header),
+            ParsedHeader::Complete {
+                length: 28,
+                addresses: addresses("192.0.2.1:56324", "198.51.100.2:443"),
+            }
+        );
+
+        let source: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let destination: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let mut payload = source.octets().to_vec();
+        payload.extend_from_slice(&destination.octets());
+        payload.extend_from_slice(&56324u16.to_be_bytes());
+        payload.extend_from_slice(&443u16.to_be_bytes());
+        let header = v2(V2_PROXY, V2_TCP6, &payload);
+        assert_eq!(
+            parse_header(&header),
+            ParsedHeader::Complete {
+                length: 52,
+                addresses: addresses("[2001:db8::1]:56324", "[2001:db8::2]:443"),
+            }
+        );
+
+        // health checks of the proxy itself
+        assert_eq!(parse_header(&v2(V2_LOCAL, 0x00, &[])), ParsedHeader::Complete { length: 16, addresses: None });
+
+        // unix stream sockets have two 108 byte paths
+        let header = v2(V2_PROXY, 0x31, &[0; 216]);
+        assert_eq!(parse_header(&header), ParsedHeader::Complete { length: 232, addresses: None });
+    }
+
+    #[test]
+    fn skips_v2_tlvs() {
+        let mut proxy_protocol = ProxyProtocol::new(ProxyProtocolVersion::V2);
+        assert!(proxy_protocol.add_tlv(0xE0, b"edge-1".to_vec()));
+        assert!(proxy_protocol.add_tlv(0x04, vec![]));
+        let header = proxy_protocol.header("192.0.2.1:56324".parse().unwrap(), "198.51.100.2:443".parse().unwrap());
+
+        let mut data = header.clone();
+        data.extend_from_slice(b"GET /");
+        assert_eq!(
+            parse_header(&data),
+            ParsedHeader::Complete {
+                length: header.len(),
+                addresses: addresses("192.0.2.1:56324", "198.51.100.2:443"),
+            }
+        );
+        for end in 0..header.len() {
+            assert_eq!(parse_header(&header[..end]), ParsedHeader::Incomplete, "{} bytes", end);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_v2_headers() {
+        let mut header = v2(V2_LOCAL, 0x00, &[]);
+        header[11] = 0x00;
+        assert_eq!(parse_header(&header), ParsedHeader::Invalid("not a PROXY protocol header"));
+
+        // version 3 doesn't exist, commands other than LOCAL and PROXY neither
+        assert_eq!(parse_header(&v2(0x31, V2_TCP4, &[0; 12])), ParsedHeader::Invalid("unsupported version or command"));
+        assert_eq!(parse_header(&v2(0x22, V2_TCP4, &[0; 12])), ParsedHeader::Invalid("unsupported version or command"));
+
+        assert_eq!(parse_header(&v2(V2_PROXY, V2_TCP4, &[0; 8])), ParsedHeader::Invalid("addresses don't fit into the header"));
+        assert_eq!(parse_header(&v2(V2_PROXY, V2_TCP6, &[0; 12])), ParsedHeader::Invalid("addresses don't fit into the header"));
+    }
+
+    #[test]
+    fn headers_use_the_same_family_for_both_addresses() {
+        let header = ProxyProtocol::new(ProxyProtocolVersion::V1).header("192.0.2.1:56324".parse().unwrap(), "[::ffff:198.51.100.2]:443".parse().unwrap());
+        assert_eq!(header, b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 443\r\n");
+
+        let header = ProxyProtocol::new(ProxyProtocolVersion::V1).header("192.0.2.1:56324".parse().unwrap(), "[2001:db8::2]:443".parse().unwrap());
+        assert_eq!(header, b"PROXY TCP6 ::ffff:192.0.2.1 2001:db8::2 56324 443\r\n");
+        assert_eq!(
+            parse_header(&header),
+            ParsedHeader::Complete {
+                length: header.len(),
+                addresses: addresses("[::ffff:192.0.2.1]:56324", "[2001:db8::2]:443"),
+            }
+        );
+
+        let header = ProxyProtocol::new(ProxyProtocolVersion::V2).header("[2001:db8::1]:56324".parse().unwrap(), "198.51.100.2:443".parse().unwrap());
+        assert_eq!(header[13], V2_TCP6);
+        assert_eq!(
+            parse_header(&header),
+            ParsedHeader::Complete {
+                length: header.len(),
+                addresses: addresses("[2001:db8::1]:56324", "[::ffff:198.51.100.2]:443"),
+            }
+        );
+    }
+}
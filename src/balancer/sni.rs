@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::Pool;
+
+// TLS record and handshake message types
+const RECORD_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+
+// extension carrying the server name, and the name type of host names in it
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/**
+    Longest ClientHello that is waited for, clients sending larger ones are routed as if they didn't send a server name
+*/
+pub const MAX_CLIENT_HELLO: usize = 64 * 1024;
+
+/**
+    Size the peek buffer of a client starts with, it's doubled while the ClientHello doesn't fit. Most ClientHellos are well below it
+*/
+pub const INITIAL_CLIENT_HELLO: usize = 2 * 1024;
+
+/**
+    Result of parsing the start of a connection as a TLS ClientHello
+*/
+#[derive(PartialEq, Debug)]
+pub enum ParsedHello {
+    /**
+        More data is needed
+    */
+    Incomplete,
+    /**
+        The data is not a TLS ClientHello
+    */
+    Invalid,
+    /**
+        The server name the client asked for, if any
+    */
+    Complete(Option<String>),
+}
+
+/**
+    Parses the ClientHello at the start of [data] and extracts the server name (SNI). The message can span multiple records
+*/
+pub fn parse_client_hello(data: &[u8]) -> ParsedHello {
+    // collect the handshake message from as many records as it takes
+    let mut message = vec![];
+    let mut rest = data;
+    loop {
+        if rest.len() < 5 {
+            return ParsedHello::Incomplete;
+        }
+        if rest[0] != RECORD_HANDSHAKE || rest[1] != 0x03 {
+            return ParsedHello::Invalid;
+        }
+
+        let length = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+        if rest.len() < 5 + length {
+            return ParsedHello::Incomplete;
+        }
+        message.extend_from_slice(&rest[5..5 + length]);
+        rest = &rest[5 + length..];
+
+        if message.len() >= 4 {
+            if message[0] != HANDSHAKE_CLIENT_HELLO {
+                return ParsedHello::Invalid;
+            }
+
+            let length = u32::from_be_bytes([0, message[1], message[2], message[3]]) as usize;
+            if message.len() >= 4 + length {
+                message.truncate(4 + length);
+                break;
+            }
+        }
+    }
+
+    match server_name(&message[4..]) {
+        Some(name) => ParsedHello::Complete(name),
+        None => ParsedHello::Invalid,
+    }
+}
+
+/**
+    Reads through a ClientHello body, returns [None] if it's malformed
+*/
+fn server_name(hello: &[u8]) -> Option<Option<String>> {
+    let mut reader = Reader { data: hello };
+
+    // version and random
+    reader.skip(2 + 32)?;
+    // session id, cipher suites and compression methods
+    let length = reader.u8()? as usize;
+    reader.skip(length)?;
+    let length = reader.u16()? as usize;
+    reader.skip(length)?;
+    let length = reader.u8()? as usize;
+    reader.skip(length)?;
+
+    // no extensions at all
+    if reader.data.is_empty() {
+        return Some(None);
+    }
+
+    let length = reader.u16()? as usize;
+    let mut extensions = Reader { data: reader.take(length)? };
+    while !extensions.data.is_empty() {
+        let kind = extensions.u16()?;
+        let length = extensions.u16()? as usize;
+        let mut extension = Reader { data: extensions.take(length)? };
+        if kind != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let length = extension.u16()? as usize;
+        let mut names = Reader { data: extension.take(length)? };
+        while !names.data.is_empty() {
+            let name_type = names.u8()?;
+            let length = names.u16()? as usize;
+            let name = names.take(length)?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                return Some(std::str::from_utf8(name).ok().map(|n| n.to_string()));
+            }
+        }
+    }
+
+    Some(None)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        if self.data.len() < length {
+            return None;
+        }
+
+        let (taken, rest) = self.data.split_at(length);
+        self.data = rest;
+        Some(taken)
+    }
+
+    fn skip(&mut self, length: usize) -> Option<()> {
+        self.take(length).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+/**
+    Values looked up by server name. Names are matched exactly first, then by the longest matching wildcard
+    (`*.example.com` matches any name ending with `.example.com`)
+*/
+#[derive(Debug)]
+pub struct NameMap<T> {
+    exact: HashMap<String, T>,
+    wildcards: Vec<(String, T)>,
+}
+
+impl<T> NameMap<T> {
+    pub fn new() -> Self {
+        NameMap {
+            exact: HashMap::new(),
+            wildcards: vec![],
+        }
+    }
+
+    /**
+        Adds [value] for [name], an exact name or a wildcard like `*.example.com`
+    */
+    pub fn insert(&mut self, name: &str, value: T) {
+        let name = normalize(name);
+        match name.strip_prefix('*') {
+            Some(suffix) => {
+                self.wildcards.push((suffix.to_string(), value));
+                // longest suffixes are the most specific, they are checked first
+                self.wildcards.sort_by_key(|(s, _)| std::cmp::Reverse(s.len()));
+            }
+            None => {
+                self.exact.insert(name, value);
+            }
+        }
+    }
+
+    /**
+        Returns the value added for exactly [name] (which may be a wildcard), without matching other names against it
+    */
+    pub fn get_added_mut(&mut self, name: &str) -> Option<&mut T> {
+        let name = normalize(name);
+        match name.strip_prefix('*') {
+            Some(suffix) => self.wildcards.iter_mut().find(|(s, _)| s == suffix).map(|(_, value)| value),
+            None => self.exact.get_mut(&name),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&T> {
+        let name = normalize(name);
+        if let Some(value) = self.exact.get(&name) {
+            return Some(value);
+        }
+
+        self.wildcards.iter().find(|(suffix, _)| name.ends_with(suffix.as_str())).map(|(_, value)| value)
+    }
+}
+
+/**
+    Picks a pool by the server name TLS clients ask for. Clients without a known name go to the default pool
+*/
+pub struct SniRouter {
+    routes: NameMap<Arc<Pool>>,
+    default: Arc<Pool>,
+}
+
+impl SniRouter {
+    pub fn new(default: Arc<Pool>) -> Self {
+        SniRouter {
+            routes: NameMap::new(),
+            default,
+        }
+    }
+
+    /**
+        Routes clients asking for [name] (an exact name or a wildcard like `*.example.com`) to [pool]
+    */
+    pub fn add_route(&mut self, name: &str, pool: Arc<Pool>) {
+        self.routes.insert(name, pool);
+    }
+
+    pub fn route(&self, name: Option<&str>) -> &Arc<Pool> {
+        name.and_then(|n| self.routes.get(n)).unwrap_or(&self.default)
+    }
+}
+
+/**
+    Server names are case insensitive and may end with a dot
+*/
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+        ClientHello handshake message with the given extensions
+    */
+    fn client_hello(extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[7; 32]);
+        // session id, two cipher suites and the null compression method
+        body.extend_from_slice(&[0, 0, 4, 0x13, 0x01, 0x13, 0x02, 1, 0]);
+
+        let mut encoded = vec![];
+        for (kind, data) in extensions {
+            encoded.extend_from_slice(&kind.to_be_bytes());
+            encoded.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            encoded.extend_from_slice(data);
+        }
+        body.extend_from_slice(&(encoded.len() as u16).to_be_bytes());
+        body.extend_from_slice(&encoded);
+
+        let mut message = vec![HANDSHAKE_CLIENT_HELLO];
+        message.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        message.extend_from_slice(&body);
+        message
+    }
+
+    fn server_name_extension(name: &str) -> (u16, Vec<u8>) {
+        let mut list = vec![NAME_TYPE_HOST_NAME];
+        list.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        list.extend_from_slice(name.as_bytes());
+
+        let mut data = (list.len() as u16).to_be_bytes().to_vec();
+        data.extend_from_slice(&list);
+        (EXTENSION_SERVER_NAME, data)
+    }
+
+    /**
+        Splits [message] into handshake records of at most [size] bytes
+    */
+    fn records(message: &[u8], size: usize) -> Vec<u8> {
+        let mut data = vec![];
+        for fragment in message.chunks(size) {
+            data.extend_from_slice(&[RECORD_HANDSHAKE, 0x03, 0x01]);
+            data.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+            data.extend_from_slice(fragment);
+        }
+        data
+    }
+
+    #[test]
+    fn parses_server_name() {
+        let hello = client_hello(&[(0x000A, vec![0, 2, 0, 0x1D]), server_name_extension("www.example.com")]);
+        let mut data = records(&hello, 16 * 1024);
+        data.extend_from_slice(&[0x17, 0x03, 0x03]);
+        assert_eq!(parse_client_hello(&data), ParsedHello::Complete(Some("www.example.com".to_string())));
+    }
+
+    #[test]
+    fn parses_client_hello_spanning_records() {
+        let hello = client_hello(&[(0x0015, vec![0; 300]), server_name_extension("www.example.com")]);
+        let data = records(&hello, 100);
+        assert!(hello.len() > 3 * 100);
+        assert_eq!(parse_client_hello(&data), ParsedHello::Complete(Some("www.example.com".to_string())));
+
+        for end in 0..data.len() {
+            assert_eq!(parse_client_hello(&data[..end]), ParsedHello::Incomplete, "{} bytes", end);
+        }
+    }
+
+    #[test]
+    fn parses_client_hello_without_server_name() {
+        let hello = client_hello(&[(0x000A, vec![0, 2, 0, 0x1D])]);
+        assert_eq!(parse_client_hello(&records(&hello, 16 * 1024)), ParsedHello::Complete(None));
+
+        // extensions are optional, the list may be missing entirely
+        let mut hello = client_hello(&[]);
+        hello.truncate(hello.len() - 2);
+        let length = (hello.len() - 4) as u32;
+        hello[1..4].copy_from_slice(&length.to_be_bytes()[1..]);
+        assert_eq!(parse_client_hello(&records(&hello, 16 * 1024)), ParsedHello::Complete(None));
+    }
+
+    #[test]
+    fn rejects_malformed_client_hellos() {
+        // the server name extension claims more data than it has
+        let (kind, mut data) = server_name_extension("www.example.com");
+        data[1] += 1;
+        let hello = client_hello(&[(kind, data)]);
+        assert_eq!(parse_client_hello(&records(&hello, 16 * 1024)), ParsedHello::Invalid);
+
+        // an extension longer than the extension list
+        let mut hello = client_hello(&[server_name_extension("www.example.com")]);
+        let at = hello.len() - 22;
+        hello[at] = 0xFF;
+        assert_eq!(parse_client_hello(&records(&hello, 16 * 1024)), ParsedHello::Invalid);
+
+        // a handshake message other than a ClientHello
+        let mut hello = client_hello(&[]);
+        hello[0] = 0x02;
+        assert_eq!(parse_client_hello(&records(&hello, 16 * 1024)), ParsedHello::Invalid);
+    }
+
+    #[test]
+    fn rejects_other_protocols() {
+        assert_eq!(parse_client_hello(b"GET / HTTP/1.1\r\n"), ParsedHello::Invalid);
+        assert_eq!(parse_client_hello(b"SSH-2.0-OpenSSH_9.6\r\n"), ParsedHello::Invalid);
+        assert_eq!(parse_client_hello(&[0x17, 0x03, 0x03, 0x00, 0x01, 0x00]), ParsedHello::Invalid);
+        // too short to tell yet
+        assert_eq!(parse_client_hello(&[RECORD_HANDSHAKE, 0x03]), ParsedHello::Incomplete);
+    }
+
+    #[test]
+    fn names_match_exactly_before_wildcards() {
+        let mut names = NameMap::new();
+        names.insert("*.example.com", "wildcard");
+        names.insert("www.example.com", "exact");
+        names.insert("*.api.example.com", "longer wildcard");
+
+        assert_eq!(names.get("www.example.com"), Some(&"exact"));
+        assert_eq!(names.get("mail.example.com"), Some(&"wildcard"));
+        assert_eq!(names.get("v1.api.example.com"), Some(&"longer wildcard"));
+        assert_eq!(names.get("api.example.com"), Some(&"wildcard"));
+        // wildcards only match subdomains
+        assert_eq!(names.get("example.com"), None);
+        assert_eq!(names.get("www.example.org"), None);
+    }
+
+    #[test]
+    fn names_ignore_case_and_trailing_dots() {
+        let mut names = NameMap::new();
+        names.insert("WWW.Example.com.", 1);
+        names.insert("*.Example.ORG", 2);
+
+        assert_eq!(names.get("www.example.com"), Some(&1));
+        assert_eq!(names.get("www.EXAMPLE.com."), Some(&1));
+        assert_eq!(names.get("Mail.example.org."), Some(&2));
+
+        *names.get_added_mut("*.example.org.").unwrap() = 3;
+        assert_eq!(names.get("mail.example.org"), Some(&3));
+        assert!(names.get_added_mut("mail.example.org").is_none());
+    }
+}
@@ -0,0 +1,113 @@
+use std::env;
+use std::io::{Error, ErrorKind, Result};
+use std::net::TcpListener;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::sync::OnceLock;
+
+use log::warn;
+
+// first file descriptor passed by systemd, see sd_listen_fds(3)
+const LISTEN_FDS_START: RawFd = 3;
+
+// values of LISTEN_PID and LISTEN_FDS when the process started, see [take_listen_env]
+static LISTEN_ENV: OnceLock<(Option<String>, Option<String>)> = OnceLock::new();
+
+/**
+    Takes the socket activation variables out of the environment, so they are not inherited by child processes. Changing the
+    environment is only sound while no other threads are running, this has to be called at the start of main
+*/
+pub fn take_listen_env() {
+    LISTEN_ENV.get_or_init(|| {
+        let values = (env::var("LISTEN_PID").ok(), env::var("LISTEN_FDS").ok());
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+        values
+    });
+}
+
+/**
+    Returns listening sockets passed by systemd socket activation (`LISTEN_FDS`/`LISTEN_PID`), empty if there are none
+*/
+pub fn listen_fds() -> Result<Vec<TcpListener>> {
+    let (pid, count) = LISTEN_ENV.get().cloned().unwrap_or_default();
+
+    // the sockets were meant for another process
+    let pid: u32 = match pid.and_then(|p| p.parse().ok()) {
+        Some(p) => p,
+        None => return Ok(vec![]),
+    };
+    if pid != std::process::id() {
+        return Ok(vec![]);
+    }
+
+    let count: RawFd = count
+        .and_then(|c| c.parse().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "invalid LISTEN_FDS value"))?;
+
+    let mut listeners = vec![];
+    for fd in LISTEN_FDS_START..(LISTEN_FDS_START + count) {
+        if !is_socket(fd) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("passed file descriptor {} is not a socket", fd)));
+        }
+
+        unsafe {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            listeners.push(TcpListener::from_raw_fd(fd));
+        }
+    }
+
+    Ok(listeners)
+}
+
+fn is_socket(fd: RawFd) -> bool {
+    unsafe {
+        let mut stat: libc::stat = std::mem::zeroed();
+        if libc::fstat(fd, &mut stat) < 0 {
+            return false;
+        }
+
+        (stat.st_mode & libc::S_IFMT) == libc::S_IFSOCK
+    }
+}
+
+/**
+    Sends a state update to the service manager (such as `READY=1`, `STOPPING=1` or `STATUS=...`), see sd_notify(3).
+    Does nothing if the balancer was not started by systemd with `NOTIFY_SOCKET` set.
+*/
+pub fn notify(state: &str) {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(p) => p,
+        Err(_) => return,
+    };
+
+    send_notification(&path, state).unwrap_or_else(|e| {
+        warn!(target: "Systemd", "Failed to send notification '{}' -> {}", state, e);
+    });
+}
+
+fn send_notification(path: &str, state: &str) -> Result<()> {
+    let socket = UnixDatagram::unbound()?;
+
+    // sockets starting with '@' are in the abstract namespace
+    if let Some(name) = path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt;
+
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+            return Ok(());
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = name;
+            return Err(Error::new(ErrorKind::Unsupported, "abstract notify sockets are only supported on Linux"));
+        }
+    }
+
+    socket.send_to(state.as_bytes(), path)?;
+    Ok(())
+}
@@ -0,0 +1,637 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+use log::{debug, warn};
+use mio::net::TcpStream;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ClientConfig, ClientConnection, Connection, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use rustls::{ServerConfig, ServerConnection, SupportedProtocolVersion};
+
+use super::sni::NameMap;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TlsVersion {
+    V12,
+    V13,
+}
+
+impl TlsVersion {
+    pub fn parse(version: &str) -> Option<Self> {
+        match version {
+            "1.2" | "TLSv1.2" => Some(TlsVersion::V12),
+            "1.3" | "TLSv1.3" => Some(TlsVersion::V13),
+            _ => None,
+        }
+    }
+
+    fn protocol_version(&self) -> &'static SupportedProtocolVersion {
+        match self {
+            TlsVersion::V12 => &rustls::version::TLS12,
+            TlsVersion::V13 => &rustls::version::TLS13,
+        }
+    }
+}
+
+/**
+    Certificates of a listener, picked by the server name clients ask for (SNI). The first certificate is used for clients
+    without a server name or with one no certificate is configured for
+*/
+pub struct CertificateStore {
+    names: NameMap<Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl CertificateStore {
+    pub fn new() -> Self {
+        CertificateStore {
+            names: NameMap::new(),
+            default: None,
+            provider: Arc::new(rustls::crypto::ring::default_provider()),
+        }
+    }
+
+    /**
+        Loads a certificate chain and its private key from PEM files and uses them for clients asking for any of [names]
+        (exact names or wildcards like `*.example.com`)
+    */
+    pub fn add_file(&mut self, cert_file: &str, key_file: &str, names: &[String]) -> Result<()> {
+        let (chain, key) = load_certificate(cert_file, key_file)?;
+        let certified = Arc::new(CertifiedKey::from_der(chain, key, &self.provider).map_err(|e| invalid(key_file, &e))?);
+        for name in names {
+            self.names.insert(name, Arc::clone(&certified));
+        }
+        self.default.get_or_insert(certified);
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none()
+    }
+
+    /**
+        Builds the configuration of TLS connections of a listener, [versions] are the allowed protocol versions (all if empty)
+        and [alpn] the application protocols offered to clients, by preference
+    */
+    pub fn into_server_config(self, versions: &[TlsVersion], alpn: &[String]) -> Result<ServerConfig> {
+        let versions: Vec<&'static SupportedProtocolVersion> = match versions.is_empty() {
+            true => rustls::ALL_VERSIONS.to_vec(),
+            false => versions.iter().map(|v| v.protocol_version()).collect(),
+        };
+
+        let mut config = ServerConfig::builder_with_provider(Arc::clone(&self.provider))
+            .with_protocol_versions(&versions)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(self));
+        config.alpn_protocols = alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+
+        Ok(config)
+    }
+}
+
+impl ResolvesServerCert for CertificateStore {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        client_hello.server_name().and_then(|n| self.names.get(n)).or(self.default.as_ref()).cloned()
+    }
+}
+
+impl fmt::Debug for CertificateStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertificateStore").field("names", &self.names).finish()
+    }
+}
+
+/**
+    Terminates TLS of the clients of a listener. The configuration can be replaced at any time (e.g. with renewed certificates),
+    connections that are already established keep the one they started with
+*/
+pub struct TlsAcceptor {
+    config: RwLock<Arc<ServerConfig>>,
+}
+
+impl TlsAcceptor {
+    pub fn new(config: ServerConfig) -> Self {
+        TlsAcceptor {
+            config: RwLock::new(Arc::new(config)),
+        }
+    }
+
+    pub fn reload(&self, config: ServerConfig) {
+        *self.config.write().unwrap() = Arc::new(config);
+    }
+
+    pub fn accept(&self) -> Result<Connection> {
+        let config = Arc::clone(&self.config.read().unwrap());
+        ServerConnection::new(config).map(Connection::from).map_err(Error::other)
+    }
+}
+
+/**
+    Starts TLS sessions with the backends of a pool
+*/
+pub struct TlsConnector {
+    config: Arc<ClientConfig>,
+    /**
+        Name sent as SNI and expected in backend certificates, the backend's address is used if not set
+    */
+    server_name: Option<ServerName<'static>>,
+}
+
+impl TlsConnector {
+    /**
+        Backend certificates are checked against the CAs in [ca_file] (a PEM bundle), or the system's CAs if not set.
+        [client_certificate] is a certificate chain and key file presented to backends asking for it (mutual TLS).
+        Without [verify], backends aren't checked at all
+    */
+    pub fn new(ca_file: Option<&str>, client_certificate: Option<(&str, &str)>, verify: bool) -> Result<Self> {
+        let provider = Arc::new(crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+
+        let builder = match verify {
+            true => builder.with_root_certificates(load_roots(ca_file)?),
+            false => builder.dangerous().with_custom_certificate_verifier(Arc::new(NoVerification { provider })),
+        };
+
+        let config = match client_certificate {
+            Some((cert_file, key_file)) => {
+                let (chain, key) = load_certificate(cert_file, key_file)?;
+                builder.with_client_auth_cert(chain, key).map_err(|e| invalid(key_file, &e))?
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        Ok(TlsConnector {
+            config: Arc::new(config),
+            server_name: None,
+        })
+    }
+
+    /**
+        Sends [name] to backends instead of their address, returns [false] if it's not a valid DNS name or IP address
+    */
+    pub fn set_server_name(&mut self, name: &str) -> bool {
+        match ServerName::try_from(name.to_string()) {
+            Ok(n) => {
+                self.server_name = Some(n);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    pub fn connect(&self, target: SocketAddr) -> Result<Connection> {
+        let server_name = self.server_name.clone().unwrap_or_else(|| ServerName::from(target.ip()));
+        ClientConnection::new(Arc::clone(&self.config), server_name).map(Connection::from).map_err(Error::other)
+    }
+}
+
+/**
+    Accepts any backend certificate, only the handshake signatures are still checked
+*/
+#[derive(Debug)]
+struct NoVerification {
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn invalid(file: &str, e: &dyn fmt::Display) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("'{}' -> {}", file, e))
+}
+
+/**
+    Reads a certificate chain and its private key from PEM files
+*/
+fn load_certificate(cert_file: &str, key_file: &str) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let chain = CertificateDer::pem_file_iter(cert_file)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| invalid(cert_file, &e))?;
+    if chain.is_empty() {
+        return Err(invalid(cert_file, &"no certificates found"));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_file).map_err(|e| invalid(key_file, &e))?;
+
+    Ok((chain, key))
+}
+
+/**
+    Reads trusted CAs from a PEM bundle, or from the system if [ca_file] isn't set
+*/
+fn load_roots(ca_file: Option<&str>) -> Result<RootCertStore> {
+    let certs = match ca_file {
+        Some(file) => CertificateDer::pem_file_iter(file)
+            .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+            .map_err(|e| invalid(file, &e))?,
+        None => rustls_native_certs::load_native_certs().certs,
+    };
+
+    let mut roots = RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(certs);
+    if added == 0 {
+        return Err(invalid(ca_file.unwrap_or("system CAs"), &"no usable CA certificates found"));
+    }
+
+    Ok(roots)
+}
+
+/**
+    Decrypted view of a non-blocking stream. Handshake messages are sent while reading, encrypted data that the stream
+    doesn't take right away stays in the connection and has to be flushed once the stream is writable again
+*/
+pub struct TlsStream<'a> {
+    pub connection: &'a mut Connection,
+    pub stream: &'a mut TcpStream,
+}
+
+impl Read for TlsStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            // 0 once the peer sent close_notify. Many backends just close the connection without it, that ends the connection
+            // the same way a plain one does. Clients doing so may have had their data cut off, that stays an error
+            match self.connection.reader().read(buf) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(ref e) if e.kind() == ErrorKind::UnexpectedEof && matches!(self.connection, Connection::Client(_)) => return Ok(0),
+                result => return result,
+            }
+
+            self.connection.read_tls(self.stream)?;
+            let processed = self.connection.process_new_packets();
+
+            // handshake messages and alerts (also the one about a failed handshake) go out right away
+            self.flush()?;
+            if let Err(e) = processed {
+                // failing backends are a configuration issue, failing clients are usually not
+                let peer = self.stream.peer_addr().map_or("-".to_string(), |a| a.to_string());
+                match self.connection {
+                    Connection::Client(_) => warn!(target: "TLS", "TLS with backend {} failed -> {}", peer, e),
+                    Connection::Server(_) => debug!(target: "TLS", "TLS with client {} failed -> {}", peer, e),
+                }
+                return Err(Error::new(ErrorKind::InvalidData, e));
+            }
+        }
+    }
+}
+
+impl Write for TlsStream<'_> {
+    fn write(&mut self, data: &[u8]) -> Result<usize> {
+        // earlier data has to go out first, so the stream pushes back on the sender
+        self.flush()?;
+        if self.connection.wants_write() {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+
+        // nothing is taken while the buffer of data waiting for the handshake is full
+        let written = self.connection.writer().write(data)?;
+        if written == 0 && !data.is_empty() {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+
+        self.flush()?;
+        Ok(written)
+    }
+
+    /**
+        Writes encrypted data until the stream would block
+    */
+    fn flush(&mut self) -> Result<()> {
+        while self.connection.wants_write() {
+            match self.connection.write_tls(self.stream) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/**
+    Stream that is either plain or wrapped in TLS
+*/
+pub enum MaybeTlsStream<'a> {
+    Plain(&'a mut TcpStream),
+    Tls(TlsStream<'a>),
+}
+
+impl<'a> MaybeTlsStream<'a> {
+    pub fn new(stream: &'a mut TcpStream, connection: Option<&'a mut Connection>) -> Self {
+        match connection {
+            Some(connection) => MaybeTlsStream::Tls(TlsStream { connection, stream }),
+            None => MaybeTlsStream::Plain(stream),
+        }
+    }
+}
+
+impl Read for MaybeTlsStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            MaybeTlsStream::Plain(s) => s.read(buf),
+            MaybeTlsStream::Tls(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for MaybeTlsStream<'_> {
+    fn write(&mut self, data: &[u8]) -> Result<usize> {
+        match self {
+            MaybeTlsStream::Plain(s) => s.write(data),
+            MaybeTlsStream::Tls(s) => s.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            MaybeTlsStream::Plain(s) => s.flush(),
+            MaybeTlsStream::Tls(s) => s.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::net::IpAddr;
+    use std::thread;
+    use std::time::Duration;
+
+    /**
+        Generates a self-signed certificate for [names] and adds it to [store] for clients asking for [sni_names]
+    */
+    fn add_certificate(store: &mut CertificateStore, names: &[&str], sni_names: &[&str]) -> CertificateDer<'static> {
+        let generated = rcgen::generate_simple_self_signed(names.iter().map(|n| n.to_string()).collect::<Vec<_>>()).unwrap();
+        let path = std::env::temp_dir().join(format!("lb-tls-{}-{}", std::process::id(), names[0].replace('*', "_")));
+        let (cert_file, key_file) = (path.with_extension("crt"), path.with_extension("key"));
+        fs::write(&cert_file, generated.cert.pem()).unwrap();
+        fs::write(&key_file, generated.key_pair.serialize_pem()).unwrap();
+
+        let sni_names: Vec<String> = sni_names.iter().map(|n| n.to_string()).collect();
+        let added = store.add_file(cert_file.to_str().unwrap(), key_file.to_str().unwrap(), &sni_names);
+        fs::remove_file(&cert_file).unwrap();
+        fs::remove_file(&key_file).unwrap();
+        added.unwrap();
+
+        generated.cert.der().clone()
+    }
+
+    /**
+        Moves TLS records from one in-memory connection to the other
+    */
+    fn transfer(from: &mut Connection, to: &mut Connection) {
+        let mut records = vec![];
+        while from.wants_write() {
+            from.write_tls(&mut records).unwrap();
+        }
+
+        let mut records = &records[..];
+        while !records.is_empty() {
+            to.read_tls(&mut records).unwrap();
+        }
+        to.process_new_packets().unwrap();
+    }
+
+    /**
+        Completes a handshake of a backend client without SNI ([server_name] is [None]) and returns the certificate it got
+    */
+    fn handshake(acceptor: &TlsAcceptor, server_name: Option<&str>) -> CertificateDer<'static> {
+        let mut connector = TlsConnector::new(None, None, false).unwrap();
+        if let Some(name) = server_name {
+            assert!(connector.set_server_name(name));
+        }
+
+        let mut client = connector.connect(SocketAddr::new(IpAddr::from([127, 0, 0, 1]), 443)).unwrap();
+        let mut server = acceptor.accept().unwrap();
+        while client.is_handshaking() || server.is_handshaking() {
+            transfer(&mut client, &mut server);
+            transfer(&mut server, &mut client);
+        }
+
+        client.peer_certificates().unwrap()[0].clone().into_owned()
+    }
+
+    #[test]
+    fn picks_certificate_by_server_name() {
+        let mut store = CertificateStore::new();
+        assert!(store.is_empty());
+        let first = add_certificate(&mut store, &["a.example.com"], &["a.example.com"]);
+        let wildcard = add_certificate(&mut store, &["*.b.example.com"], &["*.b.example.com"]);
+        assert!(!store.is_empty());
+
+        let acceptor = TlsAcceptor::new(store.into_server_config(&[], &[]).unwrap());
+        assert_eq!(handshake(&acceptor, Some("a.example.com")), first);
+        assert_eq!(handshake(&acceptor, Some("x.b.example.com")), wildcard);
+        assert_eq!(handshake(&acceptor, Some("c.example.com")), first);
+        assert_eq!(handshake(&acceptor, None), first);
+    }
+
+    #[test]
+    fn fails_for_missing_certificates() {
+        let mut store = CertificateStore::new();
+        let error = store.add_file("/nonexistent.crt", "/nonexistent.key", &[]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        let empty = std::env::temp_dir().join(format!("lb-tls-{}-empty.crt", std::process::id()));
+        fs::write(&empty, "").unwrap();
+        let result = store.add_file(empty.to_str().unwrap(), "/nonexistent.key", &[]);
+        fs::remove_file(&empty).unwrap();
+        assert!(result.unwrap_err().to_string().contains("no certificates found"));
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn reload_replaces_configuration() {
+        let mut store = CertificateStore::new();
+        let old = add_certificate(&mut store, &["old.example.com"], &[]);
+        let acceptor = TlsAcceptor::new(store.into_server_config(&[TlsVersion::V13], &[]).unwrap());
+        assert_eq!(handshake(&acceptor, Some("example.com")), old);
+
+        let mut store = CertificateStore::new();
+        let new = add_certificate(&mut store, &["new.example.com"], &[]);
+        acceptor.reload(store.into_server_config(&[TlsVersion::V12], &[]).unwrap());
+        assert_eq!(handshake(&acceptor, Some("example.com")), new);
+    }
+
+    /**
+        Connected pair of non-blocking streams
+    */
+    fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        client.set_nonblocking(true).unwrap();
+        server.set_nonblocking(true).unwrap();
+
+        (TcpStream::from_std(client), TcpStream::from_std(server))
+    }
+
+    /**
+        Reads from [receiver] until [length] bytes or the end arrived, reading from [peer] in between so its handshake progresses
+    */
+    fn receive(receiver: &mut TlsStream, peer: &mut TlsStream, length: usize) -> Result<Vec<u8>> {
+        let mut received = vec![];
+        let mut buf = [0u8; 1024];
+        for _ in 0..2000 {
+            match peer.read(&mut buf) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                result => panic!("unexpected data from peer: {:?}", result),
+            }
+
+            match receiver.read(&mut buf) {
+                Ok(0) => return Ok(received),
+                Ok(read) => received.extend_from_slice(&buf[..read]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+            if received.len() >= length {
+                return Ok(received);
+            }
+
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        panic!("timed out after receiving {:?}", received);
+    }
+
+    #[test]
+    fn tls_stream_round_trip() {
+        let mut store = CertificateStore::new();
+        add_certificate(&mut store, &["localhost"], &[]);
+        let acceptor = TlsAcceptor::new(store.into_server_config(&[], &[]).unwrap());
+        let connector = TlsConnector::new(None, None, false).unwrap();
+
+        let (mut client_stream, mut server_stream) = socket_pair();
+        let mut client_connection = connector.connect(client_stream.peer_addr().unwrap()).unwrap();
+        let mut server_connection = acceptor.accept().unwrap();
+        let mut client = TlsStream {
+            connection: &mut client_connection,
+            stream: &mut client_stream,
+        };
+        let mut server = TlsStream {
+            connection: &mut server_connection,
+            stream: &mut server_stream,
+        };
+
+        // data written before the handshake completes is sent once it does
+        assert_eq!(client.write(b"hello").unwrap(), 5);
+        assert_eq!(receive(&mut server, &mut client, 5).unwrap(), b"hello");
+
+        // more than the socket buffers hold, the client reads while the server writes
+        let response = vec![7u8; 1_000_000];
+        let mut written = 0;
+        let mut received = vec![];
+        let mut buf = [0u8; 16 * 1024];
+        for _ in 0..5000 {
+            match server.write(&response[written..]) {
+                Ok(w) => written += w,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => panic!("{}", e),
+            }
+
+            match client.read(&mut buf) {
+                Ok(read) => received.extend_from_slice(&buf[..read]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(1)),
+                Err(e) => panic!("{}", e),
+            }
+            if received.len() == response.len() {
+                break;
+            }
+        }
+        assert_eq!(received, response);
+
+        // close_notify ends the stream
+        client.connection.send_close_notify();
+        client.flush().unwrap();
+        assert_eq!(receive(&mut server, &mut client, 1).unwrap(), b"");
+    }
+
+    #[test]
+    fn tls_stream_closed_without_close_notify() {
+        let mut store = CertificateStore::new();
+        add_certificate(&mut store, &["localhost"], &[]);
+        let acceptor = TlsAcceptor::new(store.into_server_config(&[], &[]).unwrap());
+        let connector = TlsConnector::new(None, None, false).unwrap();
+
+        for backend_closes in [true, false] {
+            let (mut client_stream, mut server_stream) = socket_pair();
+            let mut client_connection = connector.connect(client_stream.peer_addr().unwrap()).unwrap();
+            let mut server_connection = acceptor.accept().unwrap();
+            let mut client = TlsStream {
+                connection: &mut client_connection,
+                stream: &mut client_stream,
+            };
+            let mut server = TlsStream {
+                connection: &mut server_connection,
+                stream: &mut server_stream,
+            };
+            client.write_all(b"hello").unwrap();
+            assert_eq!(receive(&mut server, &mut client, 5).unwrap(), b"hello");
+
+            let (closing, reading) = match backend_closes {
+                true => (&mut server, &mut client),
+                false => (&mut client, &mut server),
+            };
+            closing.stream.shutdown(std::net::Shutdown::Both).unwrap();
+
+            // the balancer's backend connections end normally, connections of its clients fail
+            let mut buf = [0u8; 16];
+            let result = loop {
+                match reading.read(&mut buf) {
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(1)),
+                    result => break result,
+                }
+            };
+            match backend_closes {
+                true => assert_eq!(result.unwrap(), 0),
+                false => assert_eq!(result.unwrap_err().kind(), ErrorKind::UnexpectedEof),
+            }
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use std::env;
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::net::TcpListener;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+use std::ptr;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/**
+    Environment variable holding the file descriptor of the handoff socket, set only for the upgraded child process
+*/
+const UPGRADE_FD_ENV: &str = "LB_UPGRADE_FD";
+
+// value of [UPGRADE_FD_ENV] when the process started, see [take_handoff_env]
+static HANDOFF_FD: OnceLock<Option<String>> = OnceLock::new();
+
+// maximum number of listeners that can be handed over
+const MAX_LISTENERS: usize = 64;
+
+// how long the parent waits for the child to start accepting on the handed over listeners
+const READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// sent by the child once it is accepting connections
+const READY_MESSAGE: u8 = b'R';
+
+/**
+    Child side of a binary upgrade. Holds the connection to the parent, which is waiting for [confirm_ready]
+*/
+pub struct UpgradeHandoff {
+    socket: UnixStream,
+}
+
+impl UpgradeHandoff {
+    /**
+        Tells the parent that listeners are being accepted on, so it can stop accepting and drain its connections
+    */
+    pub fn confirm_ready(mut self) -> Result<()> {
+        self.socket.write_all(&[READY_MESSAGE])
+    }
+}
+
+/**
+    Starts a fresh instance of the current executable (with the same arguments) and passes it the given listeners over a Unix socket.
+    Returns once the new instance is accepting connections, the caller should then stop accepting and drain existing connections.
+*/
+pub fn spawn_upgraded(listeners: &[RawFd]) -> Result<Child> {
+    let (mut parent_socket, child_socket) = UnixStream::pair()?;
+    let child_fd = child_socket.as_raw_fd();
+
+    let mut command = Command::new(env::current_exe()?);
+    command.args(env::args_os().skip(1)).env(UPGRADE_FD_ENV, child_fd.to_string());
+    unsafe {
+        // the socket is created with CLOEXEC, clear it in the child so it survives the exec
+        command.pre_exec(move || {
+            if libc::fcntl(child_fd, libc::F_SETFD, 0) < 0 {
+                return Err(Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+
+    let mut child = command.spawn()?;
+    drop(child_socket);
+
+    let result = send_fds(&parent_socket, listeners).and_then(|_| {
+        parent_socket.set_read_timeout(Some(READY_TIMEOUT))?;
+
+        let mut ready = [0u8; 1];
+        parent_socket.read_exact(&mut ready)?;
+        if ready[0] != READY_MESSAGE {
+            return Err(Error::new(ErrorKind::InvalidData, "unexpected message from upgraded process"));
+        }
+
+        Ok(())
+    });
+
+    if let Err(e) = result {
+        // the new instance never became ready, keep serving from this one
+        child.kill().unwrap_or(());
+        child.wait().unwrap_or_default();
+        return Err(e);
+    }
+
+    Ok(child)
+}
+
+/**
+    Takes the handoff socket descriptor out of the environment, so it isn't passed on to further upgrades. Changing the environment
+    is only sound while no other threads are running, this has to be called at the start of main
+*/
+pub fn take_handoff_env() {
+    HANDOFF_FD.get_or_init(|| {
+        let fd = env::var(UPGRADE_FD_ENV).ok();
+        env::remove_var(UPGRADE_FD_ENV);
+        fd
+    });
+}
+
+/**
+    If this process was started by [spawn_upgraded], receives the listeners handed over by the parent
+*/
+pub fn receive_listeners() -> Result<Option<(Vec<TcpListener>, UpgradeHandoff)>> {
+    let fd: RawFd = match HANDOFF_FD.get().and_then(|f| f.as_ref()) {
+        Some(v) => v.parse().map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid upgrade socket descriptor"))?,
+        None => return Ok(None),
+    };
+
+    let socket = unsafe { UnixStream::from_raw_fd(fd) };
+    unsafe {
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+    }
+
+    let listeners = receive_fds(&socket)?
+        .into_iter()
+        .map(|fd| unsafe { TcpListener::from_raw_fd(fd) })
+        .collect();
+
+    Ok(Some((listeners, UpgradeHandoff { socket })))
+}
+
+fn send_fds(socket: &UnixStream, fds: &[RawFd]) -> Result<()> {
+    if fds.is_empty() || fds.len() > MAX_LISTENERS {
+        return Err(Error::new(ErrorKind::InvalidInput, "invalid number of listeners to hand over"));
+    }
+
+    let data = [fds.len() as u8];
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+
+    let fds_len = mem::size_of_val(fds) as u32;
+    let mut control = control_buffer(fds_len);
+
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = libc::CMSG_SPACE(fds_len) as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+        ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+
+        if libc::sendmsg(socket.as_raw_fd(), &msg, 0) < 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+fn receive_fds(socket: &UnixStream) -> Result<Vec<RawFd>> {
+    let mut data = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+
+    let fds_len = (MAX_LISTENERS * mem::size_of::<RawFd>()) as u32;
+    let mut control = control_buffer(fds_len);
+
+    let mut fds = vec![];
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = libc::CMSG_SPACE(fds_len) as _;
+
+        if libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) <= 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data_len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let count = data_len / mem::size_of::<RawFd>();
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                for i in 0..count {
+                    fds.push(ptr::read_unaligned(data.add(i)));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    if fds.len() != data[0] as usize {
+        return Err(Error::new(ErrorKind::InvalidData, "did not receive all listeners from parent"));
+    }
+
+    Ok(fds)
+}
+
+/**
+    Control message buffer, backed by [u64] so it is aligned for [libc::cmsghdr]
+*/
+fn control_buffer(fds_len: u32) -> Vec<u64> {
+    let space = unsafe { libc::CMSG_SPACE(fds_len) } as usize;
+    vec![0u64; space.div_ceil(mem::size_of::<u64>())]
+}
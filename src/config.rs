@@ -0,0 +1,524 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+/**
+    Optional configuration, loaded from a TOML file. Every section can be omitted, in which case defaults are used.
+*/
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+    pub discovery: DiscoveryConfig,
+    pub shutdown: ShutdownConfig,
+    pub logging: LoggingConfig,
+    pub access_log: AccessLogConfig,
+    pub metrics: MetricsConfig,
+    pub admin: AdminConfig,
+    pub balancing: BalancingConfig,
+    pub limits: LimitsConfig,
+    pub access: AccessConfig,
+    pub bandwidth: BandwidthConfig,
+    pub pool: PoolConfig,
+    /**
+        Additional named backend pools, clients are routed to them by SNI
+    */
+    pub pools: HashMap<String, PoolConfig>,
+    pub listener: ListenerConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    /**
+        Directory containing per-service `.json` or `.toml` files, each listing backends of a single service
+    */
+    pub directory: Option<String>,
+    /**
+        How often (in seconds) the directory is checked for changes, at least 1
+    */
+    pub directory_interval: u64,
+    /**
+        HTTP endpoint returning a JSON list of backends
+    */
+    pub http_url: Option<String>,
+    /**
+        How often (in seconds) the HTTP endpoint is polled, at least 1
+    */
+    pub http_interval: u64,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            directory: None,
+            directory_interval: 2,
+            http_url: None,
+            http_interval: 10,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    /**
+        How long (in seconds) existing connections are allowed to finish after a stop was requested
+    */
+    pub drain_timeout: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig { drain_timeout: 30 }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /**
+        Maximum level that gets logged: error, warn, info, debug or trace
+    */
+    pub level: String,
+    /**
+        File to append logs to, logs are written to stderr if not set
+    */
+    pub file: Option<String>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: "info".to_string(),
+            file: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct AccessLogConfig {
+    /**
+        File to append a record for every proxied session to, access logging is disabled if not set
+    */
+    pub file: Option<String>,
+    /**
+        Either "human" (default) or "json" for newline-delimited JSON
+    */
+    pub format: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /**
+        Address of the HTTP listener serving Prometheus metrics on `/metrics`, disabled if not set
+    */
+    pub listen: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct AdminConfig {
+    /**
+        Address of the HTTP listener serving the admin API, disabled if not set. Only loopback addresses are allowed without a token
+    */
+    pub listen: Option<String>,
+    /**
+        Bearer token required in the `Authorization` header of every admin request
+    */
+    pub token: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct BalancingConfig {
+    /**
+        How long (in seconds) it takes for a recovered or newly added host to get its full weight, disabled if 0
+    */
+    pub slow_start: u64,
+    /**
+        Share (0 to 1) of active hosts in a tier that must be available for the tier to get traffic. With 0, traffic only
+        moves to backup hosts once all hosts of a tier are unavailable
+    */
+    pub failover_min_available: f64,
+    /**
+        How many clients every worker thread lets wait when all hosts are at their connection limit (`max_conns`)
+    */
+    pub queue_size: usize,
+    /**
+        How long (in seconds) a client waits for a host with free capacity before it's disconnected
+    */
+    pub queue_timeout: u64,
+}
+
+impl Default for BalancingConfig {
+    fn default() -> Self {
+        BalancingConfig {
+            slow_start: 0,
+            failover_min_available: 0.0,
+            queue_size: 100,
+            queue_timeout: 10,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /**
+        Maximum concurrent client connections, unlimited if 0
+    */
+    pub max_connections: usize,
+    /**
+        Maximum concurrent connections from a single client IP address, unlimited if 0
+    */
+    pub max_connections_per_ip: usize,
+    /**
+        Maximum concurrent connections from a single /24 (IPv4) or /64 (IPv6) network, unlimited if 0
+    */
+    pub max_connections_per_prefix: usize,
+    /**
+        New connections per second allowed from a single client IP address, unlimited if 0
+    */
+    pub rate_per_ip: f64,
+    /**
+        How many connections a single client IP address can open at once before [rate_per_ip] applies, defaults to the rate
+    */
+    pub burst_per_ip: f64,
+    /**
+        New connections per second allowed from all clients, unlimited if 0
+    */
+    pub global_rate: f64,
+    /**
+        How many connections can be opened at once before [global_rate] applies, defaults to the rate
+    */
+    pub global_burst: f64,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct AccessConfig {
+    /**
+        Rules for all listeners without their own
+    */
+    #[serde(flatten)]
+    pub default: AccessListConfig,
+    /**
+        Rules for the listener on the given port, replacing the default ones
+    */
+    pub port: HashMap<String, AccessListConfig>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct AccessListConfig {
+    /**
+        Allowed client networks in CIDR notation, if there are any, clients not matching them are denied
+    */
+    pub allow: Vec<String>,
+    /**
+        Denied client networks in CIDR notation
+    */
+    pub deny: Vec<String>,
+    /**
+        Files with one allowed network per line, for lists too large for the config file
+    */
+    pub allow_file: Option<String>,
+    /**
+        Files with one denied network per line, for lists too large for the config file
+    */
+    pub deny_file: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct BandwidthConfig {
+    /**
+        Bytes per second a single client can send to backends, unlimited if 0
+    */
+    pub client_upload: u64,
+    /**
+        Bytes per second a single client can receive from backends, unlimited if 0
+    */
+    pub client_download: u64,
+    /**
+        Bytes per second all clients together can send to the backend pool, unlimited if 0
+    */
+    pub pool_upload: u64,
+    /**
+        Bytes per second all clients together can receive from the backend pool, unlimited if 0
+    */
+    pub pool_download: u64,
+}
+
+/**
+    Options of a backend pool. The hosts of the default pool are listed in the `hosts` file
+*/
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct PoolConfig {
+    /**
+        File listing the hosts of the pool, in the same format as the `hosts` file. Required for named pools
+    */
+    pub hosts: Option<String>,
+    /**
+        PROXY protocol version (`v1` or `v2`) of the header sent to backends before any client data, disabled if not set
+    */
+    pub proxy_protocol: Option<String>,
+    /**
+        Additional TLVs sent in every PROXY protocol header, version 2 only
+    */
+    pub proxy_protocol_tlv: Vec<TlvConfig>,
+    /**
+        Talk TLS with the backends of the pool
+    */
+    pub tls: Option<BackendTlsConfig>,
+    /**
+        Keep backend connections of HTTP listeners open and reuse them for later requests
+    */
+    pub keepalive: Option<KeepAliveConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct KeepAliveConfig {
+    /**
+        Idle connections every worker thread keeps per host
+    */
+    pub max_idle: usize,
+    /**
+        Seconds after which idle connections are closed, should be shorter than the backends' own keep-alive timeout
+    */
+    pub idle_timeout: u64,
+}
+
+impl Default for KeepAliveConfig {
+    fn default() -> Self {
+        KeepAliveConfig { max_idle: 8, idle_timeout: 30 }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TlvConfig {
+    #[serde(rename = "type")]
+    pub kind: u8,
+    pub value: String,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct BackendTlsConfig {
+    /**
+        PEM bundle of CAs backend certificates are checked against, the system's CAs if not set
+    */
+    pub ca_file: Option<String>,
+    /**
+        Name sent as SNI and expected in backend certificates, the backend's address if not set
+    */
+    pub server_name: Option<String>,
+    /**
+        Certificate chain and private key (PEM files) presented to backends asking for a client certificate
+    */
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    /**
+        Check backend certificates, should only be disabled for testing
+    */
+    pub verify: bool,
+}
+
+impl Default for BackendTlsConfig {
+    fn default() -> Self {
+        BackendTlsConfig {
+            ca_file: None,
+            server_name: None,
+            cert: None,
+            key: None,
+            verify: true,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct ListenerConfig {
+    /**
+        Options of all listeners without their own
+    */
+    #[serde(flatten)]
+    pub default: ListenerOptionsConfig,
+    /**
+        Options of the listener on the given port, replacing the default ones
+    */
+    pub port: HashMap<String, ListenerOptionsConfig>,
+    /**
+        How long (in seconds) clients of listeners accepting PROXY protocol can take to send the header
+    */
+    pub proxy_protocol_timeout: u64,
+    /**
+        How many connections can wait for their PROXY protocol header at once, newer ones are closed right away
+    */
+    pub max_awaiting_header: usize,
+    /**
+        How long (in seconds) clients of listeners routing by SNI can take to send their TLS ClientHello
+    */
+    pub client_hello_timeout: u64,
+    /**
+        How long (in seconds) clients of HTTP listeners can take to send a request head, counted from when they can send it
+    */
+    pub request_head_timeout: u64,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        ListenerConfig {
+            default: ListenerOptionsConfig::default(),
+            port: HashMap::new(),
+            proxy_protocol_timeout: 5,
+            max_awaiting_header: 1024,
+            client_hello_timeout: 5,
+            request_head_timeout: 10,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct ListenerOptionsConfig {
+    /**
+        Expect a PROXY protocol header (v1 or v2) from another proxy in front of the balancer and use the client address from it
+    */
+    pub accept_proxy_protocol: bool,
+    /**
+        Pool names by the server name TLS clients ask for (SNI), exact names or wildcards like `*.example.com`
+    */
+    pub sni_routes: HashMap<String, String>,
+    /**
+        Pool of TLS clients with a missing or unknown server name when routing by SNI, the default pool if not set
+    */
+    pub sni_default_pool: Option<String>,
+    /**
+        Terminates TLS of clients, backends get the decrypted data
+    */
+    pub tls: Option<TlsConfig>,
+    /**
+        Handles clients as HTTP/1.x and routes every request to a pool by its host and path
+    */
+    pub http: Option<HttpConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    /**
+        Pool of requests no route matches, the default pool if not set
+    */
+    pub default_pool: Option<String>,
+    pub routes: Vec<HttpRouteConfig>,
+    /**
+        Adds `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Port` headers to requests
+    */
+    pub x_forwarded: bool,
+    /**
+        Adds the RFC 7239 `Forwarded` header to requests
+    */
+    pub forwarded: bool,
+    /**
+        Addresses and CIDR ranges of proxies whose forwarding headers are kept and appended to, other clients' ones are replaced
+    */
+    pub trusted_proxies: Vec<String>,
+    /**
+        Picks a backend for every request instead of once per client connection (and pool)
+    */
+    pub balance_requests: bool,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            default_pool: None,
+            routes: vec![],
+            x_forwarded: true,
+            forwarded: true,
+            trusted_proxies: vec![],
+            balance_requests: false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct HttpRouteConfig {
+    /**
+        Host of the request, an exact name or a wildcard like `*.example.com`. Any host if not set
+    */
+    pub host: Option<String>,
+    /**
+        Prefix of the request path, matching whole path segments. Any path if not set
+    */
+    pub path: Option<String>,
+    pub pool: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct TlsConfig {
+    /**
+        Certificate chains and private keys, the first one is used for clients asking for none of the names
+    */
+    pub certificates: Vec<CertificateConfig>,
+    /**
+        Allowed protocol versions (`1.2`, `1.3`), all if empty
+    */
+    pub versions: Vec<String>,
+    /**
+        Application protocols offered to clients (ALPN), by preference
+    */
+    pub alpn: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CertificateConfig {
+    /**
+        PEM file with the certificate chain, starting with the server's certificate
+    */
+    pub cert: String,
+    /**
+        PEM file with the private key
+    */
+    pub key: String,
+    /**
+        Server names (SNI) the certificate is used for, exact names or wildcards like `*.example.com`
+    */
+    #[serde(default)]
+    pub names: Vec<String>,
+}
+
+impl Config {
+    /**
+        Loads the config file, or returns defaults if it doesn't exist. Errors are written directly to stderr, as logging is configured from here
+    */
+    pub fn load(configfile: &str) -> Self {
+        if !Path::exists(Path::new(configfile)) {
+            return Config::default();
+        }
+
+        let content = match fs::read_to_string(configfile) {
+            Ok(c) => c,
+            Err(err) => {
+                eprintln!("[Config] Failed to read config file '{}' -> {}", configfile, err);
+                return Config::default();
+            }
+        };
+
+        match toml::from_str(&content) {
+            Ok(c) => c,
+            Err(err) => {
+                eprintln!("[Config] Failed to parse config file '{}' -> {}", configfile, err);
+                Config::default()
+            }
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use std::fs::OpenOptions;
+use std::io::{self, Result, Write};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use log::{warn, LevelFilter, Log, Metadata, Record};
+
+use super::balancer::format_timestamp;
+use super::config::LoggingConfig;
+
+/**
+    Writes log records as `[TIMESTAMP] LEVEL [TARGET] message` lines to stderr or a file.
+    Level checks go through [log::max_level], so deciding whether to log doesn't take any locks.
+*/
+pub struct Logger {
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Logger {
+    /**
+        Installs the logger as the global logger, using the output and level from config
+    */
+    pub fn init(config: &LoggingConfig) -> Result<()> {
+        let output: Box<dyn Write + Send> = match &config.file {
+            Some(path) => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+            None => Box::new(io::stderr()),
+        };
+
+        let logger = Logger { output: Mutex::new(output) };
+        log::set_boxed_logger(Box::new(logger)).map_err(|e| io::Error::new(io::ErrorKind::AlreadyExists, e))?;
+        if !Logger::set_level(&config.level) {
+            Logger::set_level("info");
+            warn!(target: "Logger", "Invalid log level '{}', using 'info'", config.level);
+        }
+
+        Ok(())
+    }
+
+    /**
+        Changes the maximum level that gets logged, can be called at any time. Returns [false] if the level is not valid
+    */
+    pub fn set_level(level: &str) -> bool {
+        match level.parse::<LevelFilter>() {
+            Ok(l) => {
+                log::set_max_level(l);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        // only log our own records, dependencies (like mio) have their own tracing
+        if !record.module_path().is_some_and(|m| m.starts_with(env!("CARGO_CRATE_NAME"))) {
+            return;
+        }
+
+        let line = format!("{} {:<5} [{}] {}\n", format_timestamp(SystemTime::now()), record.level(), record.target(), record.args());
+
+        let mut output = self.output.lock().unwrap();
+        output.write_all(line.as_bytes()).unwrap_or(());
+    }
+
+    fn flush(&self) {
+        self.output.lock().unwrap().flush().unwrap_or(());
+    }
+}
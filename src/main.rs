@@ -1,42 +1,494 @@
-use std::io::Result;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
 use std::process::exit;
+use std::time::Duration;
+
+use log::{error, info, warn};
+use rustls::ServerConfig;
 
 mod balancer;
-use balancer::Poller;
+mod config;
+mod logger;
+use balancer::{AccessAction, AccessControl, AccessList};
+use balancer::{BandwidthLimits, ConnectionLimits, RateLimit};
+use balancer::{ForwardedHeaders, HttpRouter, KeepAlive, Pool, ProxyProtocol, ProxyProtocolVersion, SniRouter, DEFAULT_POOL};
+use balancer::{CertificateStore, ListenerOptions, Poller, TlsAcceptor, TlsConnector, TlsVersion};
 use balancer::RoundRobin;
+use balancer::{AccessLog, AccessLogFormat};
+use balancer::{FileDiscovery, HttpDiscovery};
 use balancer::{HostManager, LoadBalancer};
+use balancer::{HttpRequest, HttpResponse};
+use config::{AccessConfig, AccessListConfig, BalancingConfig, Config, ListenerConfig, ListenerOptionsConfig, PoolConfig, TlsConfig};
+use logger::Logger;
+
+const CONFIG_FILE: &str = "config.toml";
 
 fn main() -> Result<()> {
+    // the environment can only be changed safely before any threads are started
+    #[cfg(unix)]
+    {
+        balancer::take_handoff_env();
+        balancer::take_listen_env();
+    }
+
+    // PARSE CONFIG
+    let config = Config::load(CONFIG_FILE);
+
+    // LOGGING
+    Logger::init(&config.logging).unwrap_or_else(|e| {
+        eprintln!("[Logger] Failed to initialize logging -> {}", e);
+        exit(2);
+    });
+
+    // ACCESS CONTROL
+    let access_control = match load_access_control(&config.access) {
+        Ok(a) => Arc::new(RwLock::new(a)),
+        Err(e) => {
+            error!(target: "Access", "{}", e);
+            exit(2);
+        }
+    };
+
     // PARSE HOSTS
-    let host_manager = HostManager::new("hosts");
-    if host_manager.hosts.len() == 0 {
+    let mut host_manager = load_host_manager("hosts", &config.balancing);
+
+    // SERVICE DISCOVERY
+    // polling without a pause would keep the discovery thread busy
+    if config.discovery.directory_interval == 0 || config.discovery.http_interval == 0 {
+        error!(target: "Config", "Discovery intervals must be at least 1 second");
+        exit(2);
+    }
+    if let Some(directory) = &config.discovery.directory {
+        let interval = Duration::from_secs(config.discovery.directory_interval);
+        host_manager.add_discovery(Box::new(FileDiscovery::new(directory, interval)));
+    }
+    if let Some(url) = &config.discovery.http_url {
+        let interval = Duration::from_secs(config.discovery.http_interval);
+        match HttpDiscovery::new(url, interval) {
+            Ok(d) => host_manager.add_discovery(Box::new(d)),
+            Err(e) => error!(target: "Discovery", "Invalid discovery URL '{}' -> {}", url, e),
+        }
+    }
+
+    if host_manager.hosts.is_empty() && !host_manager.has_discovery() && config.pools.is_empty() {
         return Ok(());
     }
 
+    // bandwidth buckets hold a second worth of bytes, so short bursts aren't slowed down
+    let bandwidth = BandwidthLimits {
+        client_upload: RateLimit::new(config.bandwidth.client_upload as f64, 0.0),
+        client_download: RateLimit::new(config.bandwidth.client_download as f64, 0.0),
+        pool_upload: RateLimit::new(config.bandwidth.pool_upload as f64, 0.0),
+        pool_download: RateLimit::new(config.bandwidth.pool_download as f64, 0.0),
+    };
+
     // INITIALIZE
-    let debug_mode = true;
-    let round_robin = RoundRobin::new(host_manager);
-    let balancer = LoadBalancer::new(round_robin, 4, debug_mode);
-    let mut poller = Poller::new(balancer);
-
-    // PARSE PORT
-    let port = match get_port() {
-        Some(p) => p,
-        None => {
-            println!("Invalid listening port provided, use default 4554");
-            4554
+    let default_pool = Arc::new(load_pool(DEFAULT_POOL, host_manager, &config.pool, &bandwidth));
+    let mut balancer = LoadBalancer::new(Arc::clone(&default_pool), 4);
+    balancer.set_queue(config.balancing.queue_size, Duration::from_secs(config.balancing.queue_timeout));
+    balancer.set_bandwidth_limits(bandwidth);
+    balancer.set_client_hello_timeout(Duration::from_secs(config.listener.client_hello_timeout));
+    balancer.set_request_head_timeout(Duration::from_secs(config.listener.request_head_timeout));
+
+    // POOLS (sorted, so they are listed in a stable order)
+    let mut pools = HashMap::from([(DEFAULT_POOL.to_string(), default_pool)]);
+    let mut pool_names: Vec<&String> = config.pools.keys().collect();
+    pool_names.sort();
+    for name in pool_names {
+        let pool_config = &config.pools[name];
+        let hosts = match (&pool_config.hosts, name.as_str()) {
+            (_, DEFAULT_POOL) => {
+                error!(target: "Config", "Pool name '{}' is reserved for the pool of the 'hosts' file", DEFAULT_POOL);
+                exit(2);
+            }
+            (Some(h), _) => h,
+            (None, _) => {
+                error!(target: "Config", "Pool '{}' has no hosts file", name);
+                exit(2);
+            }
+        };
+
+        let pool = Arc::new(load_pool(name, load_host_manager(hosts, &config.balancing), pool_config, &bandwidth));
+        balancer.add_pool(Arc::clone(&pool));
+        pools.insert(name.clone(), pool);
+    }
+
+    // ACCESS LOG
+    if let Some(path) = &config.access_log.file {
+        let format = match config.access_log.format.as_deref() {
+            Some("json") => AccessLogFormat::Json,
+            Some("human") | None => AccessLogFormat::Human,
+            Some(f) => {
+                warn!(target: "AccessLog", "Unknown access log format '{}', using 'human'", f);
+                AccessLogFormat::Human
+            }
+        };
+
+        match AccessLog::new(path, format) {
+            Ok(log) => balancer.set_access_log(log),
+            Err(e) => error!(target: "AccessLog", "Failed to open access log '{}' -> {}", path, e),
         }
-    };
+    }
+    // METRICS
+    if let Some(listen) = &config.metrics.listen {
+        match listen.parse() {
+            Ok(addr) => {
+                let render = balancer.metrics_renderer();
+                balancer::serve_http("Metrics", addr, move |request: &HttpRequest| match request.path.as_str() {
+                    "/metrics" => HttpResponse::new(200, "text/plain; version=0.0.4", render()),
+                    _ => HttpResponse::new(404, "text/plain", "Not found\n".to_string()),
+                });
+            }
+            Err(e) => error!(target: "Metrics", "Invalid metrics listen address '{}' -> {}", listen, e),
+        }
+    }
+    // ADMIN API
+    if let Some(listen) = &config.admin.listen {
+        match listen.parse::<SocketAddr>() {
+            Ok(addr) if !addr.ip().is_loopback() && config.admin.token.is_none() => {
+                error!(target: "Admin", "Refusing to serve admin API on non-loopback address {} without a token", addr);
+            }
+            Ok(addr) => {
+                let admin = balancer.admin(config.admin.token.clone());
+                balancer::serve_http("Admin", addr, move |request: &HttpRequest| admin.handle(request));
+            }
+            Err(e) => error!(target: "Admin", "Invalid admin listen address '{}' -> {}", listen, e),
+        }
+    }
+
+    // LISTENERS
+    let default_options = listener_options(&config.listener.default, &pools);
+    let port_options = listener_port_options(&config.listener, &pools);
+    #[cfg(unix)]
+    {
+        let mut tls_acceptors = vec![];
+        for (port, options) in port_options.iter().map(|(p, o)| (Some(*p), o)).chain([(None, &default_options)]) {
+            if let Some(acceptor) = &options.tls {
+                tls_acceptors.push((port, Arc::clone(acceptor)));
+            }
+        }
+        watch_reload(Arc::clone(&access_control), tls_acceptors);
+    }
+
+    let mut poller = Poller::new(balancer, Duration::from_secs(config.shutdown.drain_timeout));
+    poller.set_connection_limits(ConnectionLimits {
+        total: config.limits.max_connections,
+        per_ip: config.limits.max_connections_per_ip,
+        per_prefix: config.limits.max_connections_per_prefix,
+    });
+    poller.set_access_control(access_control);
+    poller.set_listener_options(default_options, port_options);
+    poller.set_proxy_protocol_timeout(Duration::from_secs(config.listener.proxy_protocol_timeout));
+    poller.set_max_awaiting_header(config.listener.max_awaiting_header);
+    poller.set_rate_limits(
+        RateLimit::new(config.limits.rate_per_ip, config.limits.burst_per_ip),
+        RateLimit::new(config.limits.global_rate, config.limits.global_burst),
+    );
 
-    // START
-    poller.start_listening(port).unwrap_or_else(|e| {
-        println!("{}", e.to_string());
+    // START (listeners are handed over by the previous instance when upgrading, or passed by systemd)
+    let upgraded = poller.adopt_upgraded().unwrap_or_else(|e| {
+        error!(target: "Upgrade", "Failed to receive listeners -> {}", e);
+        exit(2);
+    });
+    let activated = !upgraded
+        && poller.adopt_systemd().unwrap_or_else(|e| {
+            error!(target: "Systemd", "Failed to use passed listening sockets -> {}", e);
+            exit(2);
+        });
+    if !upgraded && !activated {
+        // PARSE PORT
+        let port = match get_port() {
+            Some(p) => p,
+            None => {
+                warn!(target: "Listener", "Invalid listening port provided, use default 4554");
+                4554
+            }
+        };
+
+        poller.bind(port).unwrap_or_else(|e| {
+            error!(target: "Listener", "Failed to bind port {} -> {}", port, e);
+            exit(2);
+        });
+    }
+
+    poller.start_listening().unwrap_or_else(|e| {
+        error!(target: "Listener", "{}", e);
         exit(2);
     });
 
     Ok(())
 }
 
+fn load_host_manager(hostfile: &str, config: &BalancingConfig) -> HostManager {
+    let mut host_manager = HostManager::new(hostfile);
+    host_manager.set_slow_start(Duration::from_secs(config.slow_start));
+    host_manager.set_failover_min_available(config.failover_min_available);
+    host_manager
+}
+
+/**
+    Builds a backend pool from config, exiting if its PROXY protocol, TLS or keep-alive options are invalid
+*/
+fn load_pool(name: &str, host_manager: HostManager, config: &PoolConfig, bandwidth: &BandwidthLimits) -> Pool {
+    let mut pool = Pool::new(name, RoundRobin::new(host_manager), bandwidth);
+
+    if let Some(version) = &config.proxy_protocol {
+        let version = ProxyProtocolVersion::parse(version).unwrap_or_else(|| {
+            error!(target: "Config", "Invalid PROXY protocol version '{}' of pool '{}', expected 'v1' or 'v2'", version, name);
+            exit(2);
+        });
+
+        let mut proxy_protocol = ProxyProtocol::new(version);
+        for tlv in &config.proxy_protocol_tlv {
+            if !proxy_protocol.add_tlv(tlv.kind, tlv.value.clone().into_bytes()) {
+                error!(target: "Config", "Can't send PROXY protocol TLV of type {:#04x}, TLVs need version 2 and must fit into the header", tlv.kind);
+                exit(2);
+            }
+        }
+
+        info!(target: "Config", "Sending PROXY protocol {:?} headers to backends of pool '{}'", version, name);
+        pool.set_proxy_protocol(proxy_protocol);
+    }
+
+    if let Some(tls) = &config.tls {
+        let client_certificate = match (&tls.cert, &tls.key) {
+            (Some(cert), Some(key)) => Some((cert.as_str(), key.as_str())),
+            (None, None) => None,
+            _ => {
+                error!(target: "Config", "TLS client certificate of pool '{}' needs both 'cert' and 'key'", name);
+                exit(2);
+            }
+        };
+
+        let mut connector = TlsConnector::new(tls.ca_file.as_deref(), client_certificate, tls.verify).unwrap_or_else(|e| {
+            error!(target: "Config", "Failed to set up TLS with backends of pool '{}' -> {}", name, e);
+            exit(2);
+        });
+        if let Some(server_name) = &tls.server_name {
+            if !connector.set_server_name(server_name) {
+                error!(target: "Config", "Invalid TLS server name '{}' of pool '{}'", server_name, name);
+                exit(2);
+            }
+        }
+
+        if !tls.verify {
+            warn!(target: "Config", "Certificates of backends of pool '{}' are not verified", name);
+        }
+        info!(target: "Config", "Talking TLS with backends of pool '{}'", name);
+        pool.set_tls(connector);
+    }
+
+    if let Some(keepalive) = &config.keepalive {
+        // the header describes the client a connection was opened for, it isn't sent again for later clients
+        if pool.proxy_protocol.is_some() {
+            error!(target: "Config", "Pool '{}' can't keep backend connections alive while sending PROXY protocol headers", name);
+            exit(2);
+        }
+
+        info!(target: "Config", "Keeping up to {} idle connections per host of pool '{}'", keepalive.max_idle, name);
+        pool.set_keepalive(KeepAlive {
+            max_idle: keepalive.max_idle,
+            idle_timeout: Duration::from_secs(keepalive.idle_timeout),
+        });
+    }
+
+    pool
+}
+
+/**
+    Builds listener options from config, exiting if a listener routes to an unknown pool
+*/
+fn listener_options(config: &ListenerOptionsConfig, pools: &HashMap<String, Arc<Pool>>) -> ListenerOptions {
+    let pool = |name: &String| {
+        pools.get(name).cloned().unwrap_or_else(|| {
+            error!(target: "Config", "Unknown pool '{}' in listener routes", name);
+            exit(2);
+        })
+    };
+
+    let mut sni_router = None;
+    if !config.sni_routes.is_empty() || config.sni_default_pool.is_some() {
+        let mut router = SniRouter::new(config.sni_default_pool.as_ref().map_or_else(|| Arc::clone(&pools[DEFAULT_POOL]), pool));
+        for (name, pool_name) in &config.sni_routes {
+            router.add_route(name, pool(pool_name));
+        }
+        sni_router = Some(Arc::new(router));
+    }
+
+    let tls = config.tls.as_ref().map(|tls| match load_tls(tls) {
+        Ok(c) => Arc::new(TlsAcceptor::new(c)),
+        Err(e) => {
+            error!(target: "Config", "{}", e);
+            exit(2);
+        }
+    });
+
+    let http_router = config.http.as_ref().map(|http| {
+        // routing by SNI picks the pool once per connection, it can't be combined with routing every request
+        if sni_router.is_some() {
+            error!(target: "Config", "Listeners can't route by SNI and by HTTP requests at once");
+            exit(2);
+        }
+
+        let mut router = HttpRouter::new(http.default_pool.as_ref().map_or_else(|| Arc::clone(&pools[DEFAULT_POOL]), pool));
+        for route in &http.routes {
+            if route.path.as_ref().is_some_and(|p| !p.starts_with('/')) {
+                error!(target: "Config", "Invalid HTTP route path '{}', paths start with '/'", route.path.as_ref().unwrap());
+                exit(2);
+            }
+            router.add_route(route.host.as_deref(), route.path.as_deref(), pool(&route.pool));
+        }
+        Arc::new(router)
+    });
+
+    let forwarded_headers = config.http.as_ref().filter(|http| http.x_forwarded || http.forwarded).map(|http| {
+        let mut trusted_proxies = AccessList::new();
+        for cidr in &http.trusted_proxies {
+            if !trusted_proxies.add(cidr, AccessAction::Allow) {
+                error!(target: "Config", "Invalid trusted proxy network '{}'", cidr);
+                exit(2);
+            }
+        }
+        Arc::new(ForwardedHeaders::new(http.x_forwarded, http.forwarded, trusted_proxies))
+    });
+
+    ListenerOptions {
+        accept_proxy_protocol: config.accept_proxy_protocol,
+        sni_router,
+        tls,
+        http_router,
+        forwarded_headers,
+        balance_requests: config.http.as_ref().is_some_and(|http| http.balance_requests),
+    }
+}
+
+fn listener_port_options(config: &ListenerConfig, pools: &HashMap<String, Arc<Pool>>) -> HashMap<u16, ListenerOptions> {
+    let mut ports = HashMap::new();
+    for (port, options) in &config.port {
+        match port.parse() {
+            Ok(p) => {
+                ports.insert(p, listener_options(options, pools));
+            }
+            Err(_) => warn!(target: "Config", "Invalid port '{}', ignoring its listener options", port),
+        }
+    }
+
+    ports
+}
+
+/**
+    Loads the certificates of a listener terminating TLS, failing if any of them can't be used
+*/
+fn load_tls(config: &TlsConfig) -> Result<ServerConfig> {
+    let mut certificates = CertificateStore::new();
+    for certificate in &config.certificates {
+        certificates
+            .add_file(&certificate.cert, &certificate.key, &certificate.names)
+            .map_err(|e| Error::new(e.kind(), format!("Failed to load TLS certificate {}", e)))?;
+    }
+    if certificates.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "TLS is enabled without any certificates"));
+    }
+
+    let mut versions = vec![];
+    for version in &config.versions {
+        match TlsVersion::parse(version) {
+            Some(v) => versions.push(v),
+            None => return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid TLS version '{}', expected '1.2' or '1.3'", version))),
+        }
+    }
+
+    info!(target: "Config", "Loaded {} TLS certificates", config.certificates.len());
+    certificates.into_server_config(&versions, &config.alpn)
+}
+
+/**
+    Builds access lists from config, failing if a list file can't be read
+*/
+fn load_access_control(config: &AccessConfig) -> Result<AccessControl> {
+    let mut access_control = AccessControl::new(load_access_list("default", &config.default)?);
+    for (port, list) in &config.port {
+        match port.parse() {
+            Ok(p) => access_control.set_port(p, load_access_list(&format!("port {}", p), list)?),
+            Err(_) => warn!(target: "Access", "Invalid port '{}', ignoring its access list", port),
+        }
+    }
+
+    Ok(access_control)
+}
+
+fn load_access_list(name: &str, config: &AccessListConfig) -> Result<AccessList> {
+    let mut list = AccessList::new();
+
+    for (cidrs, action) in [(&config.allow, AccessAction::Allow), (&config.deny, AccessAction::Deny)] {
+        for cidr in cidrs {
+            if !list.add(cidr, action) {
+                warn!(target: "Access", "Invalid network '{}' in {} access list", cidr, name);
+            }
+        }
+    }
+
+    for (file, action) in [(&config.allow_file, AccessAction::Allow), (&config.deny_file, AccessAction::Deny)] {
+        if let Some(path) = file {
+            list.add_file(path, action)
+                .map_err(|e| Error::new(e.kind(), format!("Failed to read access list '{}' -> {}", path, e)))?;
+        }
+    }
+
+    if list.rule_count() > 0 {
+        info!(target: "Access", "Loaded {} rules for {} access list", list.rule_count(), name);
+    }
+    Ok(list)
+}
+
+/**
+    Re-reads the log level, access lists and TLS certificates from the config file on SIGHUP. [tls_acceptors] are the
+    listeners terminating TLS by port ([None] for the default listener options)
+*/
+#[cfg(unix)]
+fn watch_reload(access_control: Arc<RwLock<AccessControl>>, tls_acceptors: Vec<(Option<u16>, Arc<TlsAcceptor>)>) {
+    let mut signals = match signal_hook::iterator::Signals::new([signal_hook::consts::SIGHUP]) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!(target: "Config", "Failed to register SIGHUP handler, reloading is disabled -> {}", e);
+            return;
+        }
+    };
+
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            let config = Config::load(CONFIG_FILE);
+            if Logger::set_level(&config.logging.level) {
+                info!(target: "Config", "Reloaded, log level is '{}'", config.logging.level);
+            } else {
+                warn!(target: "Config", "Reloaded, ignoring invalid log level '{}'", config.logging.level);
+            }
+
+            // access lists are only replaced if they all loaded
+            match load_access_control(&config.access) {
+                Ok(a) => *access_control.write().unwrap() = a,
+                Err(e) => error!(target: "Access", "{}, keeping previous access lists", e),
+            }
+
+            // only listeners that terminated TLS from the start can get new certificates
+            for (port, acceptor) in &tls_acceptors {
+                let options = match port {
+                    Some(p) => config.listener.port.iter().find(|(k, _)| k.parse() == Ok(*p)).map(|(_, o)| o),
+                    None => Some(&config.listener.default),
+                };
+
+                match options.and_then(|o| o.tls.as_ref()).map(load_tls) {
+                    Some(Ok(c)) => acceptor.reload(c),
+                    Some(Err(e)) => error!(target: "Config", "{}, keeping previous TLS certificates", e),
+                    None => warn!(target: "Config", "TLS can't be disabled without a restart, keeping previous TLS certificates"),
+                }
+            }
+        }
+    });
+}
+
 fn get_port() -> Option<i32> {
     let listening_port = std::env::args().nth(1)?;
     let port: i32 = match listening_port.parse() {
@@ -50,3 +502,58 @@ fn get_port() -> Option<i32> {
 
     Some(port)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::CertificateConfig;
+    use std::fs;
+
+    /**
+        Writes a self-signed certificate and its key to temporary files, returns their paths
+    */
+    fn certificate_files(name: &str) -> (String, String) {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let path = std::env::temp_dir().join(format!("lb-main-{}-{}", std::process::id(), name));
+        let (cert, key) = (path.with_extension("crt"), path.with_extension("key"));
+        fs::write(&cert, generated.cert.pem()).unwrap();
+        fs::write(&key, generated.key_pair.serialize_pem()).unwrap();
+
+        (cert.to_string_lossy().into_owned(), key.to_string_lossy().into_owned())
+    }
+
+    fn tls_config(cert: &str, key: &str, versions: &[&str]) -> TlsConfig {
+        TlsConfig {
+            certificates: vec![CertificateConfig {
+                cert: cert.to_string(),
+                key: key.to_string(),
+                names: vec![],
+            }],
+            versions: versions.iter().map(|v| v.to_string()).collect(),
+            alpn: vec!["h2".to_string()],
+        }
+    }
+
+    #[test]
+    fn loads_tls_versions() {
+        let (cert, key) = certificate_files("versions");
+        let loaded = load_tls(&tls_config(&cert, &key, &["1.2", "TLSv1.3"]));
+        let invalid = load_tls(&tls_config(&cert, &key, &["1.1"]));
+        fs::remove_file(&cert).unwrap();
+        fs::remove_file(&key).unwrap();
+
+        assert_eq!(loaded.unwrap().alpn_protocols, vec![b"h2".to_vec()]);
+        let error = invalid.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("'1.1'"));
+    }
+
+    #[test]
+    fn rejects_tls_without_certificates() {
+        let error = load_tls(&TlsConfig::default()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+
+        let error = load_tls(&tls_config("/nonexistent.crt", "/nonexistent.key", &[])).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}